@@ -1,54 +1,68 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use tanuki::{
-    TanukiConnection,
+    Result, TanukiConnection,
     capabilities::{
         User,
         buttons::{ButtonEvent, ButtonName},
         on_off::OnOff,
         sensor::SensorEvent,
     },
-    listener::EventHandler,
+    catalog::Catalog,
+    group::Group,
+    listener::AsyncEventHandler,
 };
 use tanuki_common::capabilities::{buttons::ButtonAction, on_off::OnOffCommand};
 
 pub struct Handler {
-    tanuki: Arc<TanukiConnection>,
+    main_lights: Group<OnOff<User>>,
+    living_room_lights: Group<OnOff<User>>,
 }
 
 impl Handler {
-    pub fn new(tanuki: Arc<TanukiConnection>) -> Self {
-        Self { tanuki }
-    }
+    pub async fn new(tanuki: Arc<TanukiConnection>) -> Result<Self> {
+        const ATTEMPTS: u32 = 30;
+
+        // the lights come from the hass bridge, which may still be announcing them
+        let catalog = Catalog::new(&tanuki).await?;
+
+        for attempt in 1.. {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            let handler = Self {
+                main_lights: Group::tagged_in(&tanuki, &catalog, "misc.main_light"),
+                living_room_lights: Group::tagged_in(&tanuki, &catalog, "room.living_room"),
+            };
+
+            let complete =
+                !handler.main_lights.is_empty() && !handler.living_room_lights.is_empty();
+            if complete || attempt == ATTEMPTS {
+                if !complete {
+                    tracing::warn!("Some light groups are still empty, continuing without them");
+                }
+
+                return Ok(handler);
+            }
 
-    fn set_lights(&self, command: OnOffCommand, extra: bool) {
-        const LIGHTS: [&str; 8] = [
-            "north_lamp",
-            "south_lamp",
-            "cabinet_strip",
-            "couch_strip",
-            "bed_strip",
-            // extra
-            "cabinet_lamp",
-            "cabinet_extra_lamp",
-            "kitchen_lamp",
-        ];
-
-        let extent = if extra { LIGHTS.len() } else { 5 };
-
-        for tanuki_id in &LIGHTS[..extent] {
-            self.tanuki.intent(async move |tanuki| {
-                tanuki
-                    .entity_cap::<OnOff<User>>(tanuki_id)
-                    .command(command)
-                    .await
-            });
+            tracing::info!(attempt, "Waiting for tagged lights to show up");
         }
+
+        unreachable!("attempts are unbounded")
+    }
+
+    async fn set_lights(&self, command: OnOffCommand, extra: bool) -> Result<()> {
+        let group = if extra {
+            &self.living_room_lights
+        } else {
+            &self.main_lights
+        };
+
+        group.command(command).await
     }
 }
 
-impl EventHandler<ButtonEvent> for Handler {
-    fn handle(&mut self, event: ButtonEvent) {
+impl AsyncEventHandler<ButtonEvent> for Handler {
+    async fn handle(&self, event: ButtonEvent) -> Result<()> {
         tracing::info!("Received button event: {event:?}");
 
         match event {
@@ -56,19 +70,19 @@ impl EventHandler<ButtonEvent> for Handler {
                 entity: "rodret_remote_1",
                 name: ButtonName::On,
                 action: ButtonAction::Pressed,
-            } => self.set_lights(OnOffCommand::On, false),
+            } => self.set_lights(OnOffCommand::On, false).await,
 
             ButtonEvent {
                 entity: "rodret_remote_1",
                 name: ButtonName::On,
                 action: ButtonAction::LongPressed,
-            } => self.set_lights(OnOffCommand::On, true),
+            } => self.set_lights(OnOffCommand::On, true).await,
 
             ButtonEvent {
                 entity: "rodret_remote_1",
                 name: ButtonName::Off,
                 action: ButtonAction::Pressed,
-            } => self.set_lights(OnOffCommand::Off, true),
+            } => self.set_lights(OnOffCommand::Off, true).await,
 
             _ => {
                 tracing::info!(
@@ -77,13 +91,17 @@ impl EventHandler<ButtonEvent> for Handler {
                     action = ?event.action,
                     "Unhandled button event"
                 );
+
+                Ok(())
             }
         }
     }
 }
 
-impl EventHandler<SensorEvent> for Handler {
-    fn handle(&mut self, event: SensorEvent) {
+impl AsyncEventHandler<SensorEvent> for Handler {
+    async fn handle(&self, event: SensorEvent) -> Result<()> {
         tracing::info!("Received sensor event: {event:?}");
+
+        Ok(())
     }
 }
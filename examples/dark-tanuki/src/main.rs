@@ -8,17 +8,20 @@ use std::sync::Arc;
 use tanuki::{
     TanukiConnection,
     capabilities::{buttons::ButtonEvent, sensor::SensorEvent},
+    listener::Concurrency,
 };
 use tanuki_common::capabilities::buttons::ButtonAction;
 
 use self::handler::Handler;
 
+const BROKER_URL: &str = "mqtt://192.168.0.106:1883";
+
 #[tokio::main]
 async fn main() {
     tanuki::log::init();
 
     tokio::spawn(async move {
-        tanuki_bthome::bridge("192.168.0.106:1883", [
+        tanuki_bthome::bridge(BROKER_URL, [
             ("ATC_164B6D", "balcony_door.temperature", "ATC Balcony"),
             ("ATC_C11CAF", "front_door.temperature", "ATC Door Ceiling"),
         ])
@@ -32,6 +35,7 @@ async fn main() {
         let mut mappings = vec![
             MappedEntity {
                 tanuki_id: "tapo_tv".into(),
+                tags: vec![],
                 from_hass: vec![
                     EntityDataMapping::State {
                         from_id: "sensor.tv_voltage".into(),
@@ -50,6 +54,7 @@ async fn main() {
             },
             MappedEntity {
                 tanuki_id: "vindstyrka".into(),
+                tags: vec![],
                 from_hass: vec![
                     EntityDataMapping::State {
                         from_id: "sensor.vindstyrka_temperature".into(),
@@ -68,6 +73,7 @@ async fn main() {
             },
             MappedEntity {
                 tanuki_id: "motion_room".into(),
+                tags: vec![],
                 from_hass: vec![
                     EntityDataMapping::State {
                         from_id: "binary_sensor.motion_sensor_motion".into(),
@@ -82,6 +88,7 @@ async fn main() {
             },
             MappedEntity {
                 tanuki_id: "motion_kitchen".into(),
+                tags: vec![],
                 from_hass: vec![
                     EntityDataMapping::State {
                         from_id: "binary_sensor.myggspray_wrlss_mtn_sensor_occupancy".into(),
@@ -96,6 +103,7 @@ async fn main() {
             },
             MappedEntity {
                 tanuki_id: "balcony_door.open".into(),
+                tags: vec![],
                 from_hass: vec![
                     EntityDataMapping::State {
                         from_id: "binary_sensor.myggbett_door_window_sensor_door".into(),
@@ -110,6 +118,7 @@ async fn main() {
             },
             MappedEntity {
                 tanuki_id: "rodret_remote_1".into(),
+                tags: vec![],
                 from_hass: vec![EntityDataMapping::ZhaCommands {
                     device_ieee: "88:0f:62:ff:fe:4f:86:e1".to_owned(),
                     translations: vec![
@@ -155,6 +164,7 @@ async fn main() {
             },
             MappedEntity {
                 tanuki_id: "symfonisk_remote_1".into(),
+                tags: vec![],
                 from_hass: vec![EntityDataMapping::ZhaCommands {
                     device_ieee: "94:de:b8:ff:fe:53:fd:97".to_owned(),
                     translations: vec![
@@ -256,20 +266,24 @@ async fn main() {
             },
         ];
 
-        const HASS_LIGHTS: [(&str, &str); 8] = [
-            ("north_lamp", "light.north_light"),
-            ("south_lamp", "light.kajplats_e27_cws_globe_1055lm"),
-            ("cabinet_strip", "light.cabinet_strip_light"),
-            ("couch_strip", "light.couch_strip"),
-            ("bed_strip", "light.bed_strip_light"),
-            ("cabinet_lamp", "light.cabinet_lamp_light"),
-            ("cabinet_extra_lamp", "light.ikea_of_sweden_tradfri_driver_30w_light"),
-            ("kitchen_lamp", "light.kitchen_light"),
+        const MAIN_LIGHT: &[&str] = &["room.living_room", "misc.main_light"];
+        const EXTRA_LIGHT: &[&str] = &["room.living_room"];
+
+        const HASS_LIGHTS: [(&str, &str, &[&str]); 8] = [
+            ("north_lamp", "light.north_light", MAIN_LIGHT),
+            ("south_lamp", "light.kajplats_e27_cws_globe_1055lm", MAIN_LIGHT),
+            ("cabinet_strip", "light.cabinet_strip_light", MAIN_LIGHT),
+            ("couch_strip", "light.couch_strip", MAIN_LIGHT),
+            ("bed_strip", "light.bed_strip_light", MAIN_LIGHT),
+            ("cabinet_lamp", "light.cabinet_lamp_light", EXTRA_LIGHT),
+            ("cabinet_extra_lamp", "light.ikea_of_sweden_tradfri_driver_30w_light", EXTRA_LIGHT),
+            ("kitchen_lamp", "light.kitchen_light", EXTRA_LIGHT),
         ];
 
-        for (tanuki, hass) in HASS_LIGHTS {
+        for (tanuki, hass, tags) in HASS_LIGHTS {
             mappings.push(MappedEntity {
                 tanuki_id: tanuki.into(),
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
                 from_hass: vec![EntityDataMapping::State {
                     from_id: hass.into(),
                     map_to: CapMapping::Light,
@@ -289,7 +303,7 @@ async fn main() {
 
         tokio::spawn(async move {
             tanuki_hass::bridge(
-                "192.168.0.106:1883",
+                BROKER_URL,
                 std::env::var("HASS_HOST").unwrap().as_str(),
                 std::env::var("HASS_TOKEN").unwrap().as_str(),
                 mappings,
@@ -299,18 +313,20 @@ async fn main() {
         });
 
         tokio::spawn(async move {
-            let tanuki: Arc<TanukiConnection> =
-                TanukiConnection::connect("tanuki", "192.168.0.106:1883")
-                    .await
-                    .unwrap();
+            let tanuki: Arc<TanukiConnection> = TanukiConnection::connect("tanuki", BROKER_URL)
+                .await
+                .unwrap();
+
+            let handler = Arc::new(Handler::new(tanuki.clone()).await.unwrap());
 
-            let mut handler = Handler::new(tanuki.clone());
+            let Err(e) = tanuki
+                .listener()
+                .handle_async::<SensorEvent>(handler.clone(), Concurrency::Parallel)
+                .handle_async::<ButtonEvent>(handler, Concurrency::SerialPerEntity)
+                .listen()
+                .await;
 
-            loop {
-                let msg = tanuki.recv().await.unwrap();
-                msg.try_handle::<SensorEvent>(&mut handler);
-                msg.try_handle::<ButtonEvent>(&mut handler);
-            }
+            panic!("Listener stopped: {e}");
         });
     }
 
@@ -5,13 +5,13 @@ use std::{
 };
 
 use egui::{
-    Align, Button, CentralPanel, Layout, Margin, ScrollArea, SidePanel, TextWrapMode,
+    Align, Button, CentralPanel, Layout, Margin, RichText, ScrollArea, SidePanel, TextWrapMode,
     ahash::{HashMap, HashMapExt as _},
     vec2,
 };
 use tanuki::{
     PublishEvent, TanukiConnection,
-    capabilities::{User, media::Media, on_off::OnOff},
+    capabilities::{User, media::Media, on_off::OnOff, scene::SceneTrigger},
 };
 use tanuki_common::{
     EntityId, Topic,
@@ -20,8 +20,10 @@ use tanuki_common::{
         light::LightState,
         media::{MediaCapabilities, MediaCommand, MediaState, MediaStatus},
         on_off::OnOffCommand,
+        scene::{Scene, TargetCommand},
         sensor::SensorValue,
     },
+    meta::EntityStatus,
 };
 
 pub struct TanukiApp {
@@ -29,6 +31,7 @@ pub struct TanukiApp {
     tanuki: Arc<TanukiConnection>,
     tokio_rt: tokio::runtime::Handle,
     entities: HashMap<EntityId, TanukiEntity>,
+    client_statuses: HashMap<String, EntityStatus>,
     selected_entity: Option<EntityId>,
     selected_capability: Option<String>,
 }
@@ -36,10 +39,25 @@ pub struct TanukiApp {
 pub struct TanukiEntity {
     pub id: EntityId,
     pub name: Option<String>,
+    pub status: Option<EntityStatus>,
+    pub client: Option<String>,
     pub capabilities: HashMap<String, TanukiCapability>,
 }
 
 impl TanukiEntity {
+    pub fn effective_status(
+        &self,
+        client_statuses: &HashMap<String, EntityStatus>,
+    ) -> Option<EntityStatus> {
+        let client_status = self
+            .client
+            .as_ref()
+            .and_then(|client| client_statuses.get(client).copied());
+
+        self.status
+            .map(|status| status.with_client_status(client_status))
+    }
+
     pub fn capability_mut(&mut self, name: &str) -> Option<&mut TanukiCapability> {
         match self.capabilities.entry(name.to_string()) {
             Entry::Occupied(entry) => Some(entry.into_mut()),
@@ -59,6 +77,7 @@ pub enum TanukiCapability {
     Light(TanukiLightState),
     Media(TanukiMediaState),
     OnOff(TanukiOnOffState),
+    Scene(TanukiSceneState),
     Sensor(TanukiSensorState),
 }
 
@@ -69,6 +88,7 @@ impl TanukiCapability {
             "tanuki.light" => Some(TanukiCapability::Light(Default::default())),
             "tanuki.media" => Some(TanukiCapability::Media(Default::default())),
             "tanuki.on_off" => Some(TanukiCapability::OnOff(Default::default())),
+            "tanuki.scene" => Some(TanukiCapability::Scene(Default::default())),
             "tanuki.sensor" => Some(TanukiCapability::Sensor(Default::default())),
             _ => None,
         }
@@ -102,6 +122,11 @@ pub struct TanukiMediaState {
     pub state: MediaState,
 }
 
+#[derive(Default)]
+pub struct TanukiSceneState {
+    pub scene: Option<Scene>,
+}
+
 #[derive(Default)]
 pub struct TanukiButtonsState {
     pub buttons: HashMap<String, Timeline<ButtonAction>>,
@@ -147,9 +172,10 @@ impl TanukiApp {
         let ctx = cc.egui_ctx.clone();
         std::thread::spawn(move || {
             rt.block_on(async {
-                let tanuki = tanuki::TanukiConnection::connect("tanuki-app", "192.168.0.106:1883")
-                    .await
-                    .unwrap();
+                let tanuki =
+                    tanuki::TanukiConnection::connect("tanuki-app", "mqtt://192.168.0.106:1883")
+                        .await
+                        .unwrap();
 
                 tanuki_tx.send(tanuki.clone()).unwrap();
 
@@ -186,6 +212,7 @@ impl TanukiApp {
             tanuki,
             tokio_rt,
             entities: HashMap::new(),
+            client_statuses: HashMap::new(),
             selected_entity: None,
             selected_capability: None,
         }
@@ -197,6 +224,8 @@ impl TanukiApp {
             .or_insert_with(|| TanukiEntity {
                 id,
                 name: None,
+                status: None,
+                client: None,
                 capabilities: HashMap::new(),
             })
     }
@@ -211,6 +240,21 @@ impl eframe::App for TanukiApp {
                         self.entity_mut(entity).name = Some(name.to_owned());
                     }
                 }
+                Topic::EntityMeta { entity, key } if key == "status" => {
+                    if let Ok(status) = serde_json::from_value::<EntityStatus>(packet.payload) {
+                        self.entity_mut(entity).status = Some(status);
+                    }
+                }
+                Topic::EntityMeta { entity, key } if key == "client" => {
+                    if let Some(client) = packet.payload.as_str() {
+                        self.entity_mut(entity).client = Some(client.to_owned());
+                    }
+                }
+                Topic::ClientMeta { client, key } if key == "status" => {
+                    if let Ok(status) = serde_json::from_value::<EntityStatus>(packet.payload) {
+                        self.client_statuses.insert(client.to_string(), status);
+                    }
+                }
                 Topic::CapabilityMeta { entity, capability, key } if key == "version" => {
                     log::info!("New capability: {entity} / {capability}");
                     if let Some(cap) = TanukiCapability::new_from_name(&capability) {
@@ -261,6 +305,18 @@ impl eframe::App for TanukiApp {
                         state.on.update(on);
                     }
                 }
+                Topic::CapabilityData { entity, capability, rest }
+                    if capability == "tanuki.scene" && rest == "scene" =>
+                {
+                    if let Some(TanukiCapability::Scene(state)) = self
+                        .entity_mut(entity)
+                        .capabilities
+                        .get_mut(capability.as_str())
+                        && let Ok(scene) = serde_json::from_value::<Scene>(packet.payload)
+                    {
+                        state.scene = Some(scene);
+                    }
+                }
                 _ => {}
             }
         }
@@ -272,10 +328,20 @@ impl eframe::App for TanukiApp {
                 ScrollArea::vertical().show(ui, |ui| {
                     ui.with_layout(Layout::top_down_justified(Align::Min), |ui| {
                         for (entity_id, entity) in &self.entities {
+                            let mut label =
+                                RichText::new(entity.name.as_deref().unwrap_or(entity_id.as_str()));
+
+                            // grey out entities that are not (or no longer) providing valid data
+                            if entity.effective_status(&self.client_statuses)
+                                != Some(EntityStatus::Online)
+                            {
+                                label = label.weak();
+                            }
+
                             ui.selectable_value(
                                 &mut self.selected_entity,
                                 Some(entity_id.clone()),
-                                entity.name.as_deref().unwrap_or(entity_id.as_str()),
+                                label,
                             );
                         }
                     });
@@ -375,6 +441,40 @@ impl eframe::App for TanukiApp {
                             });
                         }
                     }
+                    TanukiCapability::Scene(state) => {
+                        if let Some(scene) = &state.scene {
+                            for target in &scene.targets {
+                                ui.label(match &target.command {
+                                    TargetCommand::Light(cmd) if cmd.on => match cmd.brightness {
+                                        Some(brightness) => format!(
+                                            "{}: on, {:.0}%",
+                                            target.entity,
+                                            brightness * 100.
+                                        ),
+                                        None => format!("{}: on", target.entity),
+                                    },
+                                    TargetCommand::Light(_) => format!("{}: off", target.entity),
+                                    TargetCommand::OnOff(cmd) => {
+                                        format!("{}: {cmd:?}", target.entity)
+                                    }
+                                });
+                            }
+
+                            ui.add_space(8.);
+                        }
+
+                        if ui.button("Activate").clicked() {
+                            let tanuki = self.tanuki.clone();
+                            let entity = selected_entity_id.clone();
+                            self.tokio_rt.spawn(async move {
+                                tanuki
+                                    .entity_cap::<SceneTrigger<User>>(entity)
+                                    .activate()
+                                    .await
+                                    .unwrap();
+                            });
+                        }
+                    }
                     TanukiCapability::Sensor(_state) => {
                         ui.heading("todo");
                     }
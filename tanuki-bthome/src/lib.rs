@@ -1,11 +1,18 @@
-use std::collections::{HashMap, hash_map::Entry};
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    time::Duration,
+};
 
 use heck::ToSnakeCase as _;
 use tanuki::{
     TanukiConnection,
-    capabilities::{Authority, sensor::Sensor},
+    capabilities::{
+        Authority,
+        sensor::{Sensor, SensorPublisher},
+    },
+    operators::Throttle,
 };
-use tanuki_common::{capabilities::sensor::SensorPayload, meta};
+use tanuki_common::{capabilities::sensor::SensorPayload, meta, validate_id};
 
 mod bthome;
 
@@ -19,8 +26,21 @@ pub enum Error {
     Tanuki(#[from] tanuki::Error),
 }
 
+/// Devices advertise every few seconds, publish each reading at most this often
+///
+/// Throttled rather than deadbanded, so a steady reading keeps a fresh timestamp.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Device {
+    sensor: Sensor<Authority>,
+    publishers: HashMap<&'static str, SensorPublisher>,
+}
+
+/// Publish BTHome sensors to the broker at `broker_url`
+///
+/// `id_map` maps BLE names or addresses to an entity ID and display name.
 pub async fn bridge(
-    addr: &str,
+    broker_url: &str,
     id_map: impl IntoIterator<Item = (impl AsRef<str>, impl AsRef<str>, impl AsRef<str>)>,
 ) -> Result<()> {
     let id_map = id_map
@@ -30,22 +50,11 @@ pub async fn bridge(
         })
         .collect::<HashMap<_, _>>();
 
-    let tanuki = TanukiConnection::connect("tanuki-bthome", addr).await?;
-
-    tokio::spawn({
-        let tanuki = tanuki.clone();
-
-        async move {
-            loop {
-                let packet = tanuki.recv_raw().await;
-                tracing::debug!("Received packet: {packet:?}");
-            }
-        }
-    });
+    let tanuki = TanukiConnection::connect("tanuki-bthome", broker_url).await?;
 
     let mut updates = bthome::event_stream().await?;
 
-    let mut devices = HashMap::<String, Sensor<Authority>>::new();
+    let mut devices = HashMap::<String, Device>::new();
 
     loop {
         let update = updates
@@ -56,8 +65,8 @@ pub async fn bridge(
         tracing::debug!("BTHome update: {update:#?}");
 
         let entry = devices.entry(update.address.clone());
-        let sensor = match entry {
-            Entry::Occupied(entry) => entry,
+        let (device, init) = match entry {
+            Entry::Occupied(entry) => (entry.into_mut(), None),
             Entry::Vacant(entry) => {
                 tracing::info!(?update.name, ?update.address, "Registering new device");
 
@@ -65,7 +74,7 @@ pub async fn bridge(
                     .get(update.name.as_str())
                     .or_else(|| id_map.get(update.address.as_str()))
                     .cloned()
-                    .unwrap_or((update.name.to_snake_case(), update.name));
+                    .unwrap_or_else(|| (fallback_id(&update.name, &update.address), update.name));
 
                 let entity = tanuki.author_entity(id).await?;
 
@@ -78,19 +87,52 @@ pub async fn bridge(
                     .await?;
 
                 let sensor = entity.author_capability::<Sensor<_>>().await?;
-                entry.insert_entry(sensor)
+                let device = Device { sensor, publishers: HashMap::new() };
+                (entry.insert(device), Some(entity))
             }
         };
 
         for object in &update.objects {
-            sensor
-                .get()
-                .publish(object.topic(), SensorPayload {
-                    value: object.value(),
-                    unit: object.unit().into(),
-                    timestamp: update.timestamp,
-                })
-                .await?;
+            let payload = SensorPayload {
+                value: object.value(),
+                unit: object.unit().into(),
+                timestamp: update.timestamp,
+            };
+
+            match device.publishers.entry(object.topic()) {
+                Entry::Occupied(publisher) => publisher.get().publish(payload),
+                // the first reading is published right away, so it's there before the device
+                // is marked online
+                Entry::Vacant(entry) => {
+                    device.sensor.publish(object.topic(), payload).await?;
+                    entry.insert(
+                        device
+                            .sensor
+                            .publisher(object.topic(), Throttle::new(PUBLISH_INTERVAL)),
+                    );
+                }
+            }
+        }
+
+        // only mark new devices online once their first readings are published
+        if let Some(entity) = init {
+            entity.ready().await?;
         }
     }
 }
+
+/// Entity ID for a device missing from the ID map, from its BLE name or else its address
+///
+/// Characters that aren't allowed in entity IDs are dropped, see [`validate_id`].
+fn fallback_id(name: &str, address: &str) -> String {
+    [name, address]
+        .into_iter()
+        .map(|s| {
+            s.to_snake_case()
+                .chars()
+                .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '_')
+                .collect::<String>()
+        })
+        .find(|id| validate_id(id).is_ok())
+        .unwrap_or_else(|| "bthome".into())
+}
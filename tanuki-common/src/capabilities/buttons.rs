@@ -2,6 +2,9 @@
 //!
 //! Each topic represents a physical button, and sends actions for button presses.
 //!
+//! Devices send whichever actions they can detect. Some only report presses, others also
+//! releases or gestures like double presses.
+//!
 //! # Example Entity
 //!
 //! ```plain
@@ -16,8 +19,16 @@ use serde::{Deserialize, Serialize};
 pub enum ButtonAction {
     /// Button was pressed
     Pressed,
+    /// Button was let go
+    Released,
+    /// Button was pressed twice in quick succession
+    DoublePressed,
+    /// Button was pressed three times in quick succession
+    TriplePressed,
     /// Button was held down for some time
     LongPressed,
+    /// Button is still held down after a long press, sent repeatedly
+    Held,
 }
 
 #[cfg(test)]
@@ -30,5 +41,13 @@ mod tests {
             serde_json::to_value(ButtonAction::Pressed).unwrap(),
             serde_json::json!("pressed")
         );
+        assert_eq!(
+            serde_json::to_value(ButtonAction::DoublePressed).unwrap(),
+            serde_json::json!("double_pressed")
+        );
+        assert_eq!(
+            serde_json::to_value(ButtonAction::LongPressed).unwrap(),
+            serde_json::json!("long_pressed")
+        );
     }
 }
@@ -2,11 +2,12 @@ use alloc::{vec, vec::Vec};
 
 use serde::{Deserialize, Serialize};
 
+use super::ids;
 use crate::{Property, property};
 
 pub trait LightProperty: Property {}
 
-#[property(LightProperty, State, key = "state")]
+#[property(LightProperty, State, capability = ids::LIGHT, key = "state")]
 pub struct LightState {
     /// Should also be provided by tanuki.on_off
     pub on: bool,
@@ -17,7 +18,7 @@ pub struct LightState {
     pub color: Option<Color>,
 }
 
-#[property(LightProperty, Command, key = "command")]
+#[property(LightProperty, Command, capability = ids::LIGHT, key = "command")]
 pub struct LightCommand {
     pub on: bool,
     /// Brightness level (0.0-1.0)
@@ -2,11 +2,12 @@ use alloc::{string::String, vec::Vec};
 
 use serde::{Deserialize, Serialize};
 
+use super::ids;
 use crate::{Property, property};
 
 pub trait MediaProperty: Property {}
 
-#[property(MediaProperty, State, key = "capabilities")]
+#[property(MediaProperty, State, capability = ids::MEDIA, key = "capabilities")]
 #[derive(Default)]
 #[non_exhaustive]
 pub struct MediaCapabilities {
@@ -20,7 +21,7 @@ pub struct MediaCapabilities {
     pub shuffle: bool,
 }
 
-#[property(MediaProperty, State, key = "state")]
+#[property(MediaProperty, State, capability = ids::MEDIA, key = "state")]
 #[derive(Default)]
 #[non_exhaustive]
 pub struct MediaState {
@@ -82,7 +83,7 @@ pub struct MediaInfo {
     pub live: bool,
 }
 
-#[property(MediaProperty, Command, key = "command")]
+#[property(MediaProperty, Command, capability = ids::MEDIA, key = "command")]
 #[serde(tag = "type", rename_all = "snake_case")]
 #[non_exhaustive]
 pub enum MediaCommand {
@@ -2,6 +2,7 @@ pub mod buttons;
 pub mod light;
 pub mod media;
 pub mod on_off;
+pub mod scene;
 pub mod sensor;
 
 pub mod ids {
@@ -9,5 +10,6 @@ pub mod ids {
     pub const LIGHT: &str = "tanuki.light";
     pub const MEDIA: &str = "tanuki.media";
     pub const ON_OFF: &str = "tanuki.on_off";
+    pub const SCENE: &str = "tanuki.scene";
     pub const SENSOR: &str = "tanuki.sensor";
 }
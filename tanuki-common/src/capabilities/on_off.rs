@@ -10,16 +10,17 @@
 //! ../tanuki.on_off/command       <- "on" | "off" | "toggle"
 //! ```
 
+use super::ids;
 use crate::{Property, property};
 
 pub trait OnOffProperty: Property {}
 pub trait OnOffCommandTrait: Property {}
 
-#[property(OnOffProperty, State, key = "on")]
+#[property(OnOffProperty, State, capability = ids::ON_OFF, key = "on")]
 #[derive(Copy, Eq)]
 pub struct On(pub bool);
 
-#[property(OnOffProperty, Command, key = "command")]
+#[property(OnOffProperty, Command, capability = ids::ON_OFF, key = "command")]
 #[derive(Copy, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum OnOffCommand {
@@ -0,0 +1,111 @@
+//! A set of commands that are sent together, such as "evening" or "movie night"
+//!
+//! The scene is published as state, so clients can see what it does, and applied by sending
+//! `activate`.
+//!
+//! # Example Entity
+//!
+//! ```plain
+//! ../tanuki.scene/$meta/version => 1
+//! ../tanuki.scene/scene          -> { "targets": [{ "entity": "north_lamp", "capability": "tanuki.light", "command": { "on": true, "brightness": 0.4 } }] }
+//! ../tanuki.scene/command        <- "activate"
+//! ```
+
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use super::{ids, light::LightCommand, on_off::OnOffCommand};
+use crate::{EntityId, Property, property};
+
+pub trait SceneProperty: Property {}
+
+#[property(SceneProperty, State, capability = ids::SCENE, key = "scene")]
+#[derive(Default)]
+pub struct Scene {
+    pub targets: Vec<SceneTarget>,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn light(mut self, entity: impl Into<EntityId>, command: LightCommand) -> Self {
+        self.targets.push(SceneTarget {
+            entity: entity.into(),
+            command: TargetCommand::Light(command),
+        });
+        self
+    }
+
+    pub fn on_off(mut self, entity: impl Into<EntityId>, command: OnOffCommand) -> Self {
+        self.targets.push(SceneTarget {
+            entity: entity.into(),
+            command: TargetCommand::OnOff(command),
+        });
+        self
+    }
+}
+
+/// A command to send to one entity when the scene is applied
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneTarget {
+    pub entity: EntityId,
+    #[serde(flatten)]
+    pub command: TargetCommand,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "capability", content = "command")]
+pub enum TargetCommand {
+    #[serde(rename = "tanuki.light")]
+    Light(LightCommand),
+    #[serde(rename = "tanuki.on_off")]
+    OnOff(OnOffCommand),
+}
+
+#[property(SceneProperty, Command, capability = ids::SCENE, key = "command")]
+#[derive(Copy, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneCommand {
+    Activate,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scene_format() {
+        let scene = Scene::new()
+            .light("north_lamp", LightCommand {
+                on: true,
+                brightness: Some(0.5),
+                color: None,
+            })
+            .on_off("desk", OnOffCommand::Off);
+
+        let json = serde_json::json!({
+            "targets": [
+                {
+                    "entity": "north_lamp",
+                    "capability": "tanuki.light",
+                    "command": { "on": true, "brightness": 0.5 },
+                },
+                {
+                    "entity": "desk",
+                    "capability": "tanuki.on_off",
+                    "command": "off",
+                },
+            ]
+        });
+
+        assert_eq!(serde_json::to_value(&scene).unwrap(), json);
+        assert_eq!(serde_json::from_value::<Scene>(json).unwrap(), scene);
+        assert_eq!(
+            serde_json::to_value(SceneCommand::Activate).unwrap(),
+            serde_json::json!("activate")
+        );
+    }
+}
@@ -34,6 +34,18 @@ pub enum SensorValue {
     Boolean(bool),
 }
 
+impl From<f32> for SensorValue {
+    fn from(value: f32) -> Self {
+        Self::Number(value)
+    }
+}
+
+impl From<bool> for SensorValue {
+    fn from(value: bool) -> Self {
+        Self::Boolean(value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
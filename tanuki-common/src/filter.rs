@@ -0,0 +1,563 @@
+use core::{fmt::Display, str::FromStr};
+
+use crate::{EntityId, TanukiString, ToTanukiString, Topic, validate_id};
+
+/// A single topic level in a [`TopicFilter`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Level {
+    /// `+`, matches any value
+    Any,
+    Exact(TanukiString),
+}
+
+impl Level {
+    /// Fails if `name` is not a valid topic level, see [`validate_name`]
+    fn exact(name: &str) -> Result<Self, &'static str> {
+        validate_name(name)?;
+        Ok(Level::Exact(name.to_tanuki_string()))
+    }
+
+    /// Fails if `id` is not a valid entity ID or capability name, see [`validate_id`]
+    fn id(id: &str) -> Result<Self, &'static str> {
+        validate_id(id)?;
+        Ok(Level::Exact(id.to_tanuki_string()))
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Level::Any => true,
+            Level::Exact(exact) => exact == value,
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Level::Any => f.write_str("+"),
+            Level::Exact(exact) => exact.fmt(f),
+        }
+    }
+}
+
+/// The remaining topic levels in a [`TopicFilter`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Rest {
+    /// `#`, matches anything
+    Any,
+    Exact(TanukiString),
+}
+
+impl Rest {
+    /// Fails if any level of `path` is not valid, see [`validate_name`]
+    fn exact(path: &str) -> Result<Self, &'static str> {
+        for level in path.split('/') {
+            validate_name(level)?;
+        }
+
+        Ok(Rest::Exact(path.to_tanuki_string()))
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Rest::Any => true,
+            Rest::Exact(exact) => exact == value,
+        }
+    }
+}
+
+impl Display for Rest {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Rest::Any => f.write_str("#"),
+            Rest::Exact(exact) => exact.fmt(f),
+        }
+    }
+}
+
+/// Check that `name` can be used as a single topic level, such as a metadata key or client
+///
+/// Entity IDs and capability names follow the stricter [`validate_id`].
+pub fn validate_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() {
+        Err("empty")
+    } else if name.contains('/') {
+        Err("contains '/'")
+    } else if name.contains(['+', '#']) {
+        Err("contains a wildcard")
+    } else {
+        Ok(())
+    }
+}
+
+/// A set of topics, with the same shapes as [`Topic`]
+///
+/// Built from one of the constructors, which match any entity/client and key, and narrowed down
+/// with the builder methods:
+///
+/// ```
+/// # use tanuki_common::{TopicFilter, capabilities::ids};
+/// let filter = TopicFilter::capability_data(ids::SENSOR)?
+///     .any_entity()
+///     .key("temperature")?;
+///
+/// assert_eq!(filter.to_string(), "tanuki/entities/+/tanuki.sensor/temperature");
+/// # Ok::<(), &'static str>(())
+/// ```
+///
+/// Constructors and builder methods taking a name fail if it isn't valid, see [`validate_id`] for
+/// entities and capabilities and [`validate_name`] for everything else.
+///
+/// MQTT wildcards can't tell `$meta` apart from other levels, so the broker may deliver more
+/// than a filter [`matches`](Self::matches), eg. `tanuki/entities/+/+/#` also covers capability
+/// metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TopicFilter {
+    /// Every tanuki topic
+    All,
+    EntityMeta {
+        entity: Level,
+        key: Level,
+    },
+    CapabilityMeta {
+        entity: Level,
+        capability: Level,
+        key: Level,
+    },
+    CapabilityData {
+        entity: Level,
+        capability: Level,
+        rest: Rest,
+    },
+    ClientMeta {
+        client: Level,
+        key: Level,
+    },
+    ClientData {
+        client: Level,
+        rest: Rest,
+    },
+}
+
+impl TopicFilter {
+    pub fn all() -> Self {
+        TopicFilter::All
+    }
+
+    /// Metadata of any entity
+    pub fn entity_meta() -> Self {
+        TopicFilter::EntityMeta { entity: Level::Any, key: Level::Any }
+    }
+
+    /// Metadata of `capability` on any entity
+    pub fn capability_meta(capability: &str) -> Result<Self, &'static str> {
+        Ok(TopicFilter::CapabilityMeta {
+            entity: Level::Any,
+            capability: Level::id(capability)?,
+            key: Level::Any,
+        })
+    }
+
+    /// Metadata of every capability on any entity
+    pub fn any_capability_meta() -> Self {
+        TopicFilter::CapabilityMeta {
+            entity: Level::Any,
+            capability: Level::Any,
+            key: Level::Any,
+        }
+    }
+
+    /// Data of `capability` on any entity
+    pub fn capability_data(capability: &str) -> Result<Self, &'static str> {
+        Ok(TopicFilter::CapabilityData {
+            entity: Level::Any,
+            capability: Level::id(capability)?,
+            rest: Rest::Any,
+        })
+    }
+
+    /// Data of every capability on any entity
+    pub fn any_capability_data() -> Self {
+        TopicFilter::CapabilityData {
+            entity: Level::Any,
+            capability: Level::Any,
+            rest: Rest::Any,
+        }
+    }
+
+    /// Metadata of any client
+    pub fn client_meta() -> Self {
+        TopicFilter::ClientMeta { client: Level::Any, key: Level::Any }
+    }
+
+    /// Data of any client
+    pub fn client_data() -> Self {
+        TopicFilter::ClientData { client: Level::Any, rest: Rest::Any }
+    }
+
+    fn entity_level(&mut self) -> Option<&mut Level> {
+        match self {
+            TopicFilter::EntityMeta { entity, .. }
+            | TopicFilter::CapabilityMeta { entity, .. }
+            | TopicFilter::CapabilityData { entity, .. } => Some(entity),
+            _ => None,
+        }
+    }
+
+    /// Only match topics of `entity`
+    ///
+    /// Does nothing for filters that aren't about entities.
+    pub fn entity(mut self, entity: &EntityId) -> Result<Self, &'static str> {
+        if let Some(level) = self.entity_level() {
+            *level = Level::id(entity)?;
+        }
+        Ok(self)
+    }
+
+    /// Match topics of any entity
+    pub fn any_entity(mut self) -> Self {
+        if let Some(level) = self.entity_level() {
+            *level = Level::Any;
+        }
+        self
+    }
+
+    /// Only match topics of `client`
+    ///
+    /// Does nothing for filters that aren't about clients.
+    pub fn client(mut self, client: &str) -> Result<Self, &'static str> {
+        if let TopicFilter::ClientMeta { client: level, .. }
+        | TopicFilter::ClientData { client: level, .. } = &mut self
+        {
+            *level = Level::exact(client)?;
+        }
+        Ok(self)
+    }
+
+    /// Only match the metadata key, or data path, `key`
+    ///
+    /// Data paths may have multiple levels, eg. `state/brightness`.
+    pub fn key(mut self, key: &str) -> Result<Self, &'static str> {
+        match &mut self {
+            TopicFilter::All => {}
+            TopicFilter::EntityMeta { key: level, .. }
+            | TopicFilter::CapabilityMeta { key: level, .. }
+            | TopicFilter::ClientMeta { key: level, .. } => *level = Level::exact(key)?,
+            TopicFilter::CapabilityData { rest, .. } | TopicFilter::ClientData { rest, .. } => {
+                *rest = Rest::exact(key)?
+            }
+        }
+        Ok(self)
+    }
+
+    /// Match any metadata key or data path
+    pub fn any_key(mut self) -> Self {
+        match &mut self {
+            TopicFilter::All => {}
+            TopicFilter::EntityMeta { key: level, .. }
+            | TopicFilter::CapabilityMeta { key: level, .. }
+            | TopicFilter::ClientMeta { key: level, .. } => *level = Level::Any,
+            TopicFilter::CapabilityData { rest, .. } | TopicFilter::ClientData { rest, .. } => {
+                *rest = Rest::Any
+            }
+        }
+        self
+    }
+
+    pub fn matches(&self, topic: &Topic) -> bool {
+        match (self, topic) {
+            (TopicFilter::All, _) => true,
+            (TopicFilter::EntityMeta { entity: e, key: k }, Topic::EntityMeta { entity, key }) => {
+                e.matches(entity) && k.matches(key)
+            }
+            (
+                TopicFilter::CapabilityMeta { entity: e, capability: c, key: k },
+                Topic::CapabilityMeta { entity, capability, key },
+            ) => e.matches(entity) && c.matches(capability) && k.matches(key),
+            (
+                TopicFilter::CapabilityData { entity: e, capability: c, rest: r },
+                Topic::CapabilityData { entity, capability, rest },
+            ) => e.matches(entity) && c.matches(capability) && r.matches(rest),
+            (TopicFilter::ClientMeta { client: c, key: k }, Topic::ClientMeta { client, key }) => {
+                c.matches(client) && k.matches(key)
+            }
+            (
+                TopicFilter::ClientData { client: c, rest: r },
+                Topic::ClientData { client, rest },
+            ) => c.matches(client) && r.matches(rest),
+            _ => false,
+        }
+    }
+}
+
+impl From<Topic> for TopicFilter {
+    fn from(topic: Topic) -> Self {
+        match topic {
+            Topic::EntityMeta { entity, key } => TopicFilter::EntityMeta {
+                entity: Level::Exact(entity.0),
+                key: Level::Exact(key),
+            },
+            Topic::CapabilityMeta { entity, capability, key } => TopicFilter::CapabilityMeta {
+                entity: Level::Exact(entity.0),
+                capability: Level::Exact(capability),
+                key: Level::Exact(key),
+            },
+            Topic::CapabilityData { entity, capability, rest } => TopicFilter::CapabilityData {
+                entity: Level::Exact(entity.0),
+                capability: Level::Exact(capability),
+                rest: Rest::Exact(rest),
+            },
+            Topic::ClientMeta { client, key } => TopicFilter::ClientMeta {
+                client: Level::Exact(client),
+                key: Level::Exact(key),
+            },
+            Topic::ClientData { client, rest } => TopicFilter::ClientData {
+                client: Level::Exact(client),
+                rest: Rest::Exact(rest),
+            },
+        }
+    }
+}
+
+impl Display for TopicFilter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TopicFilter::All => f.write_str("tanuki/#"),
+            TopicFilter::EntityMeta { entity, key } => {
+                write!(f, "tanuki/entities/{}/$meta/{}", entity, key)
+            }
+            TopicFilter::CapabilityMeta { entity, capability, key } => {
+                write!(f, "tanuki/entities/{}/{}/$meta/{}", entity, capability, key)
+            }
+            TopicFilter::CapabilityData { entity, capability, rest } => {
+                write!(f, "tanuki/entities/{}/{}/{}", entity, capability, rest)
+            }
+            TopicFilter::ClientMeta { client, key } => {
+                write!(f, "tanuki/clients/{}/$meta/{}", client, key)
+            }
+            TopicFilter::ClientData { client, rest } => {
+                write!(f, "tanuki/clients/{}/{}", client, rest)
+            }
+        }
+    }
+}
+
+impl FromStr for TopicFilter {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn level(s: Option<&str>) -> Result<Level, &'static str> {
+            match s {
+                Some("+") => Ok(Level::Any),
+                Some(name) => Level::exact(name),
+                None => Err("missing topic level"),
+            }
+        }
+
+        fn id_level(s: Option<&str>) -> Result<Level, &'static str> {
+            match s {
+                Some("+") => Ok(Level::Any),
+                Some(id) => Level::id(id),
+                None => Err("missing topic level"),
+            }
+        }
+
+        fn last_level<'a>(mut parts: impl Iterator<Item = &'a str>) -> Result<Level, &'static str> {
+            let level = level(parts.next())?;
+            match parts.next() {
+                Some(_) => Err("too many topic levels"),
+                None => Ok(level),
+            }
+        }
+
+        fn rest(first: &str, remainder: Option<&str>) -> Result<Rest, &'static str> {
+            match (first, remainder) {
+                ("#", None) => Ok(Rest::Any),
+                (_, Some(remainder)) => Rest::exact(&(first.to_tanuki_string() + "/" + remainder)),
+                (_, None) => Rest::exact(first),
+            }
+        }
+
+        let mut parts = s.split('/');
+        if parts.next() != Some("tanuki") {
+            return Err("does not start with tanuki/");
+        }
+
+        match parts.next() {
+            Some("#") if parts.next().is_none() => Ok(TopicFilter::All),
+            Some("entities") => {
+                let entity = id_level(parts.next())?;
+
+                match parts.next() {
+                    Some("$meta") => {
+                        Ok(TopicFilter::EntityMeta { entity, key: last_level(parts)? })
+                    }
+                    Some(capability) => {
+                        let capability = id_level(Some(capability))?;
+
+                        match parts.next() {
+                            Some("$meta") => Ok(TopicFilter::CapabilityMeta {
+                                entity,
+                                capability,
+                                key: last_level(parts)?,
+                            }),
+                            Some(first) => Ok(TopicFilter::CapabilityData {
+                                entity,
+                                capability,
+                                rest: rest(first, parts.remainder())?,
+                            }),
+                            None => Err("tanuki/entities/{id}/{cap}"),
+                        }
+                    }
+                    None => Err("tanuki/entities/{id}"),
+                }
+            }
+            Some("clients") => {
+                let client = level(parts.next())?;
+
+                match parts.next() {
+                    Some("$meta") => {
+                        Ok(TopicFilter::ClientMeta { client, key: last_level(parts)? })
+                    }
+                    Some(first) => Ok(TopicFilter::ClientData {
+                        client,
+                        rest: rest(first, parts.remainder())?,
+                    }),
+                    None => Err("tanuki/clients/{id}"),
+                }
+            }
+            Some(_) => Err("tanuki/..."),
+            None => Err("tanuki"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::ids;
+
+    #[test]
+    fn filter_display() -> Result<(), &'static str> {
+        assert_eq!(TopicFilter::all().to_string(), "tanuki/#");
+        assert_eq!(
+            TopicFilter::capability_data(ids::SENSOR)?
+                .any_entity()
+                .to_string(),
+            "tanuki/entities/+/tanuki.sensor/#"
+        );
+        assert_eq!(
+            TopicFilter::capability_meta(ids::ON_OFF)?
+                .entity(&EntityId::from("desk.lamp"))?
+                .key("version")?
+                .to_string(),
+            "tanuki/entities/desk.lamp/tanuki.on_off/$meta/version"
+        );
+        assert_eq!(
+            TopicFilter::client_meta().key("status")?.to_string(),
+            "tanuki/clients/+/$meta/status"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn filter_from_str() -> Result<(), &'static str> {
+        for filter in [
+            TopicFilter::all(),
+            TopicFilter::entity_meta().key("name")?,
+            TopicFilter::capability_meta(ids::LIGHT)?,
+            TopicFilter::capability_data(ids::SENSOR)?.key("temperature")?,
+            TopicFilter::any_capability_data().entity(&EntityId::from("desk.lamp"))?,
+            TopicFilter::client_meta().client("tanuki-hass")?,
+            TopicFilter::client_data().key("responses/17")?,
+        ] {
+            assert_eq!(filter.to_string().parse::<TopicFilter>(), Ok(filter));
+        }
+
+        assert!("tanuki/entities/#".parse::<TopicFilter>().is_err());
+        assert!(
+            "tanuki/entities/a+b/$meta/name"
+                .parse::<TopicFilter>()
+                .is_err()
+        );
+        assert!(
+            "tanuki/entities/+/$meta/name/extra"
+                .parse::<TopicFilter>()
+                .is_err()
+        );
+        assert!(
+            "tanuki/entities/+/tanuki.sensor/#/extra"
+                .parse::<TopicFilter>()
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn filter_matches() -> Result<(), &'static str> {
+        let temperature = "tanuki/entities/balcony/tanuki.sensor/temperature".parse::<Topic>()?;
+        let version = "tanuki/entities/balcony/tanuki.sensor/$meta/version".parse::<Topic>()?;
+
+        let sensors = TopicFilter::capability_data(ids::SENSOR)?.any_entity();
+        assert!(sensors.matches(&temperature));
+        assert!(!sensors.matches(&version));
+        assert!(TopicFilter::any_capability_data().matches(&temperature));
+        assert!(TopicFilter::all().matches(&version));
+
+        assert!(sensors.clone().key("temperature")?.matches(&temperature));
+        assert!(!sensors.clone().key("humidity")?.matches(&temperature));
+        assert!(
+            !sensors
+                .entity(&EntityId::from("kitchen"))?
+                .matches(&temperature)
+        );
+
+        assert!(TopicFilter::from(temperature.clone()).matches(&temperature));
+        assert!(!TopicFilter::from(version).matches(&temperature));
+        Ok(())
+    }
+
+    #[test]
+    fn filter_rejects_wildcard_names() -> Result<(), &'static str> {
+        let sensors = TopicFilter::capability_data(ids::SENSOR)?;
+        assert!(sensors.clone().entity(&EntityId::from("#")).is_err());
+        assert!(sensors.clone().key("state/+").is_err());
+        assert!(sensors.key("").is_err());
+        assert!(TopicFilter::capability_meta("tanuki/sensor").is_err());
+        assert!(TopicFilter::client_meta().client("+").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn filter_uses_id_grammar() {
+        assert!(
+            TopicFilter::any_capability_data()
+                .entity(&EntityId::from("Desk-Lamp"))
+                .is_err()
+        );
+        assert!(TopicFilter::capability_data("Tanuki.Sensor").is_err());
+        assert!(
+            "tanuki/entities/Desk-Lamp/$meta/name"
+                .parse::<TopicFilter>()
+                .is_err()
+        );
+        assert!(
+            "tanuki/entities/+/tanuki..sensor/#"
+                .parse::<TopicFilter>()
+                .is_err()
+        );
+
+        // keys and clients aren't IDs
+        assert!(TopicFilter::client_meta().client("tanuki-hass").is_ok());
+        assert!(TopicFilter::entity_meta().key("$type").is_ok());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(validate_name("tanuki.sensor"), Ok(()));
+        assert!(validate_name("").is_err());
+        assert!(validate_name("a/b").is_err());
+        assert!(validate_name("+").is_err());
+        assert!(validate_name("a#").is_err());
+    }
+}
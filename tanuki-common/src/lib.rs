@@ -6,6 +6,7 @@ extern crate alloc;
 use core::{fmt::Display, str::FromStr};
 
 pub mod capabilities;
+pub mod filter;
 pub mod macros;
 pub mod meta;
 
@@ -14,6 +15,7 @@ pub use serde as _serde;
 
 mod property;
 mod string;
+pub use filter::TopicFilter;
 pub use property::*;
 pub use string::*;
 
@@ -33,14 +35,33 @@ pub enum Topic {
         capability: TanukiString,
         rest: TanukiString,
     },
+    ClientMeta {
+        client: TanukiString,
+        key: TanukiString,
+    },
+    ClientData {
+        client: TanukiString,
+        rest: TanukiString,
+    },
 }
 
 impl Topic {
+    #[deprecated = "not a valid topic, use `TopicFilter::any_capability_data` instead"]
     pub const CAPABILITY_DATA_WILDCARD: Self = Self::CapabilityData {
-        entity: EntityId::WILDCARD,
+        entity: EntityId(TanukiString::const_new("+")),
         capability: TanukiString::const_new("+"),
         rest: TanukiString::const_new("#"),
     };
+
+    /// The entity this topic belongs to, if any
+    pub fn entity(&self) -> Option<&EntityId> {
+        match self {
+            Topic::EntityMeta { entity, .. }
+            | Topic::CapabilityMeta { entity, .. }
+            | Topic::CapabilityData { entity, .. } => Some(entity),
+            Topic::ClientMeta { .. } | Topic::ClientData { .. } => None,
+        }
+    }
 }
 
 impl Display for Topic {
@@ -55,6 +76,12 @@ impl Display for Topic {
             Topic::CapabilityData { entity, capability, rest } => {
                 write!(f, "tanuki/entities/{}/{}/{}", entity, capability, rest)
             }
+            Topic::ClientMeta { client, key } => {
+                write!(f, "tanuki/clients/{}/$meta/{}", client, key)
+            }
+            Topic::ClientData { client, rest } => {
+                write!(f, "tanuki/clients/{}/{}", client, rest)
+            }
         }
     }
 }
@@ -73,8 +100,8 @@ impl FromStr for Topic {
                 Some(entity) => match parts.next() {
                     Some("$meta") => match parts.next() {
                         Some(key) if parts.next().is_none() => Ok(Topic::EntityMeta {
-                            entity: EntityId::from(entity),
-                            key: key.to_tanuki_string(),
+                            entity: EntityId::try_new(entity)?,
+                            key: valid_name(key)?,
                         }),
                         Some(_) => Err("tanuki/entities/{id}/$meta/{key}/..."),
                         _ => Err("tanuki/entities/{id}/$meta"),
@@ -82,16 +109,16 @@ impl FromStr for Topic {
                     Some(capability) => match parts.next() {
                         Some("$meta") => match parts.next() {
                             Some(key) if parts.next().is_none() => Ok(Topic::CapabilityMeta {
-                                entity: EntityId::from(entity),
-                                capability: capability.to_tanuki_string(),
-                                key: key.to_tanuki_string(),
+                                entity: EntityId::try_new(entity)?,
+                                capability: valid_id(capability)?,
+                                key: valid_name(key)?,
                             }),
                             Some(_) => Err("tanuki/entities/{id}/{cap}/$meta/{key}/..."),
                             _ => Err("tanuki/entities/{id}/{cap}/$meta"),
                         },
                         Some(rest) => Ok(Topic::CapabilityData {
-                            entity: EntityId::from(entity),
-                            capability: capability.to_tanuki_string(),
+                            entity: EntityId::try_new(entity)?,
+                            capability: valid_id(capability)?,
                             rest: match parts.remainder() {
                                 Some(remainder) => rest.to_tanuki_string() + "/" + remainder,
                                 None => rest.to_tanuki_string(),
@@ -103,12 +130,43 @@ impl FromStr for Topic {
                 },
                 None => Err("tanuki/entities"),
             },
+            Some("clients") => match parts.next() {
+                Some(client) => match parts.next() {
+                    Some("$meta") => match parts.next() {
+                        Some(key) if parts.next().is_none() => Ok(Topic::ClientMeta {
+                            client: valid_name(client)?,
+                            key: valid_name(key)?,
+                        }),
+                        Some(_) => Err("tanuki/clients/{id}/$meta/{key}/..."),
+                        _ => Err("tanuki/clients/{id}/$meta"),
+                    },
+                    Some(rest) => Ok(Topic::ClientData {
+                        client: valid_name(client)?,
+                        rest: match parts.remainder() {
+                            Some(remainder) => rest.to_tanuki_string() + "/" + remainder,
+                            None => rest.to_tanuki_string(),
+                        },
+                    }),
+                    None => Err("tanuki/clients/{id}"),
+                },
+                None => Err("tanuki/clients"),
+            },
             Some(_) => Err("tanuki/..."),
             None => Err("tanuki"),
         }
     }
 }
 
+fn valid_id(id: &str) -> Result<TanukiString, &'static str> {
+    validate_id(id)?;
+    Ok(id.to_tanuki_string())
+}
+
+fn valid_name(name: &str) -> Result<TanukiString, &'static str> {
+    filter::validate_name(name)?;
+    Ok(name.to_tanuki_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -156,6 +214,15 @@ mod tests {
             .to_string(),
             "tanuki/entities/sensor.temperature/temperature_sensor/current"
         );
+
+        assert_eq!(
+            Topic::ClientMeta {
+                client: "tanuki-hass".to_tanuki_string(),
+                key: "status".to_tanuki_string(),
+            }
+            .to_string(),
+            "tanuki/clients/tanuki-hass/$meta/status"
+        );
     }
 
     #[test]
@@ -213,5 +280,220 @@ mod tests {
                 rest: "current/extra".to_tanuki_string(),
             }
         );
+
+        assert_eq!(
+            "tanuki/clients/tanuki-hass/$meta/status"
+                .parse::<Topic>()
+                .unwrap(),
+            Topic::ClientMeta {
+                client: "tanuki-hass".to_tanuki_string(),
+                key: "status".to_tanuki_string(),
+            }
+        );
+
+        assert_eq!(
+            "tanuki/clients/tanuki-hass/responses/17"
+                .parse::<Topic>()
+                .unwrap(),
+            Topic::ClientData {
+                client: "tanuki-hass".to_tanuki_string(),
+                rest: "responses/17".to_tanuki_string(),
+            }
+        );
+
+        assert_eq!("tanuki/clients/tanuki-hass".parse::<Topic>(), Err("tanuki/clients/{id}"));
+
+        assert_eq!(
+            "tanuki/entities/Desk.Lamp/$meta/status".parse::<Topic>(),
+            Err("id may only contain a-z, 0-9, '_' and '.'")
+        );
+
+        assert_eq!(
+            "tanuki/entities/desk.lamp/tanuki..on_off/on".parse::<Topic>(),
+            Err("id has an empty segment")
+        );
+
+        assert_eq!("tanuki/entities//tanuki.on_off/on".parse::<Topic>(), Err("id is empty"));
+    }
+
+    #[test]
+    fn entity_id_grammar() {
+        for valid in ["lamp", "desk.lamp", "living_room.ceiling_light", "sensor.pm2_5", "2nd_floor"]
+        {
+            assert_eq!(EntityId::try_new(valid), Ok(EntityId::from(valid)));
+        }
+
+        for invalid in [
+            "",
+            ".",
+            "desk.",
+            ".lamp",
+            "desk..lamp",
+            "Desk",
+            "desk lamp",
+            "desk-lamp",
+            "a/b",
+            "+",
+            "#",
+        ] {
+            assert!(EntityId::try_new(invalid).is_err(), "{invalid:?} should be invalid");
+        }
+    }
+
+    /// Small deterministic PRNG, so the property tests don't need any dependencies
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn string(&mut self, alphabet: &[u8], max_len: usize) -> String {
+            let len = 1 + self.below(max_len);
+            (0..len)
+                .map(|_| alphabet[self.below(alphabet.len())] as char)
+                .collect()
+        }
+
+        fn id(&mut self) -> String {
+            const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789_";
+
+            let segments = 1 + self.below(3);
+            (0..segments)
+                .map(|_| self.string(ALPHABET, 8))
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+
+        fn level(&mut self) -> String {
+            self.string(b"abcdefghijklmnopqrstuvwxyz0123456789_-.", 8)
+        }
+
+        fn path(&mut self) -> String {
+            let levels = 1 + self.below(3);
+            (0..levels)
+                .map(|_| self.level())
+                .collect::<Vec<_>>()
+                .join("/")
+        }
+
+        fn topic(&mut self) -> Topic {
+            let entity = EntityId::try_new(self.id()).unwrap();
+
+            match self.below(5) {
+                0 => Topic::EntityMeta { entity, key: self.level().into() },
+                1 => Topic::CapabilityMeta {
+                    entity,
+                    capability: self.id().into(),
+                    key: self.level().into(),
+                },
+                2 => Topic::CapabilityData {
+                    entity,
+                    capability: self.id().into(),
+                    rest: self.path().into(),
+                },
+                3 => Topic::ClientMeta {
+                    client: self.level().into(),
+                    key: self.level().into(),
+                },
+                _ => Topic::ClientData {
+                    client: self.level().into(),
+                    rest: self.path().into(),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn topic_round_trip() {
+        for topic in [
+            "tanuki/entities/lamp/$meta/status",
+            "tanuki/entities/living_room.ceiling_light/$meta/name",
+            "tanuki/entities/desk.lamp/tanuki.on_off/$meta/version",
+            "tanuki/entities/desk.lamp/tanuki.on_off/on",
+            "tanuki/entities/sensor.pm2_5/tanuki.sensor/pm2.5",
+            "tanuki/entities/2nd_floor.hall/tanuki.light/state/brightness",
+            "tanuki/entities/desk.lamp/tanuki.buttons/a-b/c_d/e.f",
+            "tanuki/clients/tanuki-hass/$meta/status",
+            "tanuki/clients/tanuki-hass/responses/17",
+        ] {
+            let parsed = topic.parse::<Topic>().unwrap();
+            assert_eq!(parsed.to_string(), topic);
+            assert_eq!(parsed.to_string().parse::<Topic>(), Ok(parsed));
+        }
+
+        let mut rng = XorShift(0x7a6e_756b_6921);
+        for _ in 0..10_000 {
+            let topic = rng.topic();
+            assert_eq!(topic.to_string().parse::<Topic>(), Ok(topic.clone()), "{topic}");
+        }
+    }
+
+    #[test]
+    fn invalid_ids_rejected() {
+        for invalid in
+            ["Desk", "desk-lamp", "desk lamp", "desk..lamp", ".lamp", "lamp.", "a+", "a#"]
+        {
+            for topic in [
+                format!("tanuki/entities/{invalid}/$meta/status"),
+                format!("tanuki/entities/{invalid}/tanuki.on_off/$meta/version"),
+                format!("tanuki/entities/{invalid}/tanuki.on_off/on"),
+                format!("tanuki/entities/desk.lamp/{invalid}/$meta/version"),
+                format!("tanuki/entities/desk.lamp/{invalid}/on"),
+            ] {
+                assert!(topic.parse::<Topic>().is_err(), "{topic} should be rejected");
+            }
+        }
+
+        let mut rng = XorShift(0x6261_645f_6964);
+        for _ in 0..10_000 {
+            // a valid ID with one character replaced by something outside the grammar
+            let mut id = rng.id().into_bytes();
+            let at = rng.below(id.len());
+            id[at] = b"ABZ-/+# $"[rng.below(9)];
+            let id = String::from_utf8(id).unwrap();
+
+            assert!(EntityId::try_new(&id).is_err(), "{id:?} should be invalid");
+
+            // a / shifts the levels around, which may still parse as another (valid) topic
+            if !id.contains('/') {
+                let topic = format!("tanuki/entities/{id}/tanuki.on_off/on");
+                assert!(topic.parse::<Topic>().is_err(), "{topic} should be rejected");
+
+                let topic = format!("tanuki/entities/desk.lamp/{id}/on");
+                assert!(topic.parse::<Topic>().is_err(), "{topic} should be rejected");
+            }
+        }
+    }
+
+    #[test]
+    fn wildcard_levels_rejected() {
+        let mut rng = XorShift(0x2b23_2b23_2b23);
+
+        for _ in 0..10_000 {
+            // a valid level with one character replaced by a wildcard
+            let mut name = rng.level().into_bytes();
+            let at = rng.below(name.len());
+            name[at] = b"+#"[rng.below(2)];
+            let name = String::from_utf8(name).unwrap();
+
+            for topic in [
+                format!("tanuki/entities/{name}/$meta/status"),
+                format!("tanuki/entities/desk.lamp/$meta/{name}"),
+                format!("tanuki/entities/desk.lamp/tanuki.on_off/$meta/{name}"),
+                format!("tanuki/clients/{name}/$meta/status"),
+                format!("tanuki/clients/tanuki-hass/$meta/{name}"),
+                format!("tanuki/clients/{name}/responses/17"),
+            ] {
+                assert!(topic.parse::<Topic>().is_err(), "{topic} should be rejected");
+            }
+        }
     }
 }
@@ -1,5 +1,17 @@
 #[macro_export]
 macro_rules! property {
+    attr($namespace:ty, $kind:ident, capability = $capability:expr, key = $key:expr) (
+        $( #[ $meta:meta ] )*
+        pub $itemty:tt $ident:ident $rest:tt $($semicolon:tt)?
+    ) => {
+        #[$crate::property($namespace, $kind, key = $key)]
+        $( #[ $meta ] )*
+        pub $itemty $ident $rest $($semicolon)?
+
+        impl $crate::CapabilityProperty for $ident {
+            const CAPABILITY: &str = $capability;
+        }
+    };
     attr($namespace:ty, $kind:ident, key = $key:expr) (
         $( #[ $meta:meta ] )*
         pub $itemty:tt $ident:ident $rest:tt $($semicolon:tt)?
@@ -51,5 +63,11 @@ mod tests {
                 "value": 21.4,
             }),
         );
+
+        #[property(TestProperty, State, capability = "test.capability", key = "baz")]
+        pub struct Baz(pub bool);
+
+        assert_eq!(Baz::KEY, "baz");
+        assert_eq!(<Baz as crate::CapabilityProperty>::CAPABILITY, "test.capability");
     }
 }
@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 use compact_str::CompactString;
 
 use crate::property;
@@ -13,6 +15,45 @@ pub struct Type(pub CompactString);
 #[property(MetaField, State, key = "provider")]
 pub struct Provider(pub CompactString);
 
+/// Labels for finding entities, such as `room.living_room` or `misc.main_light`
+///
+/// Tags follow the same grammar as entity IDs, see [`validate_id`](crate::validate_id), with the
+/// first segment as a namespace. The `room` namespace places the entity in a room.
+#[property(MetaField, State, key = "tags")]
+#[derive(Default, Eq)]
+pub struct Tags(pub Vec<CompactString>);
+
+impl Tags {
+    /// Doesn't validate `tags`, see [`Tags::validate`]
+    pub fn new(tags: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Tags(tags.into_iter().map(|tag| tag.as_ref().into()).collect())
+    }
+
+    /// Check that every tag is valid, returning the first invalid one and why
+    pub fn validate(&self) -> Result<(), (&str, &'static str)> {
+        self.0
+            .iter()
+            .try_for_each(|tag| crate::validate_id(tag).map_err(|reason| (tag.as_str(), reason)))
+    }
+
+    pub fn contains(&self, tag: &str) -> bool {
+        self.0.iter().any(|t| t == tag)
+    }
+
+    /// Rooms the entity is in, without the `room.` prefix
+    pub fn rooms(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|tag| tag.strip_prefix("room."))
+    }
+}
+
+/// ID of the client that authors this entity
+///
+/// The entity's effective status also depends on the status of its client, published at
+/// `tanuki/clients/{id}/$meta/status` and set to [`EntityStatus::Lost`] by the broker if the
+/// client disconnects unexpectedly.
+#[property(MetaField, State, key = "client")]
+pub struct Client(pub CompactString);
+
 #[property(MetaField, State, key = "status")]
 #[derive(Copy, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -27,5 +68,64 @@ pub enum EntityStatus {
     Lost,
 }
 
+impl EntityStatus {
+    /// Combine the entity's own status with the status of the client that authors it
+    pub fn with_client_status(self, client: Option<EntityStatus>) -> EntityStatus {
+        match (self, client) {
+            (
+                EntityStatus::Init | EntityStatus::Online,
+                Some(status @ (EntityStatus::Lost | EntityStatus::Disconnected)),
+            ) => status,
+            (status, _) => status,
+        }
+    }
+}
+
 #[property(MetaField, State, key = "version")]
 pub struct Version(pub i32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let tags = Tags::new(["room.living_room", "misc.main_light"]);
+
+        assert_eq!(
+            serde_json::to_value(&tags).unwrap(),
+            serde_json::json!(["room.living_room", "misc.main_light"])
+        );
+        assert!(tags.contains("misc.main_light"));
+        assert!(!tags.contains("misc"));
+        assert_eq!(tags.rooms().collect::<Vec<_>>(), ["living_room"]);
+        assert_eq!(tags.validate(), Ok(()));
+
+        let tags = Tags::new(["room.living_room", "room.Living Room"]);
+        assert_eq!(
+            tags.validate(),
+            Err(("room.Living Room", "id may only contain a-z, 0-9, '_' and '.'"))
+        );
+    }
+
+    #[test]
+    fn client_status_overrides_live_entity() {
+        assert_eq!(
+            EntityStatus::Online.with_client_status(Some(EntityStatus::Lost)),
+            EntityStatus::Lost
+        );
+        assert_eq!(
+            EntityStatus::Init.with_client_status(Some(EntityStatus::Disconnected)),
+            EntityStatus::Disconnected
+        );
+        assert_eq!(
+            EntityStatus::Online.with_client_status(Some(EntityStatus::Online)),
+            EntityStatus::Online
+        );
+        assert_eq!(EntityStatus::Online.with_client_status(None), EntityStatus::Online);
+        assert_eq!(
+            EntityStatus::Disconnected.with_client_status(Some(EntityStatus::Lost)),
+            EntityStatus::Disconnected
+        );
+    }
+}
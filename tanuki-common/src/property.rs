@@ -1,3 +1,4 @@
+use alloc::string::String;
 use core::fmt::Debug;
 
 use serde::{Deserialize, Serialize};
@@ -15,3 +16,46 @@ pub trait Property: Debug + Clone + Serialize + for<'de> Deserialize<'de> {
     const KEY: &str;
     const KIND: PropertyKind;
 }
+
+/// A property with a fixed place in a capability, so it can be found from the type alone
+pub trait CapabilityProperty: Property {
+    const CAPABILITY: &str;
+}
+
+/// Reply from the authority of an entity to a command, for senders that asked for one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum CommandResponse<S> {
+    /// The command was carried out, with the state it resulted in if known
+    Success {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state: Option<S>,
+    },
+    /// The command could not be carried out
+    Failure { error: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_response_format() {
+        assert_eq!(
+            serde_json::to_value(CommandResponse::Success { state: Some(true) }).unwrap(),
+            serde_json::json!({ "status": "success", "state": true })
+        );
+        assert_eq!(
+            serde_json::from_value::<CommandResponse<bool>>(serde_json::json!({
+                "status": "success"
+            }))
+            .unwrap(),
+            CommandResponse::Success { state: None }
+        );
+        assert_eq!(
+            serde_json::to_value(CommandResponse::<bool>::Failure { error: "unreachable".into() })
+                .unwrap(),
+            serde_json::json!({ "status": "failure", "error": "unreachable" })
+        );
+    }
+}
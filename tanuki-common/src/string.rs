@@ -72,13 +72,46 @@ impl<T: ToCompactString> ToTanukiString for T {}
 pub struct EntityId(pub TanukiString);
 
 impl EntityId {
+    #[deprecated = "not a valid entity ID, match any entity with a `TopicFilter` instead"]
     pub const WILDCARD: Self = EntityId(TanukiString::const_new("+"));
 
+    /// Create an entity ID, checking that it's valid, see [`validate_id`]
+    pub fn try_new(id: impl AsRef<str>) -> Result<Self, &'static str> {
+        validate_id(id.as_ref())?;
+        Ok(EntityId(id.as_ref().to_tanuki_string()))
+    }
+
     pub fn as_str(&self) -> &str {
         &self.0
     }
 }
 
+/// Check that `id` is a valid entity ID or capability name
+///
+/// IDs are one or more segments separated by dots, each made of lowercase ASCII letters, digits
+/// and underscores, like `living_room.ceiling_light` or `tanuki.on_off`.
+pub fn validate_id(id: &str) -> Result<(), &'static str> {
+    if id.is_empty() {
+        return Err("id is empty");
+    }
+
+    for segment in id.split('.') {
+        if segment.is_empty() {
+            return Err("id has an empty segment");
+        }
+
+        if !segment
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+        {
+            return Err("id may only contain a-z, 0-9, '_' and '.'");
+        }
+    }
+
+    Ok(())
+}
+
+/// Doesn't validate `value`, use [`EntityId::try_new`] for IDs that aren't known to be valid
 impl<T: AsRef<str>> From<T> for EntityId {
     fn from(value: T) -> Self {
         EntityId(value.as_ref().to_tanuki_string())
@@ -18,6 +18,8 @@ use crate::messages::SensorState;
 
 pub struct MappedEntity {
     pub tanuki_id: EntityId,
+    /// Published as the entity's [tags](tanuki_common::meta::Tags), if any
+    pub tags: Vec<String>,
     pub from_hass: Vec<EntityDataMapping>,
     pub to_hass: Vec<EntityServiceMapping>,
 }
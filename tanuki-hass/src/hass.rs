@@ -1,18 +1,29 @@
-use core::sync::atomic::AtomicU32;
-use std::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use futures::{SinkExt, Stream, StreamExt};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
 use tokio_tungstenite::tungstenite::{self, Message};
 
 use crate::{
     Error, Packet, PacketId, Result,
     entity::TargetedServiceCall,
-    messages::{AuthClientMessage, AuthServerMessage, ClientMessage, ServerMessage},
+    messages::{AuthClientMessage, AuthServerMessage, ClientMessage, ServerError, ServerMessage},
 };
 
+type PendingCalls = Arc<Mutex<HashMap<PacketId, oneshot::Sender<Result<()>>>>>;
+
 pub struct HomeAssistant {
-    tx: UnboundedSender<ClientMessage>,
+    tx: UnboundedSender<Packet<ClientMessage>>,
+    next_id: AtomicU32,
+    /// Service calls waiting for their result
+    pending_calls: PendingCalls,
 }
 
 impl HomeAssistant {
@@ -78,21 +89,8 @@ impl HomeAssistant {
         let (mut conn_tx, mut conn_rx) = conn.split();
 
         tokio::spawn(async move {
-            let id = AtomicU32::new(1);
-            let next_id = move || {
-                PacketId(match id.fetch_add(1, std::sync::atomic::Ordering::Relaxed) {
-                    0 => id.fetch_add(1, std::sync::atomic::Ordering::Relaxed), // skip 0
-                    n => n,
-                })
-            };
-            let next_id = Arc::new(next_id);
-
-            while let Some(msg) = rx.recv().await {
-                let msg = Message::Text(
-                    serde_json::to_string(&Packet { id: next_id(), payload: msg })
-                        .unwrap()
-                        .into(),
-                );
+            while let Some(packet) = rx.recv().await {
+                let msg = Message::Text(serde_json::to_string(&packet).unwrap().into());
                 if let Err(e) = conn_tx.send(msg).await {
                     tracing::error!("Error sending message to Home Assistant: {e}");
                     break;
@@ -100,13 +98,18 @@ impl HomeAssistant {
             }
         });
 
-        tx.send(ClientMessage::SubscribeEvents { event_type: None })
-            .unwrap();
+        let hass = Self {
+            tx,
+            next_id: AtomicU32::new(1),
+            pending_calls: PendingCalls::default(),
+        };
 
-        tx.send(ClientMessage::GetStates).unwrap();
+        hass.send(ClientMessage::SubscribeEvents { event_type: None });
+        hass.send(ClientMessage::GetStates);
 
         let (packet_tx, packet_rx) = tokio::sync::mpsc::unbounded_channel();
 
+        let pending_calls = hass.pending_calls.clone();
         tokio::spawn(async move {
             loop {
                 let packet = match conn_rx.next().await {
@@ -134,21 +137,64 @@ impl HomeAssistant {
 
                 tracing::trace!("Received message: {packet:#?}");
 
+                let waiter = pending_calls.lock().unwrap().remove(&packet.id);
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(match packet.payload {
+                        ServerMessage::Result { success: true, .. } => Ok(()),
+                        ServerMessage::Result { error, .. } => {
+                            Err(Error::Hass(error.unwrap_or_else(ServerError::unknown)))
+                        }
+                        ServerMessage::Event { .. } => {
+                            Err(Error::Protocol("expected a result, got an event".to_string()))
+                        }
+                    });
+                    continue;
+                }
+
                 packet_tx.send(packet).expect("packet receiver dropped");
             }
         });
 
-        Ok((Self { tx }, packet_rx))
+        Ok((hass, packet_rx))
+    }
+
+    fn next_id(&self) -> PacketId {
+        PacketId(match self.next_id.fetch_add(1, Ordering::Relaxed) {
+            0 => self.next_id.fetch_add(1, Ordering::Relaxed), // skip 0
+            n => n,
+        })
+    }
+
+    fn send(&self, msg: ClientMessage) -> PacketId {
+        let id = self.next_id();
+        self.tx.send(Packet { id, payload: msg }).unwrap();
+        id
     }
 
-    pub fn call_service(&self, call: TargetedServiceCall) {
-        self.tx
-            .send(ClientMessage::CallService {
-                domain: call.call.domain,
-                service: call.call.service,
-                service_data: call.call.service_data,
-                target: call.target,
-            })
-            .unwrap();
+    /// Call a service, returning a future that resolves once Home Assistant reports the outcome
+    ///
+    /// The call is sent right away, whether or not the future is awaited.
+    pub fn call_service(
+        &self,
+        call: TargetedServiceCall,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let (tx, rx) = oneshot::channel();
+
+        // hold the lock so the result can't arrive before we're waiting for it
+        let mut pending_calls = self.pending_calls.lock().unwrap();
+        let id = self.send(ClientMessage::CallService {
+            domain: call.call.domain,
+            service: call.call.service,
+            service_data: call.call.service_data,
+            target: call.target,
+        });
+        pending_calls.insert(id, tx);
+        drop(pending_calls);
+
+        async move {
+            rx.await.map_err(|_| {
+                Error::Protocol("connection closed before the service call completed".to_string())
+            })?
+        }
     }
 }
@@ -1,14 +1,11 @@
 use std::sync::Arc;
 
 use tanuki::{
-    TanukiConnection, TanukiEntity,
+    CommandReply, TanukiConnection, TanukiEntity,
     capabilities::{Authority, buttons::Buttons, light::Light, on_off::OnOff},
     registry::Registry,
 };
-use tanuki_common::{
-    capabilities::{light::LightCommand, on_off::OnOffCommand},
-    meta,
-};
+use tanuki_common::{Property, capabilities::on_off::OnOffCommand, meta};
 use tokio_tungstenite::tungstenite::{self};
 
 use self::{
@@ -40,8 +37,31 @@ pub enum Error {
     Authentication(String),
 }
 
+/// Report the outcome of the service call for a command back to its sender
+///
+/// The reply carries no state: Home Assistant only reports the new state later, through a
+/// `state_changed` event, which is propagated as usual. So `command_and_wait` on a bridged entity
+/// returns `None` once the service call succeeded, watch the entity's state to see the result.
+async fn reply_with<S: Property>(result: impl Future<Output = Result<()>>, reply: CommandReply<S>) {
+    let res = match result.await {
+        Ok(()) => reply.success(None).await,
+        Err(e) => {
+            tracing::warn!("Service call failed: {e}");
+            reply.failure(e).await
+        }
+    };
+
+    if let Err(e) = res {
+        tracing::warn!("Failed to reply to command: {e}");
+    }
+}
+
+/// Bridge the `mappings` between Home Assistant at `host` and the broker at `broker_url`
+///
+/// Commands are answered once Home Assistant has carried out the service call, without the
+/// resulting state, which follows as a regular state update.
 pub async fn bridge(
-    tanuki: &str,
+    broker_url: &str,
     host: &str,
     token: &str,
     mappings: Vec<MappedEntity>,
@@ -50,37 +70,37 @@ pub async fn bridge(
     let (hass, mut hass_rx) = HomeAssistant::connect(&addr, token).await?;
     let hass = Arc::new(hass);
 
-    async fn entity_init(ent: &TanukiEntity<Authority>) -> tanuki::Result<()> {
-        ent.publish_meta(meta::Provider("tanuki-hass".into())).await
+    async fn entity_init(ent: &TanukiEntity<Authority>, tags: &[String]) -> tanuki::Result<()> {
+        ent.publish_meta(meta::Provider("tanuki-hass".into()))
+            .await?;
+
+        if !tags.is_empty() {
+            ent.publish_tags(tags).await?;
+        }
+
+        Ok(())
     }
 
-    let tanuki: Arc<TanukiConnection> = TanukiConnection::connect("tanuki-hass", tanuki).await?;
+    let tanuki: Arc<TanukiConnection> =
+        TanukiConnection::connect("tanuki-hass", broker_url).await?;
 
     let mut registry = Registry::new(tanuki.clone());
 
     let mappings = Arc::<[_]>::from(mappings.into_boxed_slice());
 
-    tokio::spawn({
-        let tanuki = tanuki.clone();
-
-        async move {
-            let Err(e) = tanuki.handle().await;
-            tracing::error!("Error handling Tanuki messages: {e}");
-        }
-    });
-
-    for MappedEntity { tanuki_id, from_hass: _, to_hass } in mappings.as_ref() {
+    for MappedEntity { tanuki_id, tags, from_hass, to_hass } in mappings.as_ref() {
         for EntityServiceMapping { hass_id, service } in to_hass {
             let hass = hass.clone();
             let hass_id = hass_id.clone();
 
             match *service {
                 ServiceMapping::OnOff { domain } => {
-                    let entity: &mut OnOff<Authority> =
-                        registry.get(tanuki_id, entity_init).await?;
+                    let entity: &mut OnOff<Authority> = registry
+                        .get(tanuki_id, async |ent| entity_init(ent, tags).await)
+                        .await?;
 
                     entity
-                        .listen(move |cmd: OnOffCommand| {
+                        .handle_commands(move |cmd, reply| {
                             let call = ServiceCall {
                                 domain: domain.to_string(),
                                 service: match cmd {
@@ -91,17 +111,20 @@ pub async fn bridge(
                                 service_data: serde_json::Value::Null,
                             };
 
-                            hass.call_service(call.target_entity(&hass_id));
+                            let result = hass.call_service(call.target_entity(&hass_id));
+                            tokio::spawn(reply_with(result, reply));
                         })
                         .await
-                        .unwrap(); // TODO: better handling?
+                        .unwrap() // TODO: better handling?
+                        .detach();
                 }
                 ServiceMapping::Light => {
-                    let entity: &mut Light<Authority> =
-                        registry.get(tanuki_id, entity_init).await?;
+                    let entity: &mut Light<Authority> = registry
+                        .get(tanuki_id, async |ent| entity_init(ent, tags).await)
+                        .await?;
 
                     entity
-                        .listen(move |cmd: LightCommand| {
+                        .handle_commands(move |cmd, reply| {
                             let call = ServiceCall {
                                 domain: "light".to_string(),
                                 service: match cmd.on {
@@ -119,15 +142,36 @@ pub async fn bridge(
                                 },
                             };
 
-                            hass.call_service(call.target_entity(&hass_id));
+                            let result = hass.call_service(call.target_entity(&hass_id));
+                            tokio::spawn(reply_with(result, reply));
                         })
                         .await
-                        .unwrap(); // TODO: better handling?
+                        .unwrap() // TODO: better handling?
+                        .detach();
                 }
             }
+        }
+
+        // buttons have no state to wait for, so author them up front instead of on the first
+        // press, otherwise button-only entities would never get marked ready
+        let has_buttons = from_hass
+            .iter()
+            .any(|mapping| matches!(mapping, EntityDataMapping::ZhaCommands { .. }));
+        if has_buttons {
+            let _: &mut Buttons<Authority> = registry
+                .get(tanuki_id, async |ent| entity_init(ent, tags).await)
+                .await?;
+        }
 
-            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        // otherwise it's marked ready once its state has been propagated
+        let has_state = from_hass
+            .iter()
+            .any(|mapping| matches!(mapping, EntityDataMapping::State { .. }));
+        if !has_state {
+            registry.ready(tanuki_id).await?;
         }
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
     }
 
     loop {
@@ -138,10 +182,7 @@ pub async fn bridge(
         match packet.payload {
             ServerMessage::Result { success, result, error } => {
                 if !success {
-                    return Err(Error::Hass(error.unwrap_or(ServerError {
-                        code: "unknown".to_string(),
-                        message: "success: false, but no error given".to_string(),
-                    })));
+                    return Err(Error::Hass(error.unwrap_or_else(ServerError::unknown)));
                 }
 
                 // get_states result
@@ -154,7 +195,13 @@ pub async fn bridge(
                             state.state.attributes.unit_of_measurement,
                         );
 
-                        for MappedEntity { tanuki_id, from_hass, to_hass: _ } in mappings.as_ref() {
+                        for MappedEntity {
+                            tanuki_id,
+                            tags,
+                            from_hass,
+                            to_hass: _,
+                        } in mappings.as_ref()
+                        {
                             for mapping in from_hass {
                                 if let EntityDataMapping::State { from_id, map_to } = mapping {
                                     if from_id != &state.entity_id {
@@ -166,9 +213,10 @@ pub async fn bridge(
                                             &state.state,
                                             &mut registry,
                                             tanuki_id,
-                                            entity_init,
+                                            async |ent| entity_init(ent, tags).await,
                                         )
                                         .await?;
+                                    registry.ready(tanuki_id).await?;
 
                                     tokio::time::sleep(std::time::Duration::from_millis(50)).await;
                                 }
@@ -187,7 +235,13 @@ pub async fn bridge(
                         sensor_event.new_state.state,
                         sensor_event.new_state.attributes.unit_of_measurement,
                     );
-                    for MappedEntity { tanuki_id, from_hass, to_hass: _ } in mappings.as_ref() {
+                    for MappedEntity {
+                        tanuki_id,
+                        tags,
+                        from_hass,
+                        to_hass: _,
+                    } in mappings.as_ref()
+                    {
                         for mapping in from_hass {
                             if let EntityDataMapping::State { from_id, map_to } = mapping {
                                 if from_id != &sensor_event.entity_id {
@@ -199,9 +253,10 @@ pub async fn bridge(
                                         &sensor_event.new_state,
                                         &mut registry,
                                         tanuki_id,
-                                        entity_init,
+                                        async |ent| entity_init(ent, tags).await,
                                     )
                                     .await?;
+                                registry.ready(tanuki_id).await?;
                             }
                         }
                     }
@@ -209,7 +264,13 @@ pub async fn bridge(
                 EventData::ZhaEvent(zha_event) => {
                     tracing::info!("ZHA Event {zha_event:#?}");
 
-                    for MappedEntity { tanuki_id, from_hass, to_hass: _ } in mappings.as_ref() {
+                    for MappedEntity {
+                        tanuki_id,
+                        tags,
+                        from_hass,
+                        to_hass: _,
+                    } in mappings.as_ref()
+                    {
                         for mapping in from_hass {
                             if let EntityDataMapping::ZhaCommands { device_ieee, translations } =
                                 mapping
@@ -251,10 +312,14 @@ pub async fn bridge(
 
                                     match &translation.map_to {
                                         entity::CapEventMapping::Button { button, action } => {
-                                            let sensor: &mut Buttons<Authority> =
-                                                registry.get(tanuki_id, entity_init).await?;
+                                            let sensor: &mut Buttons<Authority> = registry
+                                                .get(tanuki_id, async |ent| {
+                                                    entity_init(ent, tags).await
+                                                })
+                                                .await?;
 
                                             sensor.publish_action(button, *action).await?;
+                                            registry.ready(tanuki_id).await?;
                                         }
                                     }
                                 }
@@ -36,7 +36,7 @@ pub(crate) struct Packet<T> {
     pub(crate) payload: T,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
 pub(crate) struct PacketId(pub u32);
 
@@ -61,6 +61,16 @@ pub struct ServerError {
     pub message: String,
 }
 
+impl ServerError {
+    /// For failed results that don't say why
+    pub(crate) fn unknown() -> Self {
+        ServerError {
+            code: "unknown".to_string(),
+            message: "success: false, but no error given".to_string(),
+        }
+    }
+}
+
 impl Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}: {}", self.code, self.message)
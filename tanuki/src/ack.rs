@@ -0,0 +1,158 @@
+use mqtt_endpoint_tokio::mqtt_ep::{
+    packet::Packet,
+    result_code::{PubackReasonCode, PubrecReasonCode},
+};
+use tokio::sync::oneshot;
+
+use crate::{Error, Result, TanukiConnection};
+
+/// An acknowledgement we're still waiting for from the broker
+///
+/// Dropping it is fine when the caller doesn't care about the outcome.
+pub(crate) struct PendingAck {
+    rx: Option<oneshot::Receiver<Packet>>,
+}
+
+impl PendingAck {
+    /// For QoS 0 publishes, which are never acknowledged
+    pub fn none() -> Self {
+        Self { rx: None }
+    }
+
+    async fn recv(self) -> Result<Option<Packet>> {
+        match self.rx {
+            Some(rx) => rx.await.map(Some).map_err(|_| Error::AckLost),
+            None => Ok(None),
+        }
+    }
+
+    /// Wait for the PUBACK or PUBCOMP of a publish
+    pub async fn publish(self) -> Result<()> {
+        match self.recv().await? {
+            None => Ok(()),
+            Some(Packet::V5_0Puback(puback)) => match puback.reason_code() {
+                Some(code) if code.is_failure() => Err(Error::PublishRejected(code)),
+                _ => Ok(()),
+            },
+            Some(Packet::V5_0Pubrec(pubrec)) => match pubrec.reason_code() {
+                Some(code) if code.is_failure() => Err(Error::PublishRejected(
+                    // PUBREC shares its reason codes with PUBACK
+                    PubackReasonCode::try_from(code as u8)
+                        .unwrap_or(PubackReasonCode::UnspecifiedError),
+                )),
+                _ => Ok(()),
+            },
+            Some(Packet::V5_0Pubcomp(pubcomp)) => {
+                if let Some(code) = pubcomp.reason_code()
+                    && code.is_failure()
+                {
+                    // the broker did receive the message, it just lost track of the exchange
+                    tracing::warn!("Received PUBCOMP with reason code {code}");
+                }
+
+                Ok(())
+            }
+            Some(packet) => Err(unexpected("publish", &packet)),
+        }
+    }
+
+    /// Wait for the SUBACK of a subscribe
+    pub async fn subscribe(self) -> Result<()> {
+        match self.recv().await? {
+            Some(Packet::V5_0Suback(suback)) => {
+                match suback
+                    .reason_codes()
+                    .into_iter()
+                    .find(|code| code.is_failure())
+                {
+                    Some(code) => Err(Error::SubscriptionRejected(code)),
+                    None => Ok(()),
+                }
+            }
+            Some(packet) => Err(unexpected("subscribe", &packet)),
+            None => Err(Error::AckLost),
+        }
+    }
+
+    /// Wait for the UNSUBACK of an unsubscribe
+    pub async fn unsubscribe(self) -> Result<()> {
+        match self.recv().await? {
+            Some(Packet::V5_0Unsuback(unsuback)) => {
+                match unsuback
+                    .reason_codes()
+                    .into_iter()
+                    .find(|code| code.is_failure())
+                {
+                    Some(code) => Err(Error::UnsubscribeRejected(code)),
+                    None => Ok(()),
+                }
+            }
+            Some(packet) => Err(unexpected("unsubscribe", &packet)),
+            None => Err(Error::AckLost),
+        }
+    }
+}
+
+/// The broker answered with the wrong kind of packet, or we mixed up packet IDs
+fn unexpected(request: &str, packet: &Packet) -> Error {
+    tracing::warn!("Expected an acknowledgement for {request}, received {packet:?}");
+    Error::Protocol(format!("unexpected {request} acknowledgement"))
+}
+
+impl TanukiConnection {
+    /// Register interest in the acknowledgement for `packet_id`, before sending the packet
+    pub(crate) async fn expect_ack(&self, packet_id: u16) -> PendingAck {
+        let (tx, rx) = oneshot::channel();
+        self.pending_acks.lock().await.insert(packet_id, tx);
+
+        PendingAck { rx: Some(rx) }
+    }
+
+    /// Hand an acknowledgement packet to whoever is waiting for it
+    pub(crate) async fn resolve_ack(&self, packet_id: u16, packet: Packet) {
+        // a successful PUBREC is followed by a PUBCOMP, which is what we're really waiting for
+        if let Packet::V5_0Pubrec(pubrec) = &packet
+            && pubrec
+                .reason_code()
+                .is_none_or(|code: PubrecReasonCode| code.is_success())
+        {
+            return;
+        }
+
+        let Some(tx) = self.pending_acks.lock().await.remove(&packet_id) else {
+            tracing::trace!("Received unexpected acknowledgement: {packet:?}");
+            return;
+        };
+
+        // the receiver may have been dropped, if nobody cares about the result
+        let _ = tx.send(packet);
+    }
+
+    /// Fail every pending acknowledgement, after the connection was lost
+    pub(crate) async fn drop_pending_acks(&self) {
+        self.pending_acks.lock().await.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_endpoint_tokio::mqtt_ep::packet::v5_0;
+
+    use super::*;
+
+    fn acked_with(packet: impl Into<Packet>) -> PendingAck {
+        let (tx, rx) = oneshot::channel();
+        tx.send(packet.into()).unwrap();
+        PendingAck { rx: Some(rx) }
+    }
+
+    #[tokio::test]
+    async fn unexpected_acks() {
+        let puback = || v5_0::Puback::builder().packet_id(1).build().unwrap();
+
+        assert!(acked_with(puback()).publish().await.is_ok());
+        assert!(matches!(acked_with(puback()).subscribe().await, Err(Error::Protocol(_))));
+        assert!(matches!(acked_with(puback()).unsubscribe().await, Err(Error::Protocol(_))));
+        assert!(matches!(PendingAck::none().subscribe().await, Err(Error::AckLost)));
+    }
+}
@@ -1,11 +1,12 @@
 use serde::{Deserialize, Serialize};
 use tanuki_common::{
-    EntityId, TanukiString, ToTanukiString, Topic, capabilities::buttons::ButtonAction,
+    EntityId, ToTanukiString, Topic, TopicFilter, capabilities::buttons::ButtonAction,
 };
 
 use super::{Capability, User};
 use crate::{
-    Authority, EntityRole, PublishEvent, PublishOpts, Result, TanukiCapability, capability,
+    Authority, EntityRole, Error, PublishEvent, PublishOpts, Result, Subscription,
+    TanukiCapability, capability,
 };
 
 #[capability(id = tanuki_common::capabilities::ids::BUTTONS)]
@@ -19,14 +20,14 @@ impl Buttons<Authority> {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ButtonEvent {
     pub entity: EntityId,
     pub name: ButtonName,
     pub action: ButtonAction,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ButtonName {
     On,
@@ -35,6 +36,16 @@ pub enum ButtonName {
     Other(String),
 }
 
+impl ButtonName {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ButtonName::On => "on",
+            ButtonName::Off => "off",
+            ButtonName::Other(name) => name,
+        }
+    }
+}
+
 impl From<String> for ButtonName {
     fn from(value: String) -> Self {
         Self::deserialize(serde_json::Value::String(value)).unwrap()
@@ -51,6 +62,7 @@ impl TryFrom<&PublishEvent> for ButtonEvent {
             ..
         } = publish
             && capability == <Buttons>::ID
+            && !payload.is_null()
         {
             let name = ButtonName::from(rest.to_string());
 
@@ -77,16 +89,14 @@ impl<R: EntityRole> Buttons<R> {
     pub async fn listen(
         &self,
         listener: impl Fn(&str, ButtonAction) + Send + Sync + 'static,
-    ) -> Result<()> {
+    ) -> Result<Subscription> {
         self.cap
             .entity
             .conn
             .subscribe_with_handler(
-                Topic::CapabilityData {
-                    entity: self.entity.id().clone(),
-                    capability: self.capability.clone(),
-                    rest: TanukiString::const_new("+"), // any button
-                },
+                TopicFilter::capability_data(&self.capability)
+                    .and_then(|filter| filter.entity(self.entity.id()))
+                    .map_err(Error::BadTopic)?,
                 Box::new(move |ev| {
                     match (ev.topic, serde_json::from_value::<ButtonAction>(ev.payload)) {
                         (Topic::CapabilityData { rest, .. }, Ok(payload)) => {
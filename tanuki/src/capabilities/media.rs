@@ -1,7 +1,13 @@
-use tanuki_common::capabilities::media::{MediaCommand, MediaProperty};
+use core::time::Duration;
+
+use futures::Stream;
+use tanuki_common::capabilities::media::{MediaCommand, MediaProperty, MediaState};
 
 use super::Capability;
-use crate::{Authority, EntityRole, PublishOpts, Result, TanukiCapability, capability};
+use crate::{
+    Authority, CommandReply, EntityRole, PublishOpts, Result, Subscription, TanukiCapability,
+    capability,
+};
 
 #[capability(id = tanuki_common::capabilities::ids::MEDIA)]
 pub struct Media<R: EntityRole> {
@@ -14,6 +20,13 @@ impl Media<Authority> {
             .publish_property(prop, PublishOpts::entity_data())
             .await
     }
+    /// Handle commands, replying to senders that wait for the outcome
+    pub async fn handle_commands(
+        &self,
+        handler: impl FnMut(MediaCommand, CommandReply<MediaState>) + Send + Sync + 'static,
+    ) -> Result<Subscription> {
+        self.cap.handle_commands(handler).await
+    }
 }
 
 impl<R: EntityRole> Media<R> {
@@ -21,14 +34,44 @@ impl<R: EntityRole> Media<R> {
         self.cap.publish_property(cmd, PublishOpts::control()).await
     }
 
+    /// Send a command and wait for the entity to carry it out, returning the resulting state if
+    /// it was reported
+    pub async fn command_and_wait(
+        &self,
+        cmd: MediaCommand,
+        timeout: Duration,
+    ) -> Result<Option<MediaState>> {
+        self.cap.command_and_wait(cmd, timeout).await
+    }
+
     pub async fn listen<T: MediaProperty>(
         &self,
         listener: impl Fn(T) + Send + Sync + 'static,
-    ) -> Result<()> {
+    ) -> Result<Subscription> {
         self.cap.listen(listener, false).await
     }
 
     pub async fn get<T: MediaProperty + Send + 'static>(&self) -> Result<T> {
         self.cap.get().await
     }
+
+    pub async fn get_timeout<T: MediaProperty + Send + 'static>(
+        &self,
+        timeout: Duration,
+    ) -> Result<T> {
+        self.cap.get_timeout(timeout).await
+    }
+
+    pub async fn try_get<T: MediaProperty + Send + 'static>(&self) -> Result<Option<T>> {
+        self.cap.try_get().await
+    }
+
+    /// Stream every value of a property, starting with the current one
+    ///
+    /// `None` when the value is cleared. Unsubscribes when the stream is dropped.
+    pub async fn watch<T: MediaProperty + Send + 'static>(
+        &self,
+    ) -> Result<impl Stream<Item = Option<T>> + Send + Unpin + 'static> {
+        self.cap.watch().await
+    }
 }
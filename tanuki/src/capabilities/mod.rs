@@ -1,18 +1,27 @@
-use core::ops::Deref;
+use core::{ops::Deref, time::Duration};
 use std::sync::Arc;
 
-use serde::Serialize;
+use futures::Stream;
+use serde::{Serialize, de::DeserializeOwned};
 use tanuki_common::{
     EntityId, Property, TanukiString, ToTanukiString, Topic,
     meta::{self, MetaField},
 };
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 
-use crate::{PublishOpts, Result, TanukiEntity};
+use crate::{
+    CommandReply, Error, PublishOpts, Result, Subscription, TanukiEntity,
+    dispatch::RETAINED_GRACE_PERIOD, request,
+};
 
 pub mod buttons;
 pub mod light;
 pub mod media;
 pub mod on_off;
+pub mod scene;
 pub mod sensor;
 
 pub struct TanukiCapability<R: EntityRole> {
@@ -79,7 +88,7 @@ impl<R: EntityRole> TanukiCapability<R> {
         &self,
         mut listener: impl FnMut(T) + Send + Sync + 'static,
         oneshot: bool,
-    ) -> Result<()> {
+    ) -> Result<Subscription> {
         self.entity
             .conn
             .subscribe_with_handler(
@@ -102,31 +111,192 @@ impl<R: EntityRole> TanukiCapability<R> {
             .await
     }
 
-    pub(crate) async fn listen_oneshot<T: Property>(
+    /// Stream every value published under `key`, starting with the retained one
+    ///
+    /// `None` when the retained value is cleared. The subscription lasts as long as the stream.
+    pub(crate) async fn watch_raw<T: DeserializeOwned + Send + 'static>(
         &self,
-        listener: impl FnOnce(T) + Send + Sync + 'static,
-    ) -> Result<()> {
-        let mut listener = Some(listener);
-        self.listen(
-            move |v| {
-                if let Some(listener) = listener.take() {
-                    listener(v);
-                }
-            },
-            true,
-        )
-        .await
+        key: TanukiString,
+    ) -> Result<impl Stream<Item = Option<T>> + Send + Unpin + 'static> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let subscription = self
+            .entity
+            .conn
+            .subscribe_with_handler(
+                Topic::CapabilityData {
+                    entity: self.entity.id().clone(),
+                    capability: self.capability.clone(),
+                    rest: key,
+                },
+                Box::new(move |ev| {
+                    match watched_value(ev.payload) {
+                        Ok(value) => {
+                            let _ = tx.send(value);
+                        }
+                        Err(e) => tracing::error!(topic = %ev.topic, "Failed to deserialize: {e}"),
+                    }
+
+                    true
+                }),
+            )
+            .await?;
+
+        Ok(futures::stream::poll_fn(move |cx| {
+            // keeps the subscription alive for as long as the stream
+            let _ = &subscription;
+            rx.poll_recv(cx)
+        }))
     }
 
+    /// Stream every value of a property, starting with the retained one
+    pub(crate) async fn watch<T: Property + Send + 'static>(
+        &self,
+    ) -> Result<impl Stream<Item = Option<T>> + Send + Unpin + 'static> {
+        self.watch_raw(TanukiString::const_new(T::KEY)).await
+    }
+
+    /// Subscribe to a property, sending the first value the broker delivers
+    async fn first_value<T: Property + Send + 'static>(
+        &self,
+    ) -> Result<(Subscription, oneshot::Receiver<Result<T>>)> {
+        let (tx, rx) = oneshot::channel();
+        let mut tx = Some(tx);
+
+        let subscription = self
+            .entity
+            .conn
+            .subscribe_with_handler(
+                Topic::CapabilityData {
+                    entity: self.entity.id().clone(),
+                    capability: self.capability.clone(),
+                    rest: TanukiString::const_new(T::KEY),
+                },
+                Box::new(move |ev| {
+                    let value = serde_json::from_value::<T>(ev.payload).map_err(|source| {
+                        Error::BadPayload { topic: ev.topic.to_string(), source }
+                    });
+
+                    if let Some(tx) = tx.take() {
+                        let _ = tx.send(value);
+                    }
+
+                    false
+                }),
+            )
+            .await?;
+
+        Ok((subscription, rx))
+    }
+
+    /// Wait for the current value of a property
+    ///
+    /// Waits until a value is published if the broker has none retained.
     pub(crate) async fn get<T: Property + Send + 'static>(&self) -> Result<T> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
+        let (_subscription, rx) = self.first_value().await?;
+
+        rx.await.map_err(|_| Error::Closed)?
+    }
+
+    /// Like [`get`](Self::get), failing with [`Error::Timeout`] after `timeout`
+    pub(crate) async fn get_timeout<T: Property + Send + 'static>(
+        &self,
+        timeout: Duration,
+    ) -> Result<T> {
+        let deadline = Instant::now() + timeout;
+
+        let (_subscription, rx) = tokio::time::timeout_at(deadline, self.first_value())
+            .await
+            .map_err(|_| Error::Timeout)??;
+
+        recv_until(rx, deadline).await?.ok_or(Error::Timeout)
+    }
+
+    /// Get the retained value of a property, or `None` if the broker has none
+    pub(crate) async fn try_get<T: Property + Send + 'static>(&self) -> Result<Option<T>> {
+        // otherwise the subscription is only queued, and we'd time out waiting for nothing
+        self.entity.conn.wait_connected().await?;
 
-        self.listen_oneshot(move |prop: T| {
-            let _ = tx.send(prop);
-        })
-        .await?;
+        let (_subscription, rx) = self.first_value().await?;
 
-        Ok(rx.await.unwrap())
+        recv_until(rx, Instant::now() + RETAINED_GRACE_PERIOD).await
+    }
+
+    /// Send a command and wait for the authority to report its outcome
+    pub(crate) async fn command_and_wait<C: Property, S: Property>(
+        &self,
+        cmd: C,
+        timeout: Duration,
+    ) -> Result<Option<S>> {
+        let topic = Topic::CapabilityData {
+            entity: self.entity.id().clone(),
+            capability: self.capability.clone(),
+            rest: TanukiString::const_new(C::KEY),
+        };
+
+        let response = self.entity.conn.request(topic, cmd, timeout).await?;
+
+        request::command_outcome(response)
+    }
+
+    /// Call `handler` for every command, with a way to report the outcome to the sender
+    pub(crate) async fn handle_commands<C: Property, S: Property + Send + 'static>(
+        &self,
+        mut handler: impl FnMut(C, CommandReply<S>) + Send + Sync + 'static,
+    ) -> Result<Subscription> {
+        // the handler is owned by the connection, so don't keep it alive from in there
+        let conn = Arc::downgrade(&self.entity.conn);
+
+        self.entity
+            .conn
+            .subscribe_with_handler(
+                Topic::CapabilityData {
+                    entity: self.entity.id().clone(),
+                    capability: self.capability.clone(),
+                    rest: TanukiString::const_new(C::KEY),
+                },
+                Box::new(move |ev| {
+                    let Some(conn) = conn.upgrade() else {
+                        return false;
+                    };
+
+                    let reply = CommandReply::<S>::new(conn, &ev);
+
+                    match serde_json::from_value::<C>(ev.payload) {
+                        Ok(cmd) => handler(cmd, reply),
+                        Err(e) => {
+                            tracing::error!("Failed to deserialize command {}: {e}", C::KEY);
+
+                            tokio::spawn(async move {
+                                if let Err(e) = reply.failure(format!("bad command: {e}")).await {
+                                    tracing::warn!("Failed to reply to command: {e}");
+                                }
+                            });
+                        }
+                    }
+
+                    true
+                }),
+            )
+            .await
+    }
+}
+
+/// Value of a watched payload, `None` if it's `null` because the retained value was cleared
+fn watched_value<T: DeserializeOwned>(payload: serde_json::Value) -> serde_json::Result<Option<T>> {
+    match payload {
+        serde_json::Value::Null => Ok(None),
+        payload => serde_json::from_value(payload).map(Some),
+    }
+}
+
+/// Wait for the value from [`first_value`](TanukiCapability::first_value), or `None` if it
+/// doesn't arrive before `deadline`
+async fn recv_until<T>(rx: oneshot::Receiver<Result<T>>, deadline: Instant) -> Result<Option<T>> {
+    match tokio::time::timeout_at(deadline, rx).await {
+        Ok(Ok(value)) => value.map(Some),
+        Ok(Err(_)) => Err(Error::Closed),
+        Err(_) => Ok(None),
     }
 }
 
@@ -175,3 +345,44 @@ macro_rules! capability {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn first_value_outcomes() {
+        let soon = || Instant::now() + Duration::from_millis(20);
+
+        // published
+        let (tx, rx) = oneshot::channel::<Result<i32>>();
+        tx.send(Ok(1)).unwrap();
+        assert_eq!(recv_until(rx, soon()).await.unwrap(), Some(1));
+
+        // not published, `try_get` returns `None` and `get_timeout` times out
+        let (_tx, rx) = oneshot::channel::<Result<i32>>();
+        assert_eq!(recv_until(rx, soon()).await.unwrap(), None);
+
+        // published, but not what we expected
+        let (tx, rx) = oneshot::channel::<Result<i32>>();
+        let source = serde_json::from_str::<i32>("\"on\"").unwrap_err();
+        tx.send(Err(Error::BadPayload { topic: "x".into(), source }))
+            .unwrap();
+        assert!(matches!(recv_until(rx, soon()).await, Err(Error::BadPayload { .. })));
+
+        // subscription went away
+        let (tx, rx) = oneshot::channel::<Result<i32>>();
+        drop(tx);
+        assert!(matches!(recv_until(rx, soon()).await, Err(Error::Closed)));
+    }
+
+    #[test]
+    fn watched_values() {
+        use serde_json::json;
+        use tanuki_common::capabilities::on_off::On;
+
+        assert_eq!(watched_value::<On>(json!(true)).unwrap(), Some(On(true)));
+        assert_eq!(watched_value::<On>(serde_json::Value::Null).unwrap(), None);
+        assert!(watched_value::<On>(json!("on")).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use core::time::Duration;
+
+use tanuki_common::capabilities::scene::{Scene, SceneCommand, SceneProperty};
+
+use super::{Capability, User};
+use crate::{
+    Authority, CommandReply, EntityRole, PublishOpts, Result, Subscription, TanukiCapability,
+    capability,
+};
+
+#[capability(id = tanuki_common::capabilities::ids::SCENE)]
+pub struct SceneTrigger<R: EntityRole = User> {
+    cap: TanukiCapability<R>,
+}
+
+impl SceneTrigger<Authority> {
+    pub async fn publish(&self, prop: impl SceneProperty) -> Result<()> {
+        self.cap
+            .publish_property(prop, PublishOpts::entity_data())
+            .await
+    }
+
+    /// Handle commands, replying to senders that wait for the outcome
+    pub async fn handle_commands(
+        &self,
+        handler: impl FnMut(SceneCommand, CommandReply<Scene>) + Send + Sync + 'static,
+    ) -> Result<Subscription> {
+        self.cap.handle_commands(handler).await
+    }
+}
+
+impl<R: EntityRole> SceneTrigger<R> {
+    pub async fn command(&self, cmd: SceneCommand) -> Result<()> {
+        self.cap.publish_property(cmd, PublishOpts::control()).await
+    }
+
+    pub async fn activate(&self) -> Result<()> {
+        self.command(SceneCommand::Activate).await
+    }
+
+    /// Activate the scene and wait until it has been applied
+    pub async fn activate_and_wait(&self, timeout: Duration) -> Result<()> {
+        self.cap
+            .command_and_wait::<_, Scene>(SceneCommand::Activate, timeout)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the scene, or `None` if the entity hasn't published it
+    pub async fn try_get(&self) -> Result<Option<Scene>> {
+        self.cap.try_get().await
+    }
+}
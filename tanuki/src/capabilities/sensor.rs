@@ -1,3 +1,4 @@
+use futures::{Stream, StreamExt as _, channel::mpsc};
 use serde::Deserialize as _;
 use tanuki_common::{
     EntityId, TanukiString, ToTanukiString, Topic, capabilities::sensor::SensorPayload,
@@ -6,6 +7,7 @@ use tanuki_common::{
 use super::{Capability, User};
 use crate::{
     Authority, EntityRole, PublishEvent, PublishOpts, Result, TanukiCapability, capability,
+    operators::Operator,
 };
 
 #[capability(id = tanuki_common::capabilities::ids::SENSOR)]
@@ -19,6 +21,68 @@ impl Sensor<Authority> {
             .publish_raw(key, &payload, PublishOpts::entity_data())
             .await
     }
+
+    /// Publish readings of `key` through `op`, such as a
+    /// [`Deadband`](crate::operators::Deadband) to skip updates that don't say anything new
+    pub fn publisher(
+        &self,
+        key: impl ToTanukiString,
+        op: impl Operator<SensorPayload> + Send + 'static,
+    ) -> SensorPublisher {
+        let (publisher, mut readings) = SensorPublisher::new(op);
+        let sensor = Sensor {
+            cap: TanukiCapability {
+                entity: self.cap.entity.clone(),
+                capability: self.cap.capability.clone(),
+            },
+        };
+        let key = key.to_tanuki_string();
+
+        tokio::spawn(async move {
+            while let Some(payload) = readings.next().await {
+                if let Err(e) = sensor.publish(key.clone(), payload).await {
+                    tracing::warn!(sensor = %key, "Failed to publish reading: {e}");
+                }
+            }
+        });
+
+        publisher
+    }
+}
+
+/// Publishes the readings of a sensor through an [`Operator`], see [`Sensor::publisher`]
+///
+/// Readings held back by the operator are still published after this is dropped.
+pub struct SensorPublisher {
+    tx: mpsc::UnboundedSender<SensorPayload>,
+}
+
+impl SensorPublisher {
+    /// The publisher, and the readings `op` lets through for publishing
+    fn new(
+        op: impl Operator<SensorPayload> + Send + 'static,
+    ) -> (Self, impl Stream<Item = SensorPayload> + Send + Unpin + 'static) {
+        let (tx, rx) = mpsc::unbounded();
+        (Self { tx }, op.apply(rx))
+    }
+
+    /// Pass a reading to the operator, which decides if and when to publish it
+    pub fn publish(&self, payload: SensorPayload) {
+        // fails only if the task is gone, when the runtime is shutting down
+        let _ = self.tx.unbounded_send(payload);
+    }
+}
+
+impl<R: EntityRole> Sensor<R> {
+    /// Stream every reading of the sensor `key`, starting with the current one
+    ///
+    /// `None` when the reading is cleared. Unsubscribes when the stream is dropped.
+    pub async fn watch(
+        &self,
+        key: impl ToTanukiString,
+    ) -> Result<impl Stream<Item = Option<SensorPayload>> + Send + Unpin + 'static> {
+        self.cap.watch_raw(key.to_tanuki_string()).await
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +108,8 @@ impl TryFrom<&PublishEvent> for SensorEvent {
             ..
         } = publish
             && capability == <Sensor>::ID
+            // a cleared reading is no reading
+            && !payload.is_null()
         {
             if let Ok(payload) = SensorPayload::deserialize(payload) {
                 Ok(SensorEvent {
@@ -67,3 +133,40 @@ impl TryFrom<&PublishEvent> for SensorEvent {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use tanuki_common::capabilities::sensor::SensorValue;
+
+    use super::*;
+    use crate::operators::{Deadband, Throttle};
+
+    #[tokio::test]
+    async fn publisher() {
+        let reading = |n: f32| SensorPayload {
+            value: n.into(),
+            unit: "°C".into(),
+            timestamp: chrono::Utc::now(),
+        };
+
+        let (publisher, readings) = SensorPublisher::new(Operator::<SensorPayload>::chain(
+            Deadband::new(0.5),
+            Throttle::new(Duration::from_secs(60)),
+        ));
+
+        // 20.2 is within the deadband, 21 is throttled and then replaced by 22
+        for n in [20., 20.2, 21., 22.] {
+            publisher.publish(reading(n));
+        }
+
+        // the throttled reading is still published once the publisher is dropped
+        drop(publisher);
+        let published = readings
+            .map(|payload| payload.value)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(published, [SensorValue::Number(20.), SensorValue::Number(22.)]);
+    }
+}
@@ -0,0 +1,461 @@
+//! Index of the entities on the broker and what they can do
+//!
+//! A [`Catalog`] follows the metadata of every entity, so automations can discover devices by
+//! capability or provider instead of hardcoding their IDs.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
+};
+
+use serde_json::Value;
+use tanuki_common::{
+    EntityId, Property, TanukiString, Topic, TopicFilter,
+    meta::{self, EntityStatus, Tags},
+};
+use tokio::sync::broadcast;
+
+use crate::{
+    Error, PublishEvent, Result, Subscription, SubscriptionHandler, TanukiConnection, User,
+    capabilities::Capability,
+    dispatch::{EVENT_CAPACITY, RETAINED_GRACE_PERIOD},
+};
+
+/// What's known about an entity from its metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityInfo {
+    pub id: EntityId,
+    pub name: Option<String>,
+    pub entity_type: Option<String>,
+    pub provider: Option<String>,
+    /// ID of the client authoring the entity, see [`meta::Client`]
+    pub client: Option<String>,
+    /// The entity's status, combined with the status of its client
+    ///
+    /// So the entities of a client that went away unexpectedly are
+    /// [`Lost`](EntityStatus::Lost), even though they're still marked online themselves.
+    pub status: Option<EntityStatus>,
+    pub tags: Tags,
+    /// IDs of the entity's capabilities, with their versions
+    pub capabilities: BTreeMap<TanukiString, i32>,
+    /// The status published by the entity itself
+    own_status: Option<EntityStatus>,
+}
+
+impl EntityInfo {
+    fn new(id: EntityId) -> Self {
+        Self {
+            id,
+            name: None,
+            entity_type: None,
+            provider: None,
+            client: None,
+            status: None,
+            tags: Tags::default(),
+            capabilities: BTreeMap::new(),
+            own_status: None,
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.keys().any(|id| id == capability)
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+}
+
+/// An entity that appeared, changed or went away
+#[derive(Debug, Clone)]
+pub enum CatalogChange {
+    Added(EntityInfo),
+    Changed(EntityInfo),
+    /// The entity's retained status was cleared
+    Removed(EntityId),
+}
+
+/// Every entity on the broker, indexed by its metadata
+///
+/// Stays up to date for as long as it's alive, dropping it unsubscribes.
+pub struct Catalog {
+    conn: Arc<TanukiConnection>,
+    index: Arc<RwLock<Index>>,
+    changes: broadcast::Sender<CatalogChange>,
+    _subscriptions: [Subscription; 3],
+}
+
+/// Entities by ID, and the status of the clients authoring them
+#[derive(Default)]
+struct Index {
+    entities: BTreeMap<EntityId, EntityInfo>,
+    clients: BTreeMap<String, EntityStatus>,
+}
+
+impl Catalog {
+    /// Start indexing the entities on the broker
+    ///
+    /// Retained metadata arrives shortly after this returns, so the catalog may still be filling
+    /// up for a moment.
+    pub async fn new(conn: &Arc<TanukiConnection>) -> Result<Self> {
+        let index = Arc::new(RwLock::new(Index::default()));
+        let changes = broadcast::Sender::new(EVENT_CAPACITY);
+
+        let handler = || -> SubscriptionHandler {
+            let index = index.clone();
+            let changes = changes.clone();
+
+            Box::new(move |event: PublishEvent| {
+                let applied = apply(
+                    &mut index.write().unwrap_or_else(PoisonError::into_inner),
+                    &event.topic,
+                    &event.payload,
+                );
+
+                for change in applied {
+                    // fails only if nobody is listening
+                    let _ = changes.send(change);
+                }
+
+                true
+            })
+        };
+
+        let subscriptions = [
+            conn.subscribe_with_handler(TopicFilter::entity_meta(), handler())
+                .await?,
+            conn.subscribe_with_handler(
+                TopicFilter::any_capability_meta()
+                    .key(meta::Version::KEY)
+                    .map_err(Error::BadTopic)?,
+                handler(),
+            )
+            .await?,
+            conn.subscribe_with_handler(
+                TopicFilter::client_meta()
+                    .key(EntityStatus::KEY)
+                    .map_err(Error::BadTopic)?,
+                handler(),
+            )
+            .await?,
+        ];
+
+        Ok(Self {
+            conn: conn.clone(),
+            index,
+            changes,
+            _subscriptions: subscriptions,
+        })
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Index> {
+        self.index.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, entity: &EntityId) -> Option<EntityInfo> {
+        self.read().entities.get(entity).cloned()
+    }
+
+    /// All known entities
+    pub fn entities(&self) -> Vec<EntityInfo> {
+        self.read().entities.values().cloned().collect()
+    }
+
+    /// All entities matching `predicate`
+    pub fn find(&self, mut predicate: impl FnMut(&EntityInfo) -> bool) -> Vec<EntityInfo> {
+        self.read()
+            .entities
+            .values()
+            .filter(|info| predicate(info))
+            .cloned()
+            .collect()
+    }
+
+    /// All entities published by `provider`, eg. `tanuki-hass`
+    pub fn find_by_provider(&self, provider: &str) -> Vec<EntityInfo> {
+        self.find(|info| info.provider.as_deref() == Some(provider))
+    }
+
+    /// Capability `C` of every entity that has it
+    pub fn with_capability<C: Capability<User>>(&self) -> Vec<C> {
+        self.find(|info| info.has_capability(C::ID))
+            .into_iter()
+            .map(|info| self.conn.entity_cap::<C>(info.id))
+            .collect()
+    }
+
+    /// Receive every change to the catalog from now on
+    pub fn changes(&self) -> broadcast::Receiver<CatalogChange> {
+        self.changes.subscribe()
+    }
+}
+
+/// Query for entities by their metadata, such as tags
+///
+/// Resolved either against a snapshot of the broker with [`entities`](Self::entities), or against
+/// a long-lived [`Catalog`] with [`find_in`](Self::find_in).
+///
+/// ```no_run
+/// # use tanuki::{TanukiConnection, capabilities::{User, on_off::OnOff}};
+/// # async fn example(conn: std::sync::Arc<TanukiConnection>) -> tanuki::Result<()> {
+/// let lights = conn
+///     .select()
+///     .tag("room.living_room")
+///     .capability::<OnOff<User>>()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[must_use]
+pub struct Selector {
+    conn: Arc<TanukiConnection>,
+    tags: Vec<String>,
+    provider: Option<String>,
+}
+
+impl Selector {
+    /// Only select entities with `tag`, on top of any other tags
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    /// Only select entities in `room`, see [`Tags`]
+    pub fn room(self, room: &str) -> Self {
+        self.tag(format!("room.{room}"))
+    }
+
+    /// Only select entities published by `provider`
+    pub fn provider(mut self, provider: impl Into<String>) -> Self {
+        self.provider = Some(provider.into());
+        self
+    }
+
+    pub fn matches(&self, info: &EntityInfo) -> bool {
+        self.tags.iter().all(|tag| info.has_tag(tag))
+            && self
+                .provider
+                .as_ref()
+                .is_none_or(|provider| info.provider.as_ref() == Some(provider))
+    }
+
+    /// Every selected entity that currently has retained metadata
+    ///
+    /// Takes a snapshot through a temporary [`Catalog`], waiting a moment for it to fill up. To
+    /// query repeatedly, keep a catalog around and use [`find_in`](Self::find_in) instead.
+    pub async fn entities(&self) -> Result<Vec<EntityInfo>> {
+        // otherwise the subscription is only queued, and we'd wait for nothing
+        self.conn.wait_connected().await?;
+
+        let catalog = Catalog::new(&self.conn).await?;
+        tokio::time::sleep(RETAINED_GRACE_PERIOD).await;
+
+        Ok(self.find_in(&catalog))
+    }
+
+    /// Every selected entity in `catalog`, as it is now
+    pub fn find_in(&self, catalog: &Catalog) -> Vec<EntityInfo> {
+        catalog.find(|info| self.matches(info))
+    }
+
+    /// Capability `C` of every selected entity that has it
+    pub async fn capability<C: Capability<User>>(&self) -> Result<Vec<C>> {
+        Ok(self.capabilities(self.entities().await?))
+    }
+
+    /// Capability `C` of every selected entity in `catalog` that has it, see
+    /// [`find_in`](Self::find_in)
+    pub fn capability_in<C: Capability<User>>(&self, catalog: &Catalog) -> Vec<C> {
+        self.capabilities(self.find_in(catalog))
+    }
+
+    fn capabilities<C: Capability<User>>(&self, entities: Vec<EntityInfo>) -> Vec<C> {
+        entities
+            .into_iter()
+            .filter(|info| info.has_capability(C::ID))
+            .map(|info| self.conn.entity_cap::<C>(info.id))
+            .collect()
+    }
+}
+
+impl TanukiConnection {
+    /// Start a query for entities, see [`Selector`]
+    pub fn select(self: &Arc<Self>) -> Selector {
+        Selector {
+            conn: self.clone(),
+            tags: Vec::new(),
+            provider: None,
+        }
+    }
+}
+
+/// Update the index with a metadata value, returning what changed
+/// Apply a metadata update to the index, returning the entities it changed
+fn apply(index: &mut Index, topic: &Topic, value: &Value) -> Vec<CatalogChange> {
+    if let Topic::ClientMeta { client, key } = topic {
+        if key != EntityStatus::KEY {
+            return Vec::new();
+        }
+
+        match parse::<EntityStatus>(topic, value) {
+            Some(status) => index.clients.insert(client.to_string(), status),
+            None => index.clients.remove(client.as_str()),
+        };
+
+        return index
+            .entities
+            .values_mut()
+            .filter(|info| info.client.as_deref() == Some(client.as_str()))
+            .filter_map(|info| {
+                let status = effective_status(&index.clients, info);
+                (status != info.status).then(|| {
+                    info.status = status;
+                    CatalogChange::Changed(info.clone())
+                })
+            })
+            .collect();
+    }
+
+    apply_entity(index, topic, value).into_iter().collect()
+}
+
+fn apply_entity(index: &mut Index, topic: &Topic, value: &Value) -> Option<CatalogChange> {
+    let entity = topic.entity()?;
+    let entities = &mut index.entities;
+
+    if value.is_null() {
+        if !entities.contains_key(entity) {
+            return None;
+        }
+
+        if let Topic::EntityMeta { key, .. } = topic
+            && key == EntityStatus::KEY
+        {
+            entities.remove(entity);
+            return Some(CatalogChange::Removed(entity.clone()));
+        }
+    }
+
+    let added = !entities.contains_key(entity);
+    let info = entities
+        .entry(entity.clone())
+        .or_insert_with(|| EntityInfo::new(entity.clone()));
+    let before = info.clone();
+
+    match topic {
+        Topic::EntityMeta { key, .. } => match key.as_str() {
+            meta::Name::KEY => info.name = parse::<meta::Name>(topic, value).map(|v| v.0.into()),
+            meta::Type::KEY => {
+                info.entity_type = parse::<meta::Type>(topic, value).map(|v| v.0.into())
+            }
+            meta::Provider::KEY => {
+                info.provider = parse::<meta::Provider>(topic, value).map(|v| v.0.into())
+            }
+            meta::Client::KEY => {
+                info.client = parse::<meta::Client>(topic, value).map(|v| v.0.into())
+            }
+            EntityStatus::KEY => info.own_status = parse(topic, value),
+            Tags::KEY => info.tags = parse(topic, value).unwrap_or_default(),
+            _ => {}
+        },
+        Topic::CapabilityMeta { capability, key, .. } if key == meta::Version::KEY => {
+            match parse::<meta::Version>(topic, value) {
+                Some(version) => info.capabilities.insert(capability.clone(), version.0),
+                None => info.capabilities.remove(capability),
+            };
+        }
+        _ => {}
+    }
+
+    info.status = effective_status(&index.clients, info);
+
+    if added {
+        Some(CatalogChange::Added(info.clone()))
+    } else if *info != before {
+        Some(CatalogChange::Changed(info.clone()))
+    } else {
+        None
+    }
+}
+
+fn effective_status(
+    clients: &BTreeMap<String, EntityStatus>,
+    info: &EntityInfo,
+) -> Option<EntityStatus> {
+    let client = info.client.as_ref().and_then(|client| clients.get(client));
+    info.own_status
+        .map(|status| status.with_client_status(client.copied()))
+}
+
+/// Parse a metadata value, `None` if it was cleared or is malformed
+fn parse<T: Property>(topic: &Topic, value: &Value) -> Option<T> {
+    if value.is_null() {
+        return None;
+    }
+
+    serde_json::from_value(value.clone())
+        .inspect_err(|e| tracing::warn!(%topic, "Ignoring malformed metadata: {e}"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn catalog_index() {
+        let mut index = Index::default();
+        let mut apply =
+            |topic: &str, value: Value| apply(&mut index, &topic.parse().unwrap(), &value).pop();
+
+        assert!(matches!(
+            apply("tanuki/entities/desk.lamp/$meta/name", json!("Desk lamp")),
+            Some(CatalogChange::Added(info)) if info.name.as_deref() == Some("Desk lamp")
+        ));
+        assert!(matches!(
+            apply("tanuki/entities/desk.lamp/$meta/provider", json!("tanuki-hass")),
+            Some(CatalogChange::Changed(_))
+        ));
+        assert!(matches!(
+            apply("tanuki/entities/desk.lamp/tanuki.on_off/$meta/version", json!(1)),
+            Some(CatalogChange::Changed(info)) if info.has_capability("tanuki.on_off")
+        ));
+        assert!(matches!(
+            apply("tanuki/entities/desk.lamp/$meta/tags", json!(["room.office"])),
+            Some(CatalogChange::Changed(info)) if info.has_tag("room.office")
+        ));
+        assert!(matches!(
+            apply("tanuki/entities/desk.lamp/$meta/status", json!("online")),
+            Some(CatalogChange::Changed(info)) if info.status == Some(EntityStatus::Online)
+        ));
+
+        // same value again
+        assert!(apply("tanuki/entities/desk.lamp/$meta/status", json!("online")).is_none());
+        // metadata we don't index
+        assert!(apply("tanuki/entities/desk.lamp/$meta/icon", json!("lamp")).is_none());
+
+        // the status of the client authoring the entity carries over
+        assert!(matches!(
+            apply("tanuki/entities/desk.lamp/$meta/client", json!("tanuki-hass")),
+            Some(CatalogChange::Changed(info)) if info.client.as_deref() == Some("tanuki-hass")
+        ));
+        assert!(apply("tanuki/clients/dark-tanuki/$meta/status", json!("lost")).is_none());
+        assert!(matches!(
+            apply("tanuki/clients/tanuki-hass/$meta/status", json!("lost")),
+            Some(CatalogChange::Changed(info)) if info.status == Some(EntityStatus::Lost)
+        ));
+        assert!(apply("tanuki/entities/desk.lamp/$meta/status", json!("online")).is_none());
+        assert!(matches!(
+            apply("tanuki/clients/tanuki-hass/$meta/status", json!("online")),
+            Some(CatalogChange::Changed(info)) if info.status == Some(EntityStatus::Online)
+        ));
+
+        assert!(matches!(
+            apply("tanuki/entities/desk.lamp/$meta/status", Value::Null),
+            Some(CatalogChange::Removed(id)) if id == EntityId::from("desk.lamp")
+        ));
+        assert!(apply("tanuki/entities/desk.lamp/$meta/name", Value::Null).is_none());
+    }
+}
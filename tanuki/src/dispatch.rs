@@ -0,0 +1,413 @@
+use core::{convert::Infallible, str::FromStr as _, time::Duration};
+use std::{collections::BTreeSet, sync::Arc};
+
+use futures::Stream;
+use mqtt_protocol_core::mqtt::packet::{Property, v5_0::Publish};
+use tanuki_common::{Topic, TopicFilter};
+use tokio::{
+    sync::{broadcast, watch},
+    time::Instant,
+};
+
+use crate::{ConnectionState, Error, PublishEvent, Result, TanukiConnection};
+
+/// Number of events a receiver can fall behind before it starts missing them
+pub(crate) const EVENT_CAPACITY: usize = 1024;
+
+/// How long to wait for retained messages after subscribing, as they follow the SUBACK
+pub(crate) const RETAINED_GRACE_PERIOD: Duration = Duration::from_millis(250);
+
+/// Receives every PUBLISH event on the connection, independently of other receivers
+///
+/// Created with [`TanukiConnection::event_receiver`]. Only sees events received after it was
+/// created.
+pub struct EventReceiver {
+    pub(crate) rx: broadcast::Receiver<PublishEvent>,
+    pub(crate) state: watch::Receiver<ConnectionState>,
+}
+
+impl EventReceiver {
+    /// Receive the next event, or [`Error::Closed`] once the connection is shut down
+    pub async fn recv(&mut self) -> Result<PublishEvent> {
+        loop {
+            let res = tokio::select! {
+                res = self.rx.recv() => res,
+                _ = self.state.wait_for(|state| *state == ConnectionState::Closed) => {
+                    return Err(Error::Closed);
+                }
+            };
+
+            match res {
+                Ok(event) => return Ok(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Event receiver fell behind, missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return Err(Error::Closed),
+            }
+        }
+    }
+}
+
+/// Receives events like an [`EventReceiver`], minus the retained values replayed by the broker
+/// after subscribing
+///
+/// For reacting to things that happen, rather than to state that was already there.
+pub(crate) struct LiveEvents {
+    events: EventReceiver,
+    subscribed: broadcast::Receiver<TopicFilter>,
+    replay: Replay,
+}
+
+impl LiveEvents {
+    pub fn new(events: EventReceiver, subscribed: broadcast::Receiver<TopicFilter>) -> Self {
+        Self {
+            events,
+            subscribed,
+            replay: Replay::new(),
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<PublishEvent> {
+        loop {
+            tokio::select! {
+                // a SUBSCRIBE is announced before it's sent, so it's seen before its replay
+                biased;
+
+                filter = self.subscribed.recv() => match filter {
+                    Ok(filter) => self.replay.subscribed(filter, Instant::now()),
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Err(Error::Closed),
+                },
+                event = self.events.recv() => {
+                    let event = event?;
+                    if !self.replay.is_replayed(&event, Instant::now()) {
+                        return Ok(event);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tells the retained values the broker replays after subscribing apart from live ones
+///
+/// Both have the retain flag set. A replay has at most one value per topic though, so only the
+/// first retained value on each topic within the grace period after subscribing counts as
+/// replayed.
+pub(crate) struct Replay {
+    windows: Vec<ReplayWindow>,
+}
+
+struct ReplayWindow {
+    filter: TopicFilter,
+    until: Instant,
+    seen: BTreeSet<String>,
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self { windows: Vec::new() }
+    }
+
+    /// Expect the retained values on `filter` to be replayed, as it was just subscribed to
+    pub fn subscribed(&mut self, filter: TopicFilter, now: Instant) {
+        self.windows.push(ReplayWindow {
+            filter,
+            until: now + RETAINED_GRACE_PERIOD,
+            seen: BTreeSet::new(),
+        });
+    }
+
+    pub fn is_replayed(&mut self, event: &PublishEvent, now: Instant) -> bool {
+        if !event.retain {
+            return false;
+        }
+
+        self.windows.retain(|window| now < window.until);
+
+        // subscribing twice gets two replays, so each window takes one value per topic
+        let topic = event.topic.to_string();
+        self.windows
+            .iter_mut()
+            .any(|window| window.filter.matches(&event.topic) && window.seen.insert(topic.clone()))
+    }
+}
+
+impl TanukiConnection {
+    /// Fans a received PUBLISH out to subscription handlers and event receivers
+    pub(crate) async fn dispatch(self: &Arc<Self>, publish: Publish) {
+        let event = match PublishEvent::try_from(&publish) {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!(topic = publish.topic_name(), "Ignoring malformed publish: {e}");
+                return;
+            }
+        };
+
+        tracing::debug!("Dispatching publish event: {event:#?}");
+
+        // responses to our own requests aren't interesting to anyone else
+        if self.resolve_response(&event).await {
+            return;
+        }
+
+        // overlapping subscriptions put multiple identifiers on the same publish
+        let sub_ids = publish
+            .props
+            .iter()
+            .filter_map(|p| match p {
+                Property::SubscriptionIdentifier(id) => Some(id.val()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // handlers are documented not to block, as everything else waits for them
+        let done = self.subscriptions.lock().await.dispatch(&sub_ids, &event);
+        for sub_id in done {
+            // we're the ones reading the UNSUBACK, so don't wait for it here
+            let conn = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = conn.release_subscription(sub_id).await {
+                    tracing::warn!(sub_id, "Failed to unsubscribe: {e}");
+                }
+            });
+        }
+
+        // fails only if nobody is listening
+        let _ = self.events.send(event);
+    }
+
+    /// Create a receiver for every PUBLISH event from now on
+    ///
+    /// Each receiver sees all events, regardless of how many other receivers, listeners and
+    /// handlers there are.
+    pub fn event_receiver(&self) -> EventReceiver {
+        EventReceiver {
+            rx: self.events.subscribe(),
+            state: self.state.subscribe(),
+        }
+    }
+
+    /// Receive every event from now on, except for retained values replayed after subscribing
+    pub(crate) fn live_events(&self) -> LiveEvents {
+        LiveEvents::new(self.event_receiver(), self.subscribed.subscribe())
+    }
+
+    /// Stream of every event of type `E` from now on, such as
+    /// [`ButtonEvent`](crate::capabilities::buttons::ButtonEvent)s
+    ///
+    /// Only sees events on topics the connection is subscribed to. Ends when the connection is
+    /// shut down.
+    pub fn events<E>(&self) -> impl Stream<Item = E> + Send + Unpin + 'static
+    where
+        E: for<'event> TryFrom<&'event PublishEvent, Error = ()> + Send + 'static,
+    {
+        typed_events(self.event_receiver())
+    }
+
+    /// Receive the next PUBLISH event
+    ///
+    /// All callers of this method share a single queue, which starts on the first call. Use
+    /// [`event_receiver`](Self::event_receiver) or a [`Listener`](crate::listener::Listener) for
+    /// an independent view.
+    pub async fn recv(&self) -> Result<PublishEvent> {
+        let mut shared = self.shared_receiver.lock().await;
+        shared
+            .get_or_insert_with(|| self.event_receiver())
+            .recv()
+            .await
+    }
+
+    /// Receive the next PUBLISH event
+    #[deprecated = "packets are read by the connection itself, use `recv` or `event_receiver`"]
+    pub async fn recv_raw(&self) -> Result<PublishEvent> {
+        self.recv().await
+    }
+
+    /// Wait until the connection is closed
+    ///
+    /// This used to read packets and call subscription handlers, returning only on an error.
+    /// Handlers are called by the connection itself now, so it's no longer needed to drive them,
+    /// and just returns [`Error::Closed`] once the connection is [shut
+    /// down](Self::shutdown). Code that ran after it on a connection error should watch
+    /// [`connection_states`](Self::connection_states) instead, as the connection reconnects by
+    /// itself.
+    pub async fn handle(&self) -> Result<Infallible> {
+        let mut state = self.state.subscribe();
+        state
+            .wait_for(|state| *state == ConnectionState::Closed)
+            .await
+            .map_err(|_| Error::Closed)?;
+
+        Err(Error::Closed)
+    }
+}
+
+/// The events of type `E` among those `events` receives
+fn typed_events<E>(events: EventReceiver) -> impl Stream<Item = E> + Send + Unpin + 'static
+where
+    E: for<'event> TryFrom<&'event PublishEvent, Error = ()> + Send + 'static,
+{
+    Box::pin(futures::stream::unfold(events, async |mut events| {
+        loop {
+            let event = events.recv().await.ok()?;
+
+            if let Ok(event) = E::try_from(&event) {
+                break Some((event, events));
+            }
+        }
+    }))
+}
+
+impl TryFrom<&Publish> for PublishEvent {
+    type Error = Error;
+
+    fn try_from(publish: &Publish) -> Result<Self> {
+        let sub_id = publish.props.iter().find_map(|p| {
+            if let Property::SubscriptionIdentifier(id) = p {
+                Some(id.clone())
+            } else {
+                None
+            }
+        });
+
+        let topic = Topic::from_str(publish.topic_name()).map_err(Error::BadTopic)?;
+
+        let payload = match publish.payload().as_slice() {
+            // clears a retained message
+            [] => serde_json::Value::Null,
+            payload => serde_json::from_slice(payload)?,
+        };
+
+        let mut response_topic = None;
+        let mut correlation_data = None;
+        for prop in &publish.props {
+            match prop {
+                Property::ResponseTopic(topic) => response_topic = Some(topic.val().to_owned()),
+                Property::CorrelationData(data) => correlation_data = Some(data.val().to_vec()),
+                _ => {}
+            }
+        }
+
+        Ok(PublishEvent {
+            sub_id,
+            topic,
+            payload,
+            retain: publish.retain(),
+            response_topic,
+            correlation_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt as _;
+    use serde_json::json;
+
+    use super::*;
+    use crate::capabilities::sensor::SensorEvent;
+
+    #[tokio::test]
+    async fn typed_event_stream() {
+        let (events, rx) = broadcast::channel(8);
+        let (state, state_rx) = watch::channel(ConnectionState::Connected);
+        let mut sensors = typed_events::<SensorEvent>(EventReceiver { rx, state: state_rx });
+
+        let publish = |topic: &str, payload| PublishEvent {
+            sub_id: None,
+            topic: topic.parse().unwrap(),
+            payload,
+            retain: true,
+            response_topic: None,
+            correlation_data: None,
+        };
+        let reading = json!({ "value": 21.5, "unit": "°C", "timestamp": 0 });
+
+        events
+            .send(publish("tanuki/entities/desk.lamp/tanuki.on_off/on", json!(true)))
+            .unwrap();
+        // cleared readings are skipped
+        events
+            .send(publish(
+                "tanuki/entities/hall/tanuki.sensor/temperature",
+                serde_json::Value::Null,
+            ))
+            .unwrap();
+        events
+            .send(publish("tanuki/entities/hall/tanuki.sensor/temperature", reading))
+            .unwrap();
+
+        let event = sensors.next().await.unwrap();
+        assert_eq!(event.as_str_tuple(), ("hall", "temperature"));
+        assert_eq!(event.payload.value, 21.5_f32.into());
+
+        state.send_replace(ConnectionState::Closed);
+        assert!(sensors.next().await.is_none());
+    }
+
+    #[test]
+    fn replayed_retained_values() {
+        let event = |topic: &str, retain| PublishEvent {
+            sub_id: None,
+            topic: topic.parse::<Topic>().unwrap(),
+            payload: serde_json::Value::Bool(true),
+            retain,
+            response_topic: None,
+            correlation_data: None,
+        };
+        let on = "tanuki/entities/desk.lamp/tanuki.on_off/on";
+        let name = "tanuki/entities/desk.lamp/$meta/name";
+        let press = "tanuki/entities/remote/tanuki.buttons/on";
+        let data = TopicFilter::capability_data("tanuki.on_off").unwrap();
+
+        let start = Instant::now();
+        let mut replay = Replay::new();
+        assert!(!replay.is_replayed(&event(on, true), start));
+
+        replay.subscribed(data.clone(), start);
+        assert!(!replay.is_replayed(&event(press, false), start));
+        assert!(replay.is_replayed(&event(on, true), start));
+        // a second value on the same topic is live, even with the retain flag
+        assert!(!replay.is_replayed(&event(on, true), start));
+        // as are values on topics that weren't subscribed to
+        assert!(!replay.is_replayed(&event(name, true), start));
+
+        // every subscription gets a replay of its own
+        replay.subscribed(data.clone(), start);
+        replay.subscribed(TopicFilter::all(), start);
+        assert!(replay.is_replayed(&event(on, true), start));
+        assert!(replay.is_replayed(&event(on, true), start));
+        assert!(!replay.is_replayed(&event(on, true), start));
+
+        let later = start + RETAINED_GRACE_PERIOD;
+        replay.subscribed(data, start);
+        assert!(!replay.is_replayed(&event(on, true), later));
+    }
+
+    #[tokio::test]
+    async fn live_events() {
+        let (events, rx) = broadcast::channel(8);
+        let (subscribed, subscribed_rx) = broadcast::channel(8);
+        let (_state, state_rx) = watch::channel(ConnectionState::Connected);
+        let mut live = LiveEvents::new(EventReceiver { rx, state: state_rx }, subscribed_rx);
+
+        let publish = |payload, retain| PublishEvent {
+            sub_id: None,
+            topic: "tanuki/entities/desk.lamp/tanuki.on_off/on"
+                .parse()
+                .unwrap(),
+            payload,
+            retain,
+            response_topic: None,
+            correlation_data: None,
+        };
+
+        // the replay after subscribing is skipped, the value after it is live
+        subscribed.send(TopicFilter::all()).unwrap();
+        events.send(publish(json!(false), true)).unwrap();
+        events.send(publish(json!(true), true)).unwrap();
+
+        assert_eq!(live.recv().await.unwrap().payload, json!(true));
+    }
+}
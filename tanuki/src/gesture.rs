@@ -0,0 +1,381 @@
+//! Recognizing multi-presses and holds from raw button actions
+//!
+//! Many buttons only report when they're pressed and released, or even just pressed. A
+//! [`GestureRecognizer`] turns those into double/triple presses, long presses and repeated
+//! [`Held`](ButtonAction::Held) actions, based on their timing.
+
+use core::time::Duration;
+use std::collections::{HashMap, VecDeque};
+
+use futures::{Stream, StreamExt as _};
+use tanuki_common::{EntityId, capabilities::buttons::ButtonAction};
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::{
+    Authority, Result, TanukiCapability,
+    capabilities::buttons::{ButtonEvent, ButtonName, Buttons},
+};
+
+/// Timings for recognizing gestures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GestureConfig {
+    multi_press_window: Duration,
+    long_press: Duration,
+    repeat: Option<Duration>,
+    releases: bool,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            multi_press_window: Self::DEFAULT_MULTI_PRESS_WINDOW,
+            long_press: Self::DEFAULT_LONG_PRESS,
+            repeat: None,
+            releases: true,
+        }
+    }
+}
+
+impl GestureConfig {
+    pub const DEFAULT_MULTI_PRESS_WINDOW: Duration = Duration::from_millis(400);
+    pub const DEFAULT_LONG_PRESS: Duration = Duration::from_millis(800);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait for another press before deciding how many presses there were
+    pub fn multi_press_window(mut self, window: Duration) -> Self {
+        self.multi_press_window = window;
+        self
+    }
+
+    /// How long a button has to be held down to count as a long press
+    pub fn long_press(mut self, long_press: Duration) -> Self {
+        self.long_press = long_press;
+        self
+    }
+
+    /// Send [`Held`](ButtonAction::Held) every `interval` while the button is held down after a
+    /// long press, off by default
+    pub fn repeat(mut self, interval: Duration) -> Self {
+        self.repeat = Some(interval);
+        self
+    }
+
+    /// For buttons that only send [`Pressed`](ButtonAction::Pressed) once they're let go
+    ///
+    /// Only multi-presses can be recognized, as there's no way to tell how long a button was held.
+    pub fn press_only(mut self) -> Self {
+        self.releases = false;
+        self
+    }
+}
+
+#[derive(Default)]
+struct ButtonState {
+    presses: u8,
+    down: bool,
+    holding: bool,
+    deadline: Option<Instant>,
+}
+
+/// Turns raw [`Pressed`](ButtonAction::Pressed) and [`Released`](ButtonAction::Released)
+/// actions into gestures
+///
+/// Doesn't keep time by itself: feed it actions with [`input`](Self::input), and call
+/// [`poll`](Self::poll) by the [`next_deadline`](Self::next_deadline). Or let
+/// [`recognize`](Self::recognize) do both.
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    buttons: HashMap<(EntityId, ButtonName), ButtonState>,
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        Self { config, buttons: HashMap::new() }
+    }
+
+    /// Whether `action` is something the recognizer builds gestures from
+    pub fn is_raw(action: ButtonAction) -> bool {
+        matches!(action, ButtonAction::Pressed | ButtonAction::Released)
+    }
+
+    /// Feed a raw action received at `now`, returning any gestures it completes
+    ///
+    /// Other actions are ignored.
+    pub fn input(&mut self, event: &ButtonEvent, now: Instant) -> Vec<ButtonEvent> {
+        let key = (event.entity.clone(), event.name.clone());
+        let state = self.buttons.entry(key).or_default();
+
+        let finished = match (event.action, self.config.releases) {
+            (ButtonAction::Pressed, true) => {
+                // still down from before, so its release was missed
+                if state.down || state.holding {
+                    *state = ButtonState::default();
+                }
+
+                state.down = true;
+                state.presses += 1;
+                state.deadline = Some(now + self.config.long_press);
+                None
+            }
+            (ButtonAction::Pressed, false) => {
+                state.presses += 1;
+                state.deadline = Some(now + self.config.multi_press_window);
+                (state.presses >= 3).then_some(ButtonAction::TriplePressed)
+            }
+            (ButtonAction::Released, true) if state.holding => Some(ButtonAction::Released),
+            (ButtonAction::Released, true) if state.down => {
+                state.down = false;
+                state.deadline = Some(now + self.config.multi_press_window);
+                (state.presses >= 3).then_some(ButtonAction::TriplePressed)
+            }
+            _ => None,
+        };
+
+        match finished {
+            Some(action) => {
+                self.buttons
+                    .remove(&(event.entity.clone(), event.name.clone()));
+                vec![ButtonEvent { action, ..event.clone() }]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Finish the gestures that timed out by `now`
+    pub fn poll(&mut self, now: Instant) -> Vec<ButtonEvent> {
+        let mut gestures = Vec::new();
+
+        self.buttons.retain(|(entity, name), state| {
+            while let Some(deadline) = state.deadline
+                && deadline <= now
+            {
+                let action = if state.holding {
+                    state.deadline = self.config.repeat.map(|repeat| deadline + repeat);
+                    ButtonAction::Held
+                } else if state.down {
+                    state.holding = true;
+                    state.presses = 0;
+                    state.deadline = self.config.repeat.map(|repeat| deadline + repeat);
+                    ButtonAction::LongPressed
+                } else {
+                    let action = match state.presses {
+                        1 => ButtonAction::Pressed,
+                        2 => ButtonAction::DoublePressed,
+                        _ => ButtonAction::TriplePressed,
+                    };
+                    gestures.push(ButtonEvent {
+                        entity: entity.clone(),
+                        name: name.clone(),
+                        action,
+                    });
+                    return false;
+                };
+
+                gestures.push(ButtonEvent {
+                    entity: entity.clone(),
+                    name: name.clone(),
+                    action,
+                });
+            }
+
+            true
+        });
+
+        gestures
+    }
+
+    /// When [`poll`](Self::poll) needs to be called next, if there's anything in progress
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.buttons
+            .values()
+            .filter_map(|state| state.deadline)
+            .min()
+    }
+
+    /// Recognize gestures in a stream of button events, such as
+    /// [`TanukiConnection::events`](crate::TanukiConnection::events)
+    ///
+    /// Actions that aren't [raw](Self::is_raw), like long presses reported by the device itself,
+    /// are passed through as-is.
+    pub fn recognize(
+        self,
+        events: impl Stream<Item = ButtonEvent> + Send + Unpin + 'static,
+    ) -> impl Stream<Item = ButtonEvent> + Send + Unpin + 'static {
+        Box::pin(futures::stream::unfold(
+            (self, events, VecDeque::new()),
+            async |(mut recognizer, mut events, mut queue)| {
+                loop {
+                    if let Some(gesture) = queue.pop_front() {
+                        return Some((gesture, (recognizer, events, queue)));
+                    }
+
+                    let deadline = recognizer.next_deadline();
+
+                    tokio::select! {
+                        event = events.next() => {
+                            let event = event?;
+
+                            if Self::is_raw(event.action) {
+                                queue.extend(recognizer.input(&event, Instant::now()));
+                            } else {
+                                queue.push_back(event);
+                            }
+                        }
+                        _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                            if deadline.is_some() =>
+                        {
+                            queue.extend(recognizer.poll(Instant::now()));
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
+impl Buttons<Authority> {
+    /// Recognize gestures in the actions published on this entity, and publish the recognized
+    /// multi-presses and holds alongside them
+    ///
+    /// Meant for devices that only report single presses. Presses and releases are already on
+    /// the topic, so only the gestures built from them are published.
+    pub async fn publish_gestures(&self, config: GestureConfig) -> Result<GesturePublisher> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let entity = self.entity_id().clone();
+        let subscription = self
+            .listen(move |name, action| {
+                let _ = tx.send(ButtonEvent {
+                    entity: entity.clone(),
+                    name: ButtonName::from(name.to_owned()),
+                    action,
+                });
+            })
+            .await?;
+
+        let raw = futures::stream::poll_fn(move |cx| rx.poll_recv(cx)).filter(|event| {
+            // including our own gestures would count them twice
+            core::future::ready(GestureRecognizer::is_raw(event.action))
+        });
+
+        let buttons = Buttons::<Authority>::from(TanukiCapability {
+            entity: self.entity(),
+            capability: self.capability.clone(),
+        });
+
+        let task = tokio::spawn(async move {
+            let _subscription = subscription;
+            let mut gestures = GestureRecognizer::new(config).recognize(raw);
+
+            while let Some(gesture) = gestures.next().await {
+                if GestureRecognizer::is_raw(gesture.action) {
+                    continue;
+                }
+
+                if let Err(e) = buttons
+                    .publish_action(gesture.name.as_str(), gesture.action)
+                    .await
+                {
+                    tracing::warn!(entity = %gesture.entity, "Failed to publish gesture: {e}");
+                }
+            }
+        });
+
+        Ok(GesturePublisher { task })
+    }
+}
+
+/// Publishes recognized gestures, see [`Buttons::publish_gestures`]
+///
+/// Stops when dropped.
+pub struct GesturePublisher {
+    task: JoinHandle<()>,
+}
+
+impl Drop for GesturePublisher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(action: ButtonAction) -> ButtonEvent {
+        ButtonEvent {
+            entity: EntityId::from("remote"),
+            name: ButtonName::On,
+            action,
+        }
+    }
+
+    fn actions(events: Vec<ButtonEvent>) -> Vec<ButtonAction> {
+        events.into_iter().map(|event| event.action).collect()
+    }
+
+    #[test]
+    fn gestures() {
+        use ButtonAction::*;
+
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let config = GestureConfig::new()
+            .multi_press_window(ms(300))
+            .long_press(ms(500))
+            .repeat(ms(200));
+        let mut rec = GestureRecognizer::new(config.clone());
+
+        // double press
+        assert!(rec.input(&event(Pressed), start).is_empty());
+        assert!(rec.input(&event(Released), start + ms(100)).is_empty());
+        assert!(rec.input(&event(Pressed), start + ms(200)).is_empty());
+        assert!(rec.input(&event(Released), start + ms(300)).is_empty());
+        assert_eq!(rec.next_deadline(), Some(start + ms(600)));
+        assert!(rec.poll(start + ms(599)).is_empty());
+        assert_eq!(actions(rec.poll(start + ms(600))), [DoublePressed]);
+        assert_eq!(rec.next_deadline(), None);
+
+        // triple press finishes right away
+        let start = start + ms(1000);
+        for i in 0..3 {
+            rec.input(&event(Pressed), start + ms(i * 200));
+            let done = rec.input(&event(Released), start + ms(i * 200 + 100));
+            assert_eq!(actions(done), if i == 2 { vec![TriplePressed] } else { vec![] });
+        }
+
+        // hold with repeats
+        let start = start + ms(1000);
+        rec.input(&event(Pressed), start);
+        assert_eq!(actions(rec.poll(start + ms(500))), [LongPressed]);
+        assert_eq!(actions(rec.poll(start + ms(950))), [Held, Held]);
+        assert_eq!(actions(rec.input(&event(Released), start + ms(1000))), [Released]);
+        assert_eq!(rec.next_deadline(), None);
+
+        // a release missed after a long press without repeats doesn't leave the button held
+        let start = start + ms(2000);
+        let mut rec = GestureRecognizer::new(
+            GestureConfig::new()
+                .multi_press_window(ms(300))
+                .long_press(ms(500)),
+        );
+        rec.input(&event(Pressed), start);
+        assert_eq!(actions(rec.poll(start + ms(500))), [LongPressed]);
+        assert_eq!(rec.next_deadline(), None);
+        rec.input(&event(Pressed), start + ms(5000));
+        assert!(rec.input(&event(Released), start + ms(5100)).is_empty());
+        assert_eq!(actions(rec.poll(start + ms(5400))), [Pressed]);
+        assert_eq!(rec.next_deadline(), None);
+
+        // press-only buttons
+        let mut rec = GestureRecognizer::new(config.press_only());
+        rec.input(&event(Pressed), start);
+        assert_eq!(actions(rec.poll(start + ms(300))), [Pressed]);
+        rec.input(&event(Pressed), start);
+        rec.input(&event(Pressed), start + ms(200));
+        assert_eq!(actions(rec.poll(start + ms(500))), [DoublePressed]);
+    }
+}
@@ -0,0 +1,348 @@
+//! Several entities controlled as one
+//!
+//! A [`Group`] sends every command to all of its members at once, and combines their states into
+//! one. It can also be [published](Group::publish_as) as an entity of its own, so other clients
+//! can control it like any single device.
+
+use std::sync::Arc;
+
+use futures::{StreamExt as _, future::join_all, stream::select_all};
+use tanuki_common::{
+    EntityId, Property,
+    capabilities::{
+        light::{LightCommand, LightState},
+        on_off::{On, OnOffCommand},
+    },
+    meta,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    Authority, PublishOpts, Result, Subscription, TanukiCapability, TanukiConnection, TanukiEntity,
+    User,
+    capabilities::{Capability, light::Light, on_off::OnOff},
+    catalog::Catalog,
+};
+
+/// A capability that can be grouped, with a way to combine the states of the members
+pub trait GroupMember: Capability<User> + Send + Sync + 'static {
+    type Command: Property + Clone + Send + Sync + 'static;
+    type State: Property + Send + Sync + 'static;
+    type Aggregate: Clone + PartialEq + Send + Sync + 'static;
+
+    /// Combine the states of the members, `None` for members whose state isn't known
+    fn aggregate(states: &[Option<Self::State>]) -> Self::Aggregate;
+
+    /// State to publish for the group when it's exposed as an entity
+    fn group_state(aggregate: &Self::Aggregate) -> Self::State;
+}
+
+/// Combined state of a group of [`OnOff`]s
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupOn {
+    /// At least one member is on
+    pub any: bool,
+    /// Every member is known to be on, `false` for an empty group
+    pub all: bool,
+}
+
+impl GroupMember for OnOff<User> {
+    type Command = OnOffCommand;
+    type State = On;
+    type Aggregate = GroupOn;
+
+    fn aggregate(states: &[Option<On>]) -> GroupOn {
+        GroupOn {
+            any: states.iter().any(|state| matches!(state, Some(On(true)))),
+            all: !states.is_empty() && states.iter().all(|state| matches!(state, Some(On(true)))),
+        }
+    }
+
+    fn group_state(aggregate: &GroupOn) -> On {
+        On(aggregate.any)
+    }
+}
+
+/// Combined state of a group of [`Light`]s
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroupLightState {
+    /// At least one member is on
+    pub any_on: bool,
+    /// Every member is known to be on, `false` for an empty group
+    pub all_on: bool,
+    /// Average brightness of the members that are on and report one
+    pub brightness: Option<f32>,
+}
+
+impl GroupMember for Light<User> {
+    type Command = LightCommand;
+    type State = LightState;
+    type Aggregate = GroupLightState;
+
+    fn aggregate(states: &[Option<LightState>]) -> GroupLightState {
+        let is_on = |state: &Option<LightState>| state.as_ref().is_some_and(|state| state.on);
+
+        let brightness = states
+            .iter()
+            .flatten()
+            .filter(|state| state.on)
+            .filter_map(|state| state.brightness)
+            .collect::<Vec<_>>();
+
+        GroupLightState {
+            any_on: states.iter().any(is_on),
+            all_on: !states.is_empty() && states.iter().all(is_on),
+            brightness: (!brightness.is_empty())
+                .then(|| brightness.iter().sum::<f32>() / brightness.len() as f32),
+        }
+    }
+
+    fn group_state(aggregate: &GroupLightState) -> LightState {
+        LightState {
+            on: aggregate.any_on,
+            brightness: aggregate.brightness,
+            color: None,
+        }
+    }
+}
+
+/// The same capability of several entities
+pub struct Group<C> {
+    conn: Arc<TanukiConnection>,
+    members: Arc<[C]>,
+}
+
+impl<C> Clone for Group<C> {
+    fn clone(&self) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            members: self.members.clone(),
+        }
+    }
+}
+
+impl<C: GroupMember> Group<C> {
+    /// Group the entities with the given IDs
+    pub fn new(
+        conn: &Arc<TanukiConnection>,
+        ids: impl IntoIterator<Item = impl Into<EntityId>>,
+    ) -> Self {
+        Self::from_members(conn, ids.into_iter().map(|id| conn.entity_cap::<C>(id)))
+    }
+
+    /// Group every entity with `tag` that has the capability
+    ///
+    /// Members are looked up once, from a snapshot of the retained metadata on the broker. Entities
+    /// whose tags aren't retained yet, such as those of a bridge that's still starting up, don't
+    /// join the group, and neither do entities tagged later. Use [`tagged_in`](Self::tagged_in)
+    /// with a long-lived [`Catalog`] to look them up again.
+    pub async fn tagged(conn: &Arc<TanukiConnection>, tag: &str) -> Result<Self> {
+        let members = conn.select().tag(tag).capability::<C>().await?;
+        Ok(Self::from_members(conn, members))
+    }
+
+    /// Group every entity in `catalog` with `tag` that has the capability, as it is now
+    pub fn tagged_in(conn: &Arc<TanukiConnection>, catalog: &Catalog, tag: &str) -> Self {
+        Self::from_members(conn, conn.select().tag(tag).capability_in::<C>(catalog))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn from_members(
+        conn: &Arc<TanukiConnection>,
+        members: impl IntoIterator<Item = C>,
+    ) -> Self {
+        Self {
+            conn: conn.clone(),
+            members: members.into_iter().collect(),
+        }
+    }
+
+    pub fn members(&self) -> &[C] {
+        &self.members
+    }
+
+    /// Send `cmd` to every member at once
+    ///
+    /// Every member gets the command even if sending it to some fails, the first error is
+    /// returned.
+    pub async fn command(&self, cmd: C::Command) -> Result<()> {
+        let results = join_all(self.members.iter().map(|member| {
+            let cmd = cmd.clone();
+            async move {
+                member
+                    .publish_property(cmd, PublishOpts::control())
+                    .await
+                    .inspect_err(|e| {
+                        tracing::warn!(entity = %member.entity_id(), "Group command failed: {e}")
+                    })
+            }
+        }))
+        .await;
+
+        results.into_iter().collect()
+    }
+
+    /// Combined current state of the members
+    ///
+    /// Members without a retained state count as unknown.
+    pub async fn state(&self) -> Result<C::Aggregate> {
+        let states = join_all(
+            self.members
+                .iter()
+                .map(|member| member.try_get::<C::State>()),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+        Ok(C::aggregate(&states))
+    }
+
+    /// Publish the group as entity `id`, forwarding its commands to the members and keeping its
+    /// state up to date
+    ///
+    /// The entity stays up for as long as the returned [`VirtualGroup`] is alive.
+    pub async fn publish_as(&self, id: impl Into<EntityId>) -> Result<VirtualGroup> {
+        let init = self.conn.author_entity(id).await?;
+        init.publish_meta(meta::Type("Group".into())).await?;
+
+        let cap = TanukiCapability::<Authority> {
+            entity: (*init).clone(),
+            capability: C::ID.into(),
+        };
+        cap.initialize(C::VERSION).await?;
+
+        let group = self.clone();
+        let commands = cap
+            .handle_commands::<C::Command, C::State>(move |cmd, reply| {
+                let group = group.clone();
+
+                tokio::spawn(async move {
+                    let res = match group.command(cmd).await {
+                        Ok(()) => reply.success(None).await,
+                        Err(e) => reply.failure(e).await,
+                    };
+
+                    if let Err(e) = res {
+                        tracing::warn!("Failed to reply to group command: {e}");
+                    }
+                });
+            })
+            .await?;
+
+        let mut updates = Vec::new();
+        for (i, member) in self.members.iter().enumerate() {
+            let states = member.watch::<C::State>().await?;
+            updates.push(states.map(move |state| (i, state)));
+        }
+
+        let entity = init.ready().await?;
+
+        let task = tokio::spawn({
+            let mut states = (0..self.members.len()).map(|_| None).collect::<Vec<_>>();
+            let mut updates = select_all(updates);
+
+            async move {
+                let mut published = None;
+
+                while let Some((i, state)) = updates.next().await {
+                    // cleared states count as unknown
+                    states[i] = state;
+
+                    let aggregate = C::aggregate(&states);
+                    if published.as_ref() == Some(&aggregate) {
+                        continue;
+                    }
+
+                    if let Err(e) = cap
+                        .publish_property(C::group_state(&aggregate), PublishOpts::entity_data())
+                        .await
+                    {
+                        tracing::warn!("Failed to publish group state: {e}");
+                        continue;
+                    }
+
+                    published = Some(aggregate);
+                }
+            }
+        });
+
+        Ok(VirtualGroup { entity, task, _commands: commands })
+    }
+}
+
+/// A [`Group`] published as an entity, see [`Group::publish_as`]
+///
+/// Stops updating and handling commands when dropped, and marks the entity
+/// [`Disconnected`](meta::EntityStatus::Disconnected).
+pub struct VirtualGroup {
+    entity: Arc<TanukiEntity<Authority>>,
+    task: JoinHandle<()>,
+    _commands: Subscription,
+}
+
+impl VirtualGroup {
+    pub fn entity(&self) -> &Arc<TanukiEntity<Authority>> {
+        &self.entity
+    }
+}
+
+impl Drop for VirtualGroup {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.entity.disconnect_in_background();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate_states() {
+        assert_eq!(<OnOff<User>>::aggregate(&[Some(On(true)), Some(On(false))]), GroupOn {
+            any: true,
+            all: false
+        });
+        assert_eq!(<OnOff<User>>::aggregate(&[Some(On(true)), None]), GroupOn {
+            any: true,
+            all: false
+        });
+        assert_eq!(<OnOff<User>>::aggregate(&[Some(On(true)), Some(On(true))]), GroupOn {
+            any: true,
+            all: true
+        });
+
+        let light = |on, brightness| Some(LightState { on, brightness, color: None });
+
+        assert_eq!(
+            <Light<User>>::aggregate(&[
+                light(true, Some(0.25)),
+                light(true, Some(0.75)),
+                light(false, Some(1.0)),
+                light(true, None),
+            ]),
+            GroupLightState {
+                any_on: true,
+                all_on: false,
+                brightness: Some(0.5),
+            }
+        );
+        assert_eq!(<Light<User>>::aggregate(&[light(false, None), None]), GroupLightState {
+            any_on: false,
+            all_on: false,
+            brightness: None,
+        });
+
+        // an empty group is neither on nor off
+        assert_eq!(<OnOff<User>>::aggregate(&[]), GroupOn { any: false, all: false });
+        assert_eq!(<Light<User>>::aggregate(&[]), GroupLightState {
+            any_on: false,
+            all_on: false,
+            brightness: None,
+        });
+    }
+}
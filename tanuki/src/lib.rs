@@ -1,38 +1,62 @@
 #![feature(async_fn_traits, macro_attr, unboxed_closures)]
 
-use core::{convert::Infallible, marker::PhantomData, str::FromStr as _, sync::atomic::AtomicU16};
-use std::{collections::BTreeMap, sync::Arc};
-
-use mqtt_endpoint_tokio::mqtt_ep::{
-    self, Endpoint,
-    packet::v5_0,
-    role,
-    transport::{TcpTransport, connect_helper},
+use core::{marker::PhantomData, sync::atomic::AtomicU16};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
 };
-use mqtt_protocol_core::mqtt::packet::{
-    Property, Qos, SubEntry, SubOpts, SubscriptionIdentifier,
-    v5_0::{Connack, Publish},
+
+use futures::Stream;
+use mqtt_endpoint_tokio::mqtt_ep::{self, Endpoint, packet::v5_0, role};
+use mqtt_protocol_core::mqtt::{
+    packet::{Properties, Property, Qos, SubEntry, SubOpts, SubscriptionIdentifier, v5_0::Connack},
+    result_code::{ConnectReasonCode, PubackReasonCode, SubackReasonCode, UnsubackReasonCode},
 };
 use serde::Serialize;
 use tanuki_common::{
-    EntityId, TanukiString, ToTanukiString, Topic,
+    EntityId, Property as _, TanukiString, ToTanukiString, Topic, TopicFilter,
     meta::{self, MetaField},
 };
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OnceCell, broadcast, oneshot, watch};
 
 use self::{
+    ack::PendingAck,
     capabilities::{Authority, EntityRole, User},
     listener::{EventHandler, Listener},
+    options::Connector,
+    request::PendingResponses,
+    subscription::Subscriptions,
 };
 use crate::capabilities::{Capability, TanukiCapability};
 
+mod ack;
 pub mod capabilities;
+pub mod catalog;
+mod dispatch;
+pub mod gesture;
+pub mod group;
 pub mod listener;
 pub mod log;
+pub mod mirror;
+pub mod operators;
+pub mod options;
 pub mod registry;
+mod request;
+pub mod rule;
+pub mod scene;
+pub mod schedule;
+mod subscription;
+mod supervisor;
+pub mod virtual_entity;
+pub mod wait;
 
 pub use tanuki_common as common;
 
+pub use self::{
+    dispatch::EventReceiver, options::ConnectOptions, request::CommandReply,
+    subscription::Subscription, supervisor::ConnectionState,
+};
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(thiserror::Error, Debug)]
@@ -49,6 +73,57 @@ pub enum Error {
     SerdeJson(#[from] serde_json::Error),
     #[error("bad topic: {0}")]
     BadTopic(&'static str),
+    #[error("invalid id {id:?}: {reason}")]
+    InvalidId { id: String, reason: &'static str },
+    #[error("connection refused by broker: {0}")]
+    ConnectionRefused(ConnectReasonCode),
+    #[error("connection closed")]
+    Closed,
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("connection lost before the broker acknowledged")]
+    AckLost,
+    #[error("mqtt protocol error: {0}")]
+    Protocol(String),
+    #[error("publish rejected by broker: {0}")]
+    PublishRejected(PubackReasonCode),
+    #[error("subscription rejected by broker: {0}")]
+    SubscriptionRejected(SubackReasonCode),
+    #[error("unsubscribe rejected by broker: {0}")]
+    UnsubscribeRejected(UnsubackReasonCode),
+    #[error("bad payload on {topic}: {source}")]
+    BadPayload { topic: String, source: serde_json::Error },
+    #[error("command failed: {0}")]
+    CommandFailed(String),
+    #[error("timed out")]
+    Timeout,
+    #[error("a rule called '{0}' already exists")]
+    DuplicateRule(String),
+    #[error("invalid schedule: {0}")]
+    InvalidSchedule(String),
+    #[error("bad broker url: {0}")]
+    BadUrl(String),
+    #[cfg(feature = "tls")]
+    #[error("tls error: {0}")]
+    Tls(#[from] rustls::Error),
+    #[cfg(feature = "tls")]
+    #[error("pem error: {0}")]
+    Pem(#[from] rustls::pki_types::pem::Error),
+}
+
+impl Error {
+    /// Whether this error was caused by the broker connection going away, in which case the
+    /// operation can be retried once the connection has been re-established
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            Error::MqttTransport(_)
+                | Error::AckLost
+                | Error::MqttConnection(
+                    mqtt_ep::ConnectionError::NotConnected | mqtt_ep::ConnectionError::Transport(_)
+                )
+        )
+    }
 }
 
 impl From<mqtt_ep::result_code::MqttError> for Error {
@@ -60,151 +135,312 @@ impl From<mqtt_ep::result_code::MqttError> for Error {
 pub(crate) type SubscriptionHandler = Box<dyn FnMut(PublishEvent) -> bool + Send + Sync>;
 
 pub struct TanukiConnection {
-    endpoint: Endpoint<role::Client>,
+    /// Shared with the supervisor, which keeps reading from it without keeping us alive
+    endpoint: Arc<Endpoint<role::Client>>,
+    client_id: String,
+    connector: Connector,
     next_payload_id: AtomicU16,
-    // key could be SubscriptionIdentifier if it implemented Ord
-    sub_handlers: Mutex<BTreeMap<u32, SubscriptionHandler>>,
+    state: watch::Sender<ConnectionState>,
+    /// Every received PUBLISH, for event receivers and listeners
+    events: broadcast::Sender<PublishEvent>,
+    /// Every topic filter we send a SUBSCRIBE for, after which the broker replays retained values
+    subscribed: broadcast::Sender<TopicFilter>,
+    /// Receiver shared by all callers of [`recv`](Self::recv)
+    shared_receiver: Mutex<Option<EventReceiver>>,
+    /// Subscriptions on the broker and their handlers, replayed after reconnecting
+    subscriptions: Mutex<Subscriptions>,
+    /// Serialized retained metadata by topic, republished after reconnecting
+    retained_meta: Mutex<BTreeMap<String, String>>,
+    /// Entities authored through this connection, marked as disconnected on shutdown
+    authored: Mutex<BTreeSet<EntityId>>,
+    /// Senders waiting for an acknowledgement, by packet ID
+    pending_acks: Mutex<BTreeMap<u16, oneshot::Sender<mqtt_ep::packet::Packet>>>,
+    /// Set once we're subscribed to responses to our requests
+    responses_subscribed: OnceCell<()>,
+    pending_responses: Mutex<PendingResponses>,
 }
 
 impl TanukiConnection {
-    pub async fn connect(client_id: &str, addr: &str) -> Result<Arc<Self>> {
+    /// Connect to the broker at `url`, see [`ConnectOptions`] for the supported formats
+    pub async fn connect(client_id: &str, url: &str) -> Result<Arc<Self>> {
+        Self::connect_with(client_id, url.parse()?).await
+    }
+
+    pub async fn connect_with(client_id: &str, options: ConnectOptions) -> Result<Arc<Self>> {
+        let connector = options.connector()?;
+
         // Create a client endpoint
-        let endpoint = mqtt_ep::endpoint::Endpoint::<role::Client>::new(mqtt_ep::Version::V5_0);
+        let endpoint =
+            Arc::new(mqtt_ep::endpoint::Endpoint::<role::Client>::new(mqtt_ep::Version::V5_0));
 
-        // Connect to TCP transport
-        let tcp_stream = connect_helper::connect_tcp(addr, None).await?;
-        let transport = TcpTransport::from_stream(tcp_stream);
-        endpoint
+        let conn = Arc::new(TanukiConnection {
+            endpoint,
+            client_id: client_id.to_owned(),
+            connector,
+            next_payload_id: AtomicU16::new(1),
+            state: watch::Sender::new(ConnectionState::Connecting),
+            events: broadcast::Sender::new(dispatch::EVENT_CAPACITY),
+            subscribed: broadcast::Sender::new(dispatch::EVENT_CAPACITY),
+            shared_receiver: Mutex::new(None),
+            subscriptions: Mutex::new(Subscriptions::new()),
+            retained_meta: Mutex::new(BTreeMap::new()),
+            authored: Mutex::new(BTreeSet::new()),
+            pending_acks: Mutex::new(BTreeMap::new()),
+            responses_subscribed: OnceCell::new(),
+            pending_responses: Mutex::new(PendingResponses::new()),
+        });
+
+        conn.establish().await?;
+        conn.state.send_replace(ConnectionState::Connected);
+
+        tokio::spawn(Self::supervise(Arc::downgrade(&conn), conn.endpoint.clone()));
+
+        Ok(conn)
+    }
+
+    /// Attaches a new transport to the endpoint and performs the CONNECT/CONNACK handshake
+    async fn establish(&self) -> Result<()> {
+        const CONNACK_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(10);
+
+        let transport = self.connector.open().await?;
+        self.endpoint
             .attach(transport, mqtt_ep::endpoint::Mode::Client)
             .await?;
 
-        // Send CONNECT packet
-        let connect = v5_0::Connect::builder()
-            .client_id(client_id)
-            .unwrap()
-            .build()
-            .unwrap();
+        let status_topic = self.client_status_topic().to_string();
 
-        endpoint.send(connect).await?;
+        // Send CONNECT packet, with a will that marks our entities as lost
+        let connect = self.connector.options.connect_packet(
+            &self.client_id,
+            &status_topic,
+            serde_json::to_vec(&meta::EntityStatus::Lost)?,
+            PublishOpts::metadata(),
+        )?;
+
+        self.endpoint.send(connect).await?;
 
-        // Receive CONNACK
-        let packet = endpoint.recv().await?;
+        // Receive CONNACK, a broker that accepted the transport but never answers shouldn't hang us
+        let packet = tokio::time::timeout(CONNACK_TIMEOUT, self.endpoint.recv())
+            .await
+            .map_err(|_| Error::Timeout)??;
         let connack: Connack = packet.try_into().map_err(Error::MqttPacketField)?;
         tracing::debug!("Received CONNACK: {connack:?}");
 
-        Ok(TanukiConnection {
-            endpoint,
-            next_payload_id: AtomicU16::new(1),
-            sub_handlers: Mutex::new(BTreeMap::new()),
+        if connack.reason_code() != ConnectReasonCode::Success {
+            return Err(Error::ConnectionRefused(connack.reason_code()));
         }
-        .into())
-    }
 
-    fn next_payload_id(&self) -> u16 {
-        loop {
-            let id = self
-                .next_payload_id
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // the supervisor isn't reading acknowledgements yet, so don't wait for this one
+        self.send_publish(
+            &status_topic,
+            &serde_json::to_string(&meta::EntityStatus::Online)?,
+            PublishOpts::metadata(),
+            Vec::new(),
+        )
+        .await?;
 
-            if id != 0 {
-                break id;
-            }
-        }
+        Ok(())
     }
 
-    fn next_subscription_id(&self) -> SubscriptionIdentifier {
-        // max value is 2^28 - 1 (min value is also 1)
-        SubscriptionIdentifier::new(self.next_payload_id() as u32).unwrap()
+    /// Topic holding the status of this client, which applies to all entities it authors
+    pub fn client_status_topic(&self) -> Topic {
+        Topic::ClientMeta {
+            client: self.client_id.to_tanuki_string(),
+            key: TanukiString::const_new(meta::EntityStatus::KEY),
+        }
     }
 
-    pub async fn recv_raw(&self) -> Result<mqtt_ep::packet::Packet> {
-        let packet = self.endpoint.recv().await?;
-        Ok(packet)
+    pub fn client_id(&self) -> &str {
+        &self.client_id
     }
 
-    pub async fn recv(&self) -> Result<PublishEvent> {
-        loop {
-            let packet = self.recv_raw().await?;
+    /// Current state of the connection to the broker
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
 
-            let publish: Result<Publish, _> = packet.try_into();
-            if let Ok(publish) = publish {
-                let sub_id = publish.props.iter().find_map(|p| {
-                    if let Property::SubscriptionIdentifier(id) = p {
-                        Some(id.clone())
-                    } else {
-                        None
-                    }
-                });
+    /// Stream of connection state changes, starting with the current state
+    pub fn connection_states(&self) -> impl Stream<Item = ConnectionState> + Send + 'static {
+        let mut rx = self.state.subscribe();
+        rx.mark_changed();
 
-                let topic = Topic::from_str(publish.topic_name()).map_err(Error::BadTopic)?;
+        futures::stream::unfold(rx, async |mut rx| {
+            rx.changed().await.ok()?;
+            let state = *rx.borrow_and_update();
+            Some((state, rx))
+        })
+    }
 
-                let payload: serde_json::Value =
-                    serde_json::from_slice(publish.payload().as_slice())?;
+    async fn wait_connected(&self) -> Result<()> {
+        let mut rx = self.state.subscribe();
+        let state = rx
+            .wait_for(|s| matches!(s, ConnectionState::Connected | ConnectionState::Closed))
+            .await
+            .map_err(|_| Error::Closed)?;
 
-                break Ok(PublishEvent { sub_id, topic, payload });
-            }
+        match *state {
+            ConnectionState::Closed => Err(Error::Closed),
+            _ => Ok(()),
         }
     }
 
-    pub async fn handle(&self) -> Result<Infallible> {
+    fn next_payload_id(&self) -> u16 {
         loop {
-            let event = self.recv().await?;
+            let id = self
+                .next_payload_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+            if id != 0 {
+                break id;
+            }
+        }
+    }
 
-            tracing::debug!("Handling publish event: {event:#?}");
+    pub async fn raw_subscribe(&self, topic: &str) -> Result<SubscriptionIdentifier> {
+        let sub_id = self.subscriptions.lock().await.acquire(topic);
+        self.subscribe_as(sub_id.clone(), topic).await?;
 
-            if let Some(sub_id) = event.sub_id.clone() {
-                let mut handlers = self.sub_handlers.lock().await;
+        Ok(sub_id)
+    }
 
-                if let Some(handler) = handlers.get_mut(&sub_id.val()) {
-                    let retain = handler(event);
+    /// Send a SUBSCRIBE for a subscription that was already acquired, releasing it if that fails
+    async fn subscribe_as(&self, sub_id: SubscriptionIdentifier, topic: &str) -> Result<()> {
+        tracing::info!("Subscribing to topic '{topic}'");
 
-                    if !retain {
-                        tracing::warn!("Removing subscription handler for ID {}", sub_id.val());
-                        handlers.remove(&sub_id.val());
-                    }
-                }
+        let res = match self.send_subscribe(sub_id.clone(), topic).await {
+            Ok(ack) => ack.subscribe().await,
+            Err(e) => Err(e),
+        };
+
+        match res {
+            // the subscription will be replayed once we're connected again
+            Err(e) if e.is_disconnect() => Ok(()),
+            Err(e) => {
+                // the broker didn't take it, so there's nothing to unsubscribe from
+                self.subscriptions.lock().await.release(sub_id.val());
+                Err(e)
             }
+            Ok(()) => Ok(()),
         }
     }
 
-    pub async fn raw_subscribe(&self, topic: &str) -> Result<SubscriptionIdentifier> {
-        let sub_id = self.next_subscription_id();
+    async fn send_subscribe(
+        &self,
+        sub_id: SubscriptionIdentifier,
+        topic: &str,
+    ) -> Result<PendingAck> {
+        // fails only if nobody is listening, and filters we can't parse don't cover tanuki topics
+        if let Ok(filter) = topic.parse() {
+            let _ = self.subscribed.send(filter);
+        }
 
         let subscribe = v5_0::Subscribe::builder()
             .packet_id(self.next_payload_id())
-            .props(vec![Property::SubscriptionIdentifier(sub_id.clone())])
+            .props(vec![Property::SubscriptionIdentifier(sub_id)])
             .entries(vec![SubEntry::new(
                 topic.to_string(),
-                SubOpts::new().set_qos(Qos::AtLeastOnce),
+                // keep the retain flag on live messages too, so state and events can be told apart
+                SubOpts::new().set_qos(Qos::AtLeastOnce).set_rap(true),
             )?])
             .build()?;
 
-        tracing::info!("Subscribing to topic '{topic}'");
-
         self.endpoint
             .register_packet_id(subscribe.packet_id())
             .await?;
 
+        let ack = self.expect_ack(subscribe.packet_id()).await;
         self.endpoint.send(subscribe).await?;
 
-        Ok(sub_id)
+        Ok(ack)
     }
 
-    pub async fn subscribe(&self, topic: Topic) -> Result<SubscriptionIdentifier> {
-        self.raw_subscribe(&topic.to_string()).await
+    pub(crate) async fn send_unsubscribe(
+        &self,
+        topics: impl IntoIterator<Item = String>,
+    ) -> Result<PendingAck> {
+        let unsubscribe = v5_0::Unsubscribe::builder()
+            .packet_id(self.next_payload_id())
+            .entries(topics)?
+            .build()?;
+
+        self.endpoint
+            .register_packet_id(unsubscribe.packet_id())
+            .await?;
+
+        let ack = self.expect_ack(unsubscribe.packet_id()).await;
+        self.endpoint.send(unsubscribe).await?;
+
+        Ok(ack)
     }
 
-    pub async fn subscribe_with_handler(
+    pub async fn subscribe(
         &self,
-        topic: Topic,
+        filter: impl Into<TopicFilter>,
+    ) -> Result<SubscriptionIdentifier> {
+        self.raw_subscribe(&filter.into().to_string()).await
+    }
+
+    /// Subscribe to `filter`, calling `handler` for every matching message until it returns
+    /// `false` or the returned [`Subscription`] is dropped
+    ///
+    /// Handlers are called from the task reading from the broker, while the other handlers are
+    /// locked. Until a handler returns, no other messages or acknowledgements are processed, so it
+    /// must not block or do anything slow: hand the event off to a channel or spawn a task
+    /// instead.
+    pub async fn subscribe_with_handler(
+        self: &Arc<Self>,
+        filter: impl Into<TopicFilter>,
         handler: SubscriptionHandler,
-    ) -> Result<()> {
-        let sub_id = self.subscribe(topic).await?;
+    ) -> Result<Subscription> {
+        let filter = filter.into();
+        let topic = filter.to_string();
+
+        // register the handler first, so we don't miss retained messages
+        let (sub_id, handler_id) = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            let sub_id = subscriptions.acquire(&topic);
+            let handler_id = subscriptions.add_handler(sub_id.val(), filter, handler);
+            (sub_id, handler_id)
+        };
+
+        // subscribing again to a filter that's in use makes the broker resend its retained
+        // messages, which the new handler needs
+        if let Err(e) = self.subscribe_as(sub_id.clone(), &topic).await {
+            self.subscriptions.lock().await.remove_handler(handler_id);
+            return Err(e);
+        }
 
-        let mut handlers = self.sub_handlers.lock().await;
-        handlers.insert(sub_id.val(), handler);
+        Ok(Subscription::new(self.clone(), sub_id.val(), handler_id))
+    }
 
-        Ok(())
+    /// Remove a subscription handler, unsubscribing from the broker if nothing else uses the same
+    /// topic filter
+    pub(crate) async fn unsubscribe(&self, handler_id: u64) -> Result<()> {
+        let Some(sub_id) = self.subscriptions.lock().await.remove_handler(handler_id) else {
+            return Ok(());
+        };
+
+        self.release_subscription(sub_id).await
+    }
+
+    /// Stop using subscription `sub_id`, unsubscribing from the broker if nothing else uses it
+    pub(crate) async fn release_subscription(&self, sub_id: u32) -> Result<()> {
+        let Some(topic) = self.subscriptions.lock().await.release(sub_id) else {
+            return Ok(());
+        };
+
+        tracing::info!("Unsubscribing from topic '{topic}'");
+
+        let res = match self.send_unsubscribe([topic]).await {
+            Ok(ack) => ack.unsubscribe().await,
+            Err(e) => Err(e),
+        };
+
+        match res {
+            // the broker forgets about it anyway, and we won't replay it
+            Err(e) if e.is_disconnect() => Ok(()),
+            res => res,
+        }
     }
 
     pub async fn publish(
@@ -213,27 +449,82 @@ impl TanukiConnection {
         payload: impl Serialize,
         opts: PublishOpts,
     ) -> Result<()> {
+        let is_meta = matches!(topic, Topic::EntityMeta { .. } | Topic::CapabilityMeta { .. });
+        let topic = topic.to_string();
         let payload = serde_json::to_string(&payload)?;
 
         tracing::debug!("Publishing to topic {topic}: {payload}");
 
-        let publish = v5_0::Publish::builder()
-            .topic_name(topic.to_string())?
+        if opts.retain && is_meta {
+            self.retained_meta
+                .lock()
+                .await
+                .insert(topic.clone(), payload.clone());
+        }
+
+        self.publish_with_props(&topic, &payload, opts, Vec::new())
+            .await
+    }
+
+    /// Publish an already serialized payload with MQTT properties, holding it until we're
+    /// connected
+    pub(crate) async fn publish_with_props(
+        &self,
+        topic: &str,
+        payload: &str,
+        opts: PublishOpts,
+        props: Properties,
+    ) -> Result<()> {
+        loop {
+            let res = match self.send_publish(topic, payload, opts, props.clone()).await {
+                Ok(ack) => ack.publish().await,
+                Err(e) => Err(e),
+            };
+
+            match res {
+                Err(e) if e.is_disconnect() => {
+                    tracing::debug!("Not connected, holding publish to {topic} until reconnected");
+                    self.wait_connected().await?;
+                }
+                res => break res,
+            }
+        }
+    }
+
+    /// Sends a PUBLISH packet, returning the acknowledgement to wait for
+    async fn send_publish(
+        &self,
+        topic: &str,
+        payload: &str,
+        opts: PublishOpts,
+        props: Properties,
+    ) -> Result<PendingAck> {
+        let mut publish = v5_0::Publish::builder()
+            .topic_name(topic)?
             .payload(payload)
             .qos(opts.qos)
             .retain(opts.retain)
-            .packet_id(self.next_payload_id())
-            .build()?;
+            .props(props);
+
+        if opts.qos != Qos::AtMostOnce {
+            publish = publish.packet_id(self.next_payload_id());
+        }
+
+        let publish = publish.build()?;
 
         tracing::debug!("Publishing MQTT message: {publish:#?}");
 
-        self.endpoint
-            .register_packet_id(publish.packet_id().unwrap())
-            .await?;
+        let ack = match publish.packet_id() {
+            Some(packet_id) => {
+                self.endpoint.register_packet_id(packet_id).await?;
+                self.expect_ack(packet_id).await
+            }
+            None => PendingAck::none(),
+        };
 
         self.endpoint.send(publish).await?;
 
-        Ok(())
+        Ok(ack)
     }
 
     pub async fn publish_entity_meta<T: MetaField>(&self, entity: EntityId, meta: T) -> Result<()> {
@@ -260,15 +551,25 @@ impl TanukiConnection {
         TanukiEntity::new(id.into(), self.clone()).capability::<C>()
     }
 
-    pub async fn author_entity(
-        self: &Arc<Self>,
-        id: impl Into<EntityId>,
-    ) -> Result<Arc<TanukiEntity<Authority>>> {
-        let entity = TanukiEntity::new(id.into(), self.clone());
+    /// Start authoring an entity
+    ///
+    /// The entity is announced with [`EntityStatus::Init`](meta::EntityStatus::Init). Publish its
+    /// metadata, capabilities and initial data through the returned [`EntityInit`], then call
+    /// [`EntityInit::ready`] to mark it online.
+    ///
+    /// Fails with [`Error::InvalidId`] if `id` isn't a valid entity ID, see
+    /// [`validate_id`](tanuki_common::validate_id).
+    pub async fn author_entity(self: &Arc<Self>, id: impl Into<EntityId>) -> Result<EntityInit> {
+        let id = id.into();
+        if let Err(reason) = tanuki_common::validate_id(&id) {
+            return Err(Error::InvalidId { id: id.to_string(), reason });
+        }
+
+        let entity = TanukiEntity::new(id, self.clone());
 
         entity.initialize().await?;
 
-        Ok(entity)
+        Ok(EntityInit { entity, ready: false })
     }
 
     pub fn listener<'a>(self: &Arc<Self>) -> Listener<'a> {
@@ -289,11 +590,30 @@ impl TanukiConnection {
     }
 }
 
+impl Drop for TanukiConnection {
+    fn drop(&mut self) {
+        // wakes up the supervisor, so it notices we're gone and stops
+        let endpoint = self.endpoint.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = endpoint.close().await;
+            });
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PublishEvent {
     pub sub_id: Option<SubscriptionIdentifier>,
     pub topic: Topic,
+    /// `null` if the payload was empty, which clears a retained message
     pub payload: serde_json::Value,
+    /// Published as retained state, rather than as a one-off event or command
+    pub retain: bool,
+    /// Where the publisher wants a response, if it's a request
+    pub response_topic: Option<String>,
+    /// Identifies the request a response belongs to
+    pub correlation_data: Option<Vec<u8>>,
 }
 
 impl PublishEvent {
@@ -362,23 +682,65 @@ impl TanukiEntity<User> {
 
 impl TanukiEntity<Authority> {
     pub(crate) async fn initialize(&self) -> Result<()> {
+        self.conn.authored.lock().await.insert(self.id.clone());
+
         self.conn
-            .publish_entity_meta(self.id.clone(), meta::EntityStatus::Online) // TODO: Init first
+            .publish_entity_meta(self.id.clone(), meta::Client(self.conn.client_id.as_str().into()))
+            .await?;
+
+        self.conn
+            .publish_entity_meta(self.id.clone(), meta::EntityStatus::Init)
             .await?;
 
         Ok(())
     }
 
-    // pub async fn status_online(&self) -> Result<()> {
-    //     self.conn
-    //         .publish_entity_meta(&self.id, meta::Status(EntityStatus::Online))
-    //         .await
-    // }
-
     pub async fn publish_meta(&self, meta: impl MetaField) -> Result<()> {
         self.conn.publish_entity_meta(self.id.clone(), meta).await
     }
 
+    /// Publish the entity's [tags](meta::Tags), replacing any previous ones
+    ///
+    /// Fails with [`Error::InvalidId`] if any tag isn't a valid ID, see
+    /// [`validate_id`](tanuki_common::validate_id).
+    pub async fn publish_tags(
+        &self,
+        tags: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<()> {
+        let tags = meta::Tags::new(tags);
+        if let Err((tag, reason)) = tags.validate() {
+            return Err(Error::InvalidId { id: tag.to_string(), reason });
+        }
+
+        self.publish_meta(tags).await
+    }
+
+    /// Place the entity in `room`, tagging it with `room.{room}`
+    ///
+    /// Replaces any previous tags, use [`publish_tags`](Self::publish_tags) for more.
+    pub async fn publish_room(&self, room: &str) -> Result<()> {
+        self.publish_tags([format!("room.{room}")]).await
+    }
+
+    /// Mark the entity [`Disconnected`](meta::EntityStatus::Disconnected) in the background, for
+    /// when it stops being served before the connection shuts down
+    ///
+    /// Meant for `Drop` impls, does nothing outside a tokio runtime.
+    pub(crate) fn disconnect_in_background(self: &Arc<Self>) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let entity = self.clone();
+        runtime.spawn(async move {
+            entity.conn.authored.lock().await.remove(&entity.id);
+
+            if let Err(e) = entity.publish_meta(meta::EntityStatus::Disconnected).await {
+                tracing::warn!(entity = %entity.id, "Failed to mark entity disconnected: {e}");
+            }
+        });
+    }
+
     pub async fn author_capability<C: Capability<Authority>>(self: &Arc<Self>) -> Result<C> {
         let cap = C::from(TanukiCapability {
             entity: self.clone(),
@@ -390,3 +752,47 @@ impl TanukiEntity<Authority> {
         Ok(cap)
     }
 }
+
+/// An authored entity that has not been marked online yet
+///
+/// Derefs to the entity, so metadata and capabilities can be authored as usual. Consumers see the
+/// entity as [`Init`](meta::EntityStatus::Init) until [`ready`](Self::ready) is called.
+#[must_use = "the entity stays in the init state until `ready` is called"]
+pub struct EntityInit {
+    entity: Arc<TanukiEntity<Authority>>,
+    ready: bool,
+}
+
+impl EntityInit {
+    /// Mark the entity as [`Online`](meta::EntityStatus::Online)
+    pub async fn ready(mut self) -> Result<Arc<TanukiEntity<Authority>>> {
+        self.mark_ready().await?;
+        Ok(self.entity.clone())
+    }
+
+    pub(crate) async fn mark_ready(&mut self) -> Result<()> {
+        self.entity.publish_meta(meta::EntityStatus::Online).await?;
+
+        self.ready = true;
+        Ok(())
+    }
+}
+
+impl core::ops::Deref for EntityInit {
+    type Target = Arc<TanukiEntity<Authority>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entity
+    }
+}
+
+impl Drop for EntityInit {
+    fn drop(&mut self) {
+        if !self.ready {
+            tracing::warn!(
+                entity = %self.entity.id,
+                "Entity dropped before it was ready, it will stay in the init state"
+            );
+        }
+    }
+}
@@ -1,17 +1,39 @@
 use core::convert::Infallible;
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+};
 
-use crate::{PublishEvent, Result, TanukiConnection};
+use tanuki_common::EntityId;
+use tokio::task::JoinHandle;
 
+use crate::{Error, EventReceiver, PublishEvent, Result, TanukiConnection};
+
+type ErrorHook = Box<dyn Fn(Error) + Send + Sync>;
+
+/// Dispatches events to typed [`EventHandler`]s and [`AsyncEventHandler`]s
+///
+/// Sees every event received after it was created, independently of other listeners.
 pub struct Listener<'handler> {
-    conn: Arc<TanukiConnection>,
+    events: EventReceiver,
     #[expect(clippy::type_complexity)] // oh no a boxed function
     handlers: Vec<Box<dyn FnMut(&PublishEvent) + Send + 'handler>>,
+    error_hook: Arc<RwLock<ErrorHook>>,
 }
 
 impl<'handler> Listener<'handler> {
     pub(super) fn new(conn: Arc<TanukiConnection>) -> Self {
-        Self { conn, handlers: Vec::new() }
+        Self::with_events(conn.event_receiver())
+    }
+
+    fn with_events(events: EventReceiver) -> Self {
+        Self {
+            events,
+            handlers: Vec::new(),
+            error_hook: Arc::new(RwLock::new(Box::new(|e| {
+                tracing::error!("Event handler error: {e}");
+            }))),
+        }
     }
 
     pub fn handle<E: for<'event> TryFrom<&'event PublishEvent, Error = ()> + Send>(
@@ -26,6 +48,83 @@ impl<'handler> Listener<'handler> {
         self
     }
 
+    /// Handle events with an [`AsyncEventHandler`], running its futures on the runtime
+    ///
+    /// Errors are passed to the [error hook](Self::on_error).
+    pub fn handle_async<E>(
+        mut self,
+        handler: impl AsyncEventHandler<E> + Send + Sync + 'static,
+        concurrency: Concurrency,
+    ) -> Self
+    where
+        E: for<'event> TryFrom<&'event PublishEvent, Error = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let error_hook = self.error_hook.clone();
+
+        let run = move |event: E| {
+            let handler = handler.clone();
+            let error_hook = error_hook.clone();
+
+            async move {
+                if let Err(e) = handler.handle(event).await {
+                    (error_hook.read().unwrap_or_else(PoisonError::into_inner))(e);
+                }
+            }
+        };
+
+        match concurrency {
+            Concurrency::Parallel => {
+                self.handlers.push(Box::new(move |event| {
+                    if let Ok(event) = E::try_from(event) {
+                        tokio::spawn(run(event));
+                    }
+                }));
+            }
+            Concurrency::SerialPerEntity => {
+                // the latest task per entity, which the next event for that entity waits for
+                let mut latest = HashMap::<Option<EntityId>, JoinHandle<()>>::new();
+
+                self.handlers.push(Box::new(move |event| {
+                    let Ok(typed) = E::try_from(event) else {
+                        return;
+                    };
+
+                    // entities that are done aren't worth keeping around
+                    latest.retain(|_, task| !task.is_finished());
+
+                    let entity = event.topic.entity().cloned();
+                    let previous = latest.remove(&entity);
+                    let run = run(typed);
+
+                    let task = tokio::spawn(async move {
+                        if let Some(previous) = previous {
+                            // a panic in an earlier handler doesn't hold up the rest
+                            let _ = previous.await;
+                        }
+
+                        run.await;
+                    });
+
+                    latest.insert(entity, task);
+                }));
+            }
+        }
+
+        self
+    }
+
+    /// Replace what happens to errors from [`AsyncEventHandler`]s, which are logged by default
+    ///
+    /// Applies to all handlers of this listener, including ones added before.
+    pub fn on_error(self, hook: impl Fn(Error) + Send + Sync + 'static) -> Self {
+        *self
+            .error_hook
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Box::new(hook);
+        self
+    }
+
     fn dispatch(&mut self, event: &PublishEvent) {
         for handler in &mut self.handlers {
             handler(event);
@@ -34,15 +133,166 @@ impl<'handler> Listener<'handler> {
 
     pub async fn listen(mut self) -> Result<Infallible> {
         loop {
-            let ev = self.conn.recv().await?;
+            let ev = self.events.recv().await?;
             self.dispatch(&ev);
         }
     }
 }
 
+/// How an [`AsyncEventHandler`] handles events that arrive while it's still busy
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Concurrency {
+    /// Events for the same entity are handled one at a time, in the order they arrived; events
+    /// for different entities are handled in parallel
+    #[default]
+    SerialPerEntity,
+    /// Every event is handled right away
+    Parallel,
+}
+
 pub trait EventHandler<E: for<'event> TryFrom<&'event PublishEvent, Error = ()>> {
     fn handle(&mut self, event: E);
 }
 
+/// Like [`EventHandler`], for handlers that need to do I/O
+///
+/// Handlers can be called concurrently, see [`Concurrency`].
+pub trait AsyncEventHandler<E: for<'event> TryFrom<&'event PublishEvent, Error = ()>> {
+    fn handle(&self, event: E) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl<E, H> AsyncEventHandler<E> for Arc<H>
+where
+    E: for<'event> TryFrom<&'event PublishEvent, Error = ()>,
+    H: AsyncEventHandler<E> + Sync,
+{
+    fn handle(&self, event: E) -> impl Future<Output = Result<()>> + Send {
+        H::handle(self, event)
+    }
+}
+
 const fn assert_send<T: Send>() {}
 const _: () = assert_send::<Listener<'_>>();
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::sync::Mutex;
+
+    use serde_json::json;
+    use tokio::sync::{broadcast, watch};
+
+    use super::*;
+    use crate::{ConnectionState, capabilities::sensor::SensorEvent};
+
+    /// Logs when it starts and finishes handling a reading, failing on readings of `broken`
+    struct Recorder {
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl AsyncEventHandler<SensorEvent> for Recorder {
+        async fn handle(&self, event: SensorEvent) -> Result<()> {
+            let (entity, key) = event.as_str_tuple();
+            let name = format!("{entity}.{key}");
+
+            self.log.lock().unwrap().push(format!("start {name}"));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.log.lock().unwrap().push(format!("end {name}"));
+
+            match key {
+                "broken" => Err(Error::CommandFailed(name)),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn listener(concurrency: Concurrency) -> (Listener<'static>, Arc<Mutex<Vec<String>>>) {
+        let (_, rx) = broadcast::channel(8);
+        let (_, state) = watch::channel(ConnectionState::Connected);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let errors = log.clone();
+        let listener = Listener::with_events(EventReceiver { rx, state })
+            .handle_async(Recorder { log: log.clone() }, concurrency)
+            .on_error(move |e| errors.lock().unwrap().push(format!("error {e}")));
+
+        (listener, log)
+    }
+
+    fn reading(entity: &str, key: &str) -> PublishEvent {
+        PublishEvent {
+            sub_id: None,
+            topic: format!("tanuki/entities/{entity}/tanuki.sensor/{key}")
+                .parse()
+                .unwrap(),
+            payload: json!({ "value": 1.0, "unit": "", "timestamp": 0 }),
+            retain: false,
+            response_topic: None,
+            correlation_data: None,
+        }
+    }
+
+    /// Wait for `count` entries in the log
+    async fn settled(log: &Arc<Mutex<Vec<String>>>, count: usize) -> Vec<String> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while log.lock().unwrap().len() < count {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("handlers finish");
+
+        log.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn serial_per_entity() {
+        let (mut listener, log) = listener(Concurrency::SerialPerEntity);
+
+        listener.dispatch(&reading("hall", "first"));
+        listener.dispatch(&reading("hall", "second"));
+        listener.dispatch(&reading("desk", "only"));
+
+        let log = settled(&log, 6).await;
+        let at = |entry: &str| log.iter().position(|e| e == entry).unwrap();
+
+        // the same entity is handled in order, one at a time
+        assert!(at("end hall.first") < at("start hall.second"));
+        // while other entities don't have to wait
+        assert!(at("start desk.only") < at("end hall.first"));
+    }
+
+    #[tokio::test]
+    async fn parallel() {
+        let (mut listener, log) = listener(Concurrency::Parallel);
+
+        listener.dispatch(&reading("hall", "first"));
+        listener.dispatch(&reading("hall", "second"));
+
+        let log = settled(&log, 4).await;
+        let at = |entry: &str| log.iter().position(|e| e == entry).unwrap();
+
+        assert!(at("start hall.second") < at("end hall.first"));
+    }
+
+    #[tokio::test]
+    async fn errors_reach_the_hook() {
+        for concurrency in [Concurrency::SerialPerEntity, Concurrency::Parallel] {
+            let (mut listener, log) = listener(concurrency);
+
+            listener.dispatch(&reading("hall", "broken"));
+            // not a sensor reading, so it's skipped
+            listener.dispatch(&PublishEvent {
+                topic: "tanuki/entities/hall/tanuki.on_off/on".parse().unwrap(),
+                payload: json!(true),
+                ..reading("hall", "broken")
+            });
+
+            assert_eq!(settled(&log, 3).await, [
+                "start hall.broken",
+                "end hall.broken",
+                "error command failed: hall.broken"
+            ]);
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! Local copy of the retained state on the broker
+//!
+//! A [`StateMirror`] subscribes to everything under `tanuki/`, or just the topics of a
+//! [`TopicFilter`], and keeps the latest retained value of every topic, so automations can read
+//! current state synchronously instead of asking the broker each time.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
+};
+
+use serde_json::Value;
+use tanuki_common::{
+    CapabilityProperty, EntityId, Property, TanukiString, ToTanukiString as _, Topic, TopicFilter,
+    meta::MetaField,
+};
+use tokio::sync::broadcast;
+
+use crate::{Result, Subscription, TanukiConnection, dispatch::EVENT_CAPACITY};
+
+/// A retained value that changed in the mirror
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    pub topic: Topic,
+    /// Value before the change, `None` if the topic wasn't known yet
+    pub previous: Option<Value>,
+    /// `null` if the retained value was cleared
+    pub value: Value,
+}
+
+/// Latest retained value of every tanuki topic
+///
+/// Stays up to date for as long as it's alive, dropping it unsubscribes.
+pub struct StateMirror {
+    state: Arc<RwLock<MirrorState>>,
+    changes: broadcast::Sender<StateChange>,
+    _subscription: Subscription,
+}
+
+impl StateMirror {
+    /// Start mirroring the retained state on the broker
+    ///
+    /// Retained values arrive shortly after this returns, so the mirror may still be filling up
+    /// for a moment.
+    pub async fn new(conn: &Arc<TanukiConnection>) -> Result<Self> {
+        Self::with_filter(conn, TopicFilter::all()).await
+    }
+
+    /// Start mirroring only the retained state on topics matching `filter`, see
+    /// [`new`](Self::new)
+    pub async fn with_filter(
+        conn: &Arc<TanukiConnection>,
+        filter: impl Into<TopicFilter>,
+    ) -> Result<Self> {
+        let state = Arc::new(RwLock::new(MirrorState::default()));
+        let changes = broadcast::Sender::new(EVENT_CAPACITY);
+
+        let subscription = conn
+            .subscribe_with_handler(
+                filter,
+                Box::new({
+                    let state = state.clone();
+                    let changes = changes.clone();
+
+                    move |event| {
+                        // events and commands aren't state, and would be stale by the time
+                        // anyone reads them
+                        if !event.retain {
+                            return true;
+                        }
+
+                        let previous = state
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .insert(&event.topic, event.payload.clone());
+
+                        if previous.as_ref().unwrap_or(&Value::Null) != &event.payload {
+                            // fails only if nobody is listening
+                            let _ = changes.send(StateChange {
+                                topic: event.topic,
+                                previous,
+                                value: event.payload,
+                            });
+                        }
+
+                        true
+                    }
+                }),
+            )
+            .await?;
+
+        Ok(Self {
+            state,
+            changes,
+            _subscription: subscription,
+        })
+    }
+
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, MirrorState> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get the current value of a capability property of `entity`
+    pub fn get<T: CapabilityProperty>(&self, entity: &EntityId) -> Option<T> {
+        self.read().get_property(entity)
+    }
+
+    /// Get a metadata field of `entity`
+    pub fn get_meta<T: MetaField>(&self, entity: &EntityId) -> Option<T> {
+        self.get_typed(&Topic::EntityMeta {
+            entity: entity.clone(),
+            key: TanukiString::const_new(T::KEY),
+        })
+    }
+
+    /// Get a metadata field of one of the capabilities of `entity`
+    pub fn get_capability_meta<T: MetaField>(
+        &self,
+        entity: &EntityId,
+        capability: &str,
+    ) -> Option<T> {
+        self.get_typed(&Topic::CapabilityMeta {
+            entity: entity.clone(),
+            capability: capability.to_tanuki_string(),
+            key: TanukiString::const_new(T::KEY),
+        })
+    }
+
+    /// Get the current value of any topic, as it was published
+    pub fn get_raw(&self, topic: &Topic) -> Option<Value> {
+        self.read().get(topic).cloned()
+    }
+
+    fn get_typed<T: Property>(&self, topic: &Topic) -> Option<T> {
+        self.read().get_typed(topic)
+    }
+
+    /// All entities with any retained state
+    pub fn entities(&self) -> Vec<EntityId> {
+        self.read().entities.keys().cloned().collect()
+    }
+
+    /// IDs of the capabilities of `entity` with any retained state
+    pub fn capabilities(&self, entity: &EntityId) -> Vec<TanukiString> {
+        self.read()
+            .entities
+            .get(entity)
+            .map(|entity| entity.capabilities.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// All clients with any retained metadata
+    pub fn clients(&self) -> Vec<TanukiString> {
+        self.read().clients.keys().cloned().collect()
+    }
+
+    /// Receive every change to the mirror from now on
+    pub fn changes(&self) -> broadcast::Receiver<StateChange> {
+        self.changes.subscribe()
+    }
+}
+
+type Values = BTreeMap<TanukiString, Value>;
+
+#[derive(Default)]
+pub(crate) struct MirrorState {
+    entities: BTreeMap<EntityId, EntityState>,
+    clients: BTreeMap<TanukiString, ClientState>,
+}
+
+#[derive(Default)]
+struct EntityState {
+    meta: Values,
+    capabilities: BTreeMap<TanukiString, CapabilityState>,
+}
+
+#[derive(Default)]
+struct CapabilityState {
+    meta: Values,
+    data: Values,
+}
+
+#[derive(Default)]
+struct ClientState {
+    meta: Values,
+    data: Values,
+}
+
+impl MirrorState {
+    /// Store the value of `topic`, or remove it if `null`, returning the previous one
+    pub(crate) fn insert(&mut self, topic: &Topic, value: Value) -> Option<Value> {
+        if value.is_null() {
+            return self.remove(topic);
+        }
+
+        let (values, key) = match topic {
+            Topic::EntityMeta { entity, key } => {
+                (&mut self.entities.entry(entity.clone()).or_default().meta, key)
+            }
+            Topic::CapabilityMeta { entity, capability, key } => (
+                &mut self
+                    .entities
+                    .entry(entity.clone())
+                    .or_default()
+                    .capabilities
+                    .entry(capability.clone())
+                    .or_default()
+                    .meta,
+                key,
+            ),
+            Topic::CapabilityData { entity, capability, rest } => (
+                &mut self
+                    .entities
+                    .entry(entity.clone())
+                    .or_default()
+                    .capabilities
+                    .entry(capability.clone())
+                    .or_default()
+                    .data,
+                rest,
+            ),
+            Topic::ClientMeta { client, key } => {
+                (&mut self.clients.entry(client.clone()).or_default().meta, key)
+            }
+            Topic::ClientData { client, rest } => {
+                (&mut self.clients.entry(client.clone()).or_default().data, rest)
+            }
+        };
+
+        values.insert(key.clone(), value)
+    }
+
+    /// Remove the value of `topic`, returning it
+    fn remove(&mut self, topic: &Topic) -> Option<Value> {
+        let previous = match topic {
+            Topic::EntityMeta { entity, key } => self.entities.get_mut(entity)?.meta.remove(key),
+            Topic::CapabilityMeta { entity, capability, key } => self
+                .entities
+                .get_mut(entity)?
+                .capabilities
+                .get_mut(capability)?
+                .meta
+                .remove(key),
+            Topic::CapabilityData { entity, capability, rest } => self
+                .entities
+                .get_mut(entity)?
+                .capabilities
+                .get_mut(capability)?
+                .data
+                .remove(rest),
+            Topic::ClientMeta { client, key } => self.clients.get_mut(client)?.meta.remove(key),
+            Topic::ClientData { client, rest } => self.clients.get_mut(client)?.data.remove(rest),
+        };
+
+        self.prune(topic);
+        previous
+    }
+
+    /// Forget the entity, capability or client of `topic` once it has no values left
+    fn prune(&mut self, topic: &Topic) {
+        let (entity, capability) = match topic {
+            Topic::EntityMeta { entity, .. } => (entity, None),
+            Topic::CapabilityMeta { entity, capability, .. }
+            | Topic::CapabilityData { entity, capability, .. } => (entity, Some(capability)),
+            Topic::ClientMeta { client, .. } | Topic::ClientData { client, .. } => {
+                if self
+                    .clients
+                    .get(client)
+                    .is_some_and(|state| state.meta.is_empty() && state.data.is_empty())
+                {
+                    self.clients.remove(client);
+                }
+                return;
+            }
+        };
+
+        let Some(state) = self.entities.get_mut(entity) else {
+            return;
+        };
+
+        if let Some(capability) = capability
+            && let Some(cap) = state.capabilities.get(capability)
+            && cap.meta.is_empty()
+            && cap.data.is_empty()
+        {
+            state.capabilities.remove(capability);
+        }
+
+        if state.meta.is_empty() && state.capabilities.is_empty() {
+            self.entities.remove(entity);
+        }
+    }
+
+    fn get(&self, topic: &Topic) -> Option<&Value> {
+        match topic {
+            Topic::EntityMeta { entity, key } => self.entities.get(entity)?.meta.get(key),
+            Topic::CapabilityMeta { entity, capability, key } => self
+                .entities
+                .get(entity)?
+                .capabilities
+                .get(capability)?
+                .meta
+                .get(key),
+            Topic::CapabilityData { entity, capability, rest } => self
+                .entities
+                .get(entity)?
+                .capabilities
+                .get(capability)?
+                .data
+                .get(rest),
+            Topic::ClientMeta { client, key } => self.clients.get(client)?.meta.get(key),
+            Topic::ClientData { client, rest } => self.clients.get(client)?.data.get(rest),
+        }
+    }
+
+    fn get_typed<T: Property>(&self, topic: &Topic) -> Option<T> {
+        let value = self.get(topic)?.clone();
+
+        serde_json::from_value(value)
+            .inspect_err(|e| tracing::warn!(%topic, "Ignoring malformed retained value: {e}"))
+            .ok()
+    }
+
+    /// Current value of a capability property of `entity`, see [`StateMirror::get`]
+    pub(crate) fn get_property<T: CapabilityProperty>(&self, entity: &EntityId) -> Option<T> {
+        self.get_typed(&Topic::CapabilityData {
+            entity: entity.clone(),
+            capability: TanukiString::const_new(T::CAPABILITY),
+            rest: TanukiString::const_new(T::KEY),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn mirror_state() {
+        let mut state = MirrorState::default();
+
+        let on = "tanuki/entities/desk.lamp/tanuki.on_off/on"
+            .parse::<Topic>()
+            .unwrap();
+        let version = "tanuki/entities/desk.lamp/tanuki.on_off/$meta/version"
+            .parse::<Topic>()
+            .unwrap();
+        let status = "tanuki/clients/dark-tanuki/$meta/status"
+            .parse::<Topic>()
+            .unwrap();
+
+        assert_eq!(state.insert(&on, json!(false)), None);
+        assert_eq!(state.insert(&on, json!(true)), Some(json!(false)));
+        assert_eq!(state.insert(&version, json!(1)), None);
+        assert_eq!(state.insert(&status, json!("online")), None);
+        assert_eq!(state.insert(&status, Value::Null), Some(json!("online")));
+        assert_eq!(state.insert(&status, json!("online")), None);
+
+        assert_eq!(state.get(&on), Some(&json!(true)));
+        assert_eq!(state.get(&version), Some(&json!(1)));
+        assert_eq!(state.get(&status), Some(&json!("online")));
+
+        let lamp = &state.entities[&EntityId::from("desk.lamp")];
+        assert_eq!(lamp.capabilities.len(), 1);
+        assert!(lamp.meta.is_empty());
+
+        // clearing the last value forgets the entity
+        assert_eq!(state.insert(&version, Value::Null), Some(json!(1)));
+        assert_eq!(state.insert(&on, Value::Null), Some(json!(true)));
+        assert_eq!(state.insert(&on, Value::Null), None);
+        assert!(state.entities.is_empty());
+    }
+}
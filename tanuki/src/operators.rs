@@ -0,0 +1,553 @@
+//! Calming down noisy sensor data
+//!
+//! [`Operator`]s sit between readings and whatever uses them: they [`Debounce`], [`Throttle`],
+//! drop changes within a [`Deadband`], turn numbers into on/off with [`Hysteresis`] or smooth
+//! them with a [`MovingAverage`]. Apply them to a stream of readings with [`Operator::apply`],
+//! or to the readings an authority publishes with
+//! [`Sensor::publisher`](crate::capabilities::sensor::Sensor::publisher). Events from several
+//! sensors go through [`per_sensor`], so each sensor gets its own operator.
+//!
+//! Operators are given the time explicitly, so they behave the same in tests as in real life.
+
+use core::time::Duration;
+use std::collections::{HashMap, VecDeque};
+
+use futures::{Stream, StreamExt as _};
+use tanuki_common::{
+    EntityId, TanukiString,
+    capabilities::sensor::{SensorPayload, SensorValue},
+};
+use tokio::time::Instant;
+
+use crate::capabilities::sensor::SensorEvent;
+
+/// A reading with a [`SensorValue`] that operators can look at and replace
+pub trait Reading: Clone {
+    fn value(&self) -> SensorValue;
+    fn with_value(self, value: SensorValue) -> Self;
+
+    fn number(&self) -> Option<f32> {
+        match self.value() {
+            SensorValue::Number(n) => Some(n),
+            SensorValue::Boolean(_) => None,
+        }
+    }
+}
+
+impl Reading for SensorValue {
+    fn value(&self) -> SensorValue {
+        *self
+    }
+
+    fn with_value(self, value: SensorValue) -> Self {
+        value
+    }
+}
+
+impl Reading for SensorPayload {
+    fn value(&self) -> SensorValue {
+        self.value
+    }
+
+    fn with_value(self, value: SensorValue) -> Self {
+        Self { value, ..self }
+    }
+}
+
+impl Reading for SensorEvent {
+    fn value(&self) -> SensorValue {
+        self.payload.value
+    }
+
+    fn with_value(self, value: SensorValue) -> Self {
+        Self {
+            payload: self.payload.with_value(value),
+            ..self
+        }
+    }
+}
+
+/// Decides which values to pass on, and when
+///
+/// Operators keep state about the values they've seen, so use one per sensor, eg. through
+/// [`per_sensor`].
+pub trait Operator<T> {
+    /// Feed a value that arrived at `now`, returning what to pass on right away
+    fn input(&mut self, value: T, now: Instant) -> Option<T>;
+
+    /// Pass on a value that was held back, if it's due at `now`
+    fn poll(&mut self, now: Instant) -> Option<T> {
+        let _ = now;
+        None
+    }
+
+    /// When [`poll`](Self::poll) should be called next, if anything is held back
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Pass the values this operator lets through on to `next`
+    fn chain<B: Operator<T>>(self, next: B) -> Chain<Self, B>
+    where
+        Self: Sized,
+    {
+        Chain { first: self, next }
+    }
+
+    /// Apply the operator to a stream of values
+    ///
+    /// Values that are held back when the stream ends are passed on right away.
+    fn apply(
+        self,
+        values: impl Stream<Item = T> + Send + Unpin + 'static,
+    ) -> impl Stream<Item = T> + Send + Unpin + 'static
+    where
+        Self: Sized + Send + 'static,
+        T: Send + 'static,
+    {
+        Box::pin(futures::stream::unfold((self, Some(values)), async |(mut op, mut values)| {
+            loop {
+                let deadline = op.next_deadline();
+
+                let Some(input) = &mut values else {
+                    // flush whatever is left, a poll can just move a value along a chain
+                    while let Some(deadline) = op.next_deadline() {
+                        if let Some(value) = op.poll(deadline) {
+                            return Some((value, (op, values)));
+                        }
+
+                        // an operator that doesn't move on at its own deadline never will
+                        if op.next_deadline() == Some(deadline) {
+                            break;
+                        }
+                    }
+
+                    return None;
+                };
+
+                tokio::select! {
+                    value = input.next() => match value {
+                        Some(value) => {
+                            if let Some(value) = op.input(value, Instant::now()) {
+                                return Some((value, (op, values)));
+                            }
+                        }
+                        None => values = None,
+                    },
+                    _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                        if deadline.is_some() =>
+                    {
+                        if let Some(value) = op.poll(Instant::now()) {
+                            return Some((value, (op, values)));
+                        }
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Two operators one after the other, see [`Operator::chain`]
+#[derive(Debug, Clone)]
+pub struct Chain<A, B> {
+    first: A,
+    next: B,
+}
+
+impl<T, A: Operator<T>, B: Operator<T>> Operator<T> for Chain<A, B> {
+    fn input(&mut self, value: T, now: Instant) -> Option<T> {
+        let value = self.first.input(value, now)?;
+        self.next.input(value, now)
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<T> {
+        if let Some(value) = self.first.poll(now)
+            && let Some(value) = self.next.input(value, now)
+        {
+            return Some(value);
+        }
+
+        self.next.poll(now)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        match (self.first.next_deadline(), self.next.next_deadline()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Only passes on a value once no new ones arrived for a while
+#[derive(Debug, Clone)]
+pub struct Debounce<T> {
+    quiet: Duration,
+    pending: Option<(T, Instant)>,
+}
+
+impl<T> Debounce<T> {
+    /// Pass on values after `quiet` without newer ones
+    pub fn new(quiet: Duration) -> Self {
+        Self { quiet, pending: None }
+    }
+}
+
+impl<T> Operator<T> for Debounce<T> {
+    fn input(&mut self, value: T, now: Instant) -> Option<T> {
+        self.pending = Some((value, now + self.quiet));
+        None
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<T> {
+        let (_, deadline) = self.pending.as_ref()?;
+        if now < *deadline {
+            return None;
+        }
+
+        self.pending.take().map(|(value, _)| value)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|(_, deadline)| *deadline)
+    }
+}
+
+/// Passes on at most one value per interval
+///
+/// The first value passes right away. Values that arrive too soon after are held back, and the
+/// latest of them is passed on once the interval is over, so the last value is never lost.
+#[derive(Debug, Clone)]
+pub struct Throttle<T> {
+    interval: Duration,
+    last: Option<Instant>,
+    pending: Option<T>,
+}
+
+impl<T> Throttle<T> {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, last: None, pending: None }
+    }
+
+    fn next_allowed(&self) -> Option<Instant> {
+        self.last.map(|last| last + self.interval)
+    }
+}
+
+impl<T> Operator<T> for Throttle<T> {
+    fn input(&mut self, value: T, now: Instant) -> Option<T> {
+        if self.next_allowed().is_some_and(|allowed| now < allowed) {
+            self.pending = Some(value);
+            return None;
+        }
+
+        self.last = Some(now);
+        self.pending = None;
+        Some(value)
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<T> {
+        if self.next_allowed().is_some_and(|allowed| now < allowed) {
+            return None;
+        }
+
+        let value = self.pending.take()?;
+        self.last = Some(now);
+        Some(value)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().and(self.next_allowed())
+    }
+}
+
+/// Drops values that are too close to the last one passed on
+///
+/// Numbers need to differ by at least `band`, other values only need to differ. A band of `0`
+/// just drops repeated values.
+#[derive(Debug, Clone)]
+pub struct Deadband {
+    band: f32,
+    last: Option<SensorValue>,
+}
+
+impl Deadband {
+    pub fn new(band: f32) -> Self {
+        Self { band, last: None }
+    }
+}
+
+impl<T: Reading> Operator<T> for Deadband {
+    fn input(&mut self, reading: T, _now: Instant) -> Option<T> {
+        let value = reading.value();
+
+        let passes = match (self.last, value) {
+            (Some(SensorValue::Number(last)), SensorValue::Number(n)) => {
+                n != last && (n - last).abs() >= self.band
+            }
+            (last, value) => last != Some(value),
+        };
+
+        if passes {
+            self.last = Some(value);
+            Some(reading)
+        } else {
+            None
+        }
+    }
+}
+
+/// Turns a number into on or off, with separate thresholds for each to stop it flapping
+///
+/// Passes on a boolean reading whenever the state changes. Until the number has crossed either
+/// threshold the state is unknown, and nothing is passed on.
+#[derive(Debug, Clone)]
+pub struct Hysteresis {
+    off_below: f32,
+    on_above: f32,
+    state: Option<bool>,
+}
+
+impl Hysteresis {
+    /// On once the number reaches `on_above`, off again once it drops to `off_below`
+    ///
+    /// # Panics
+    ///
+    /// If `off_below` is greater than `on_above`.
+    pub fn new(off_below: f32, on_above: f32) -> Self {
+        assert!(off_below <= on_above, "hysteresis thresholds are the wrong way around");
+
+        Self { off_below, on_above, state: None }
+    }
+
+    pub fn state(&self) -> Option<bool> {
+        self.state
+    }
+}
+
+impl<T: Reading> Operator<T> for Hysteresis {
+    fn input(&mut self, reading: T, _now: Instant) -> Option<T> {
+        let n = reading.number()?;
+
+        let state = if n >= self.on_above {
+            true
+        } else if n <= self.off_below {
+            false
+        } else {
+            return None;
+        };
+
+        if self.state == Some(state) {
+            return None;
+        }
+
+        self.state = Some(state);
+        Some(reading.with_value(SensorValue::Boolean(state)))
+    }
+}
+
+/// Replaces numbers with the average of the last few
+///
+/// Other values are passed on as they are.
+#[derive(Debug, Clone)]
+pub struct MovingAverage {
+    window: usize,
+    values: VecDeque<f32>,
+}
+
+impl MovingAverage {
+    /// Average over the last `window` numbers
+    ///
+    /// # Panics
+    ///
+    /// If `window` is `0`.
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0, "moving average over no values");
+
+        Self {
+            window,
+            values: VecDeque::with_capacity(window),
+        }
+    }
+}
+
+impl<T: Reading> Operator<T> for MovingAverage {
+    fn input(&mut self, reading: T, _now: Instant) -> Option<T> {
+        let Some(n) = reading.number() else {
+            return Some(reading);
+        };
+
+        if self.values.len() == self.window {
+            self.values.pop_front();
+        }
+        self.values.push_back(n);
+
+        let average = self.values.iter().sum::<f32>() / self.values.len() as f32;
+        Some(reading.with_value(SensorValue::Number(average)))
+    }
+}
+
+/// Runs a separate operator for every sensor, see [`per_sensor`]
+pub struct PerSensor<O, F> {
+    make: F,
+    operators: HashMap<(EntityId, TanukiString), O>,
+}
+
+/// Apply an operator to the events of every sensor separately, made with `make` the first time a
+/// sensor is seen
+///
+/// Sensors are told apart by their entity and key.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use tanuki::{capabilities::sensor::SensorEvent, operators::{Debounce, Operator, per_sensor}};
+/// # fn example(events: impl futures::Stream<Item = SensorEvent> + Send + Unpin + 'static) {
+/// let debounced = per_sensor(|| Debounce::new(Duration::from_secs(1))).apply(events);
+/// # }
+/// ```
+pub fn per_sensor<O, F>(make: F) -> PerSensor<O, F>
+where
+    O: Operator<SensorEvent>,
+    F: FnMut() -> O,
+{
+    PerSensor { make, operators: HashMap::new() }
+}
+
+impl<O, F> Operator<SensorEvent> for PerSensor<O, F>
+where
+    O: Operator<SensorEvent>,
+    F: FnMut() -> O,
+{
+    fn input(&mut self, event: SensorEvent, now: Instant) -> Option<SensorEvent> {
+        self.operators
+            .entry((event.entity.clone(), event.key.clone()))
+            .or_insert_with(&mut self.make)
+            .input(event, now)
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<SensorEvent> {
+        self.operators.values_mut().find_map(|op| op.poll(now))
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.operators
+            .values()
+            .filter_map(|op| op.next_deadline())
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let n = SensorValue::Number;
+        let b = SensorValue::Boolean;
+
+        let mut debounce = Debounce::new(Duration::from_millis(100));
+        assert_eq!(debounce.input(n(1.), at(0)), None);
+        assert_eq!(debounce.input(n(2.), at(50)), None);
+        assert_eq!(debounce.next_deadline(), Some(at(150)));
+        assert_eq!(debounce.poll(at(100)), None);
+        assert_eq!(debounce.poll(at(150)), Some(n(2.)));
+        assert_eq!(debounce.next_deadline(), None);
+
+        let mut throttle = Throttle::new(Duration::from_millis(100));
+        assert_eq!(throttle.input(n(1.), at(0)), Some(n(1.)));
+        assert_eq!(throttle.input(n(2.), at(20)), None);
+        assert_eq!(throttle.input(n(3.), at(40)), None);
+        assert_eq!(throttle.poll(at(100)), Some(n(3.)));
+        assert_eq!(throttle.input(n(4.), at(150)), None);
+        assert_eq!(throttle.input(n(5.), at(250)), Some(n(5.)));
+        assert_eq!(throttle.next_deadline(), None);
+
+        let mut deadband = Deadband::new(0.5);
+        assert_eq!(deadband.input(n(20.), at(0)), Some(n(20.)));
+        assert_eq!(deadband.input(n(20.3), at(0)), None);
+        assert_eq!(deadband.input(n(20.6), at(0)), Some(n(20.6)));
+        assert_eq!(deadband.input(b(true), at(0)), Some(b(true)));
+        assert_eq!(deadband.input(b(true), at(0)), None);
+
+        let mut hysteresis = Hysteresis::new(60., 70.);
+        assert_eq!(hysteresis.input(n(65.), at(0)), None);
+        assert_eq!(hysteresis.input(n(72.), at(0)), Some(b(true)));
+        assert_eq!(hysteresis.input(n(65.), at(0)), None);
+        assert_eq!(hysteresis.input(n(75.), at(0)), None);
+        assert_eq!(hysteresis.input(n(60.), at(0)), Some(b(false)));
+
+        let mut smooth = Operator::<SensorValue>::chain(MovingAverage::new(2), Deadband::new(1.));
+        assert_eq!(smooth.input(n(10.), at(0)), Some(n(10.)));
+        assert_eq!(smooth.input(n(11.), at(0)), None);
+        assert_eq!(smooth.input(n(13.), at(0)), Some(n(12.)));
+    }
+
+    #[tokio::test]
+    async fn held_back_values_are_flushed() {
+        let n = SensorValue::Number;
+
+        // flushing the throttle only hands 3 on to the debounce, which needs polling in turn
+        let op = Operator::<SensorValue>::chain(
+            Throttle::new(Duration::from_secs(60)),
+            Debounce::new(Duration::from_secs(60)),
+        );
+        let values = futures::stream::iter([n(1.), n(2.), n(3.)]);
+
+        let flushed = op.apply(values).collect::<Vec<_>>().await;
+        assert_eq!(flushed, [n(3.)]);
+    }
+
+    #[test]
+    fn per_sensor_operators() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let event = |entity: &str, key: &str, value: f32| SensorEvent {
+            entity: entity.into(),
+            key: key.into(),
+            payload: SensorPayload {
+                value: value.into(),
+                unit: "".into(),
+                timestamp: chrono::Utc::now(),
+            },
+        };
+        let sensor = |event: Option<SensorEvent>| {
+            event.map(|event| format!("{}/{}={:?}", event.entity, event.key, event.payload.value))
+        };
+
+        let mut debounce = per_sensor(|| Debounce::new(Duration::from_millis(100)));
+        assert!(
+            debounce
+                .input(event("kitchen", "temperature", 20.), at(0))
+                .is_none()
+        );
+        assert!(
+            debounce
+                .input(event("kitchen", "humidity", 50.), at(50))
+                .is_none()
+        );
+        assert!(
+            debounce
+                .input(event("balcony", "temperature", 10.), at(80))
+                .is_none()
+        );
+        assert_eq!(debounce.next_deadline(), Some(at(100)));
+
+        // one sensor's readings don't hold back the others'
+        assert_eq!(
+            sensor(debounce.poll(at(100))).as_deref(),
+            Some("kitchen/temperature=Number(20.0)")
+        );
+        assert!(debounce.poll(at(100)).is_none());
+        assert_eq!(debounce.next_deadline(), Some(at(150)));
+
+        let mut polled = [debounce.poll(at(200)), debounce.poll(at(200))].map(sensor);
+        polled.sort();
+        assert_eq!(polled, [
+            Some("balcony/temperature=Number(10.0)".into()),
+            Some("kitchen/humidity=Number(50.0)".into()),
+        ]);
+        assert_eq!(debounce.next_deadline(), None);
+    }
+}
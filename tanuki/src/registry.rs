@@ -6,11 +6,17 @@ use std::{
 
 use tanuki_common::EntityId;
 
-use crate::{Authority, Result, TanukiConnection, TanukiEntity, capabilities::Capability};
+use crate::{
+    Authority, EntityInit, Result, TanukiConnection, TanukiEntity, capabilities::Capability,
+};
 
+/// Authors entities and their capabilities on first use
+///
+/// Entities stay [`Init`](tanuki_common::meta::EntityStatus::Init) until [`ready`](Self::ready)
+/// is called, which should happen once their initial data has been published.
 pub struct Registry {
     tanuki: Arc<TanukiConnection>,
-    entities: HashMap<EntityId, Arc<TanukiEntity<Authority>>>,
+    entities: HashMap<EntityId, RegisteredEntity>,
     caps: HashMap<(EntityId, TypeId), Box<dyn Any + Send + Sync + 'static>>,
 }
 
@@ -32,18 +38,21 @@ impl Registry {
         let out = match cap {
             Entry::Occupied(cap) => cap.into_mut(),
             Entry::Vacant(entry) => {
-                let entity = self.entities.entry(id.clone());
-                let entity = match entity {
-                    Entry::Occupied(entry) => entry.into_mut(),
-                    Entry::Vacant(entry) => {
+                let cap = match self.entities.entry(id.clone()) {
+                    Entry::Occupied(entity) => {
+                        entity.get().entity().author_capability::<T>().await?
+                    }
+                    Entry::Vacant(entity_entry) => {
                         let entity = self.tanuki.author_entity(id.clone()).await?;
                         entity_init(&entity).await?;
-                        entry.insert(entity)
+
+                        let cap = entity.author_capability::<T>().await?;
+                        entity_entry.insert(RegisteredEntity::Init(entity));
+                        cap
                     }
                 };
 
-                let sensor = entity.author_capability::<T>().await?;
-                entry.insert(Box::new(sensor))
+                entry.insert(Box::new(cap))
             }
         };
 
@@ -51,4 +60,33 @@ impl Registry {
             .downcast_mut::<T>()
             .expect("wrong type stored in registry"))
     }
+
+    /// Mark entity `id` as [`Online`](tanuki_common::meta::EntityStatus::Online), if it isn't
+    /// already
+    pub async fn ready(&mut self, id: &EntityId) -> Result<()> {
+        let Some(entity) = self.entities.get_mut(id) else {
+            return Ok(());
+        };
+
+        if let RegisteredEntity::Init(init) = entity {
+            init.mark_ready().await?;
+            *entity = RegisteredEntity::Ready(init.entity.clone());
+        }
+
+        Ok(())
+    }
+}
+
+enum RegisteredEntity {
+    Init(EntityInit),
+    Ready(Arc<TanukiEntity<Authority>>),
+}
+
+impl RegisteredEntity {
+    fn entity(&self) -> &Arc<TanukiEntity<Authority>> {
+        match self {
+            RegisteredEntity::Init(init) => init,
+            RegisteredEntity::Ready(entity) => entity,
+        }
+    }
 }
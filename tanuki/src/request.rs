@@ -0,0 +1,290 @@
+use core::{fmt::Display, marker::PhantomData, time::Duration};
+use std::{collections::BTreeMap, sync::Arc};
+
+use mqtt_protocol_core::mqtt::packet::{CorrelationData, Property, ResponseTopic};
+use serde::Serialize;
+use tanuki_common::{CommandResponse, TanukiString, ToTanukiString, Topic};
+use tokio::sync::{Mutex, oneshot};
+
+use crate::{Error, PublishEvent, PublishOpts, Result, TanukiConnection};
+
+impl TanukiConnection {
+    /// Topic other clients send responses to our requests to
+    fn response_topic(&self) -> Topic {
+        Topic::ClientData {
+            client: self.client_id.to_tanuki_string(),
+            rest: TanukiString::const_new("responses"),
+        }
+    }
+
+    /// Publish `payload` as an MQTT 5 request, and wait for the response to it
+    pub(crate) async fn request(
+        &self,
+        topic: Topic,
+        payload: impl Serialize,
+        timeout: Duration,
+    ) -> Result<PublishEvent> {
+        let response_topic = self.response_topic().to_string();
+
+        self.responses_subscribed
+            .get_or_try_init(|| async {
+                self.raw_subscribe(&response_topic).await?;
+                Ok::<_, Error>(())
+            })
+            .await?;
+
+        let (id, rx) = self.pending_responses.lock().await.register();
+
+        let topic = topic.to_string();
+        let payload = serde_json::to_string(&payload)?;
+        let props = vec![
+            Property::ResponseTopic(ResponseTopic::new(response_topic.as_str())?),
+            Property::CorrelationData(CorrelationData::new(id.to_be_bytes().to_vec())?),
+        ];
+
+        tracing::debug!("Sending request to topic {topic}: {payload}");
+
+        let exchange = async {
+            self.publish_with_props(&topic, &payload, PublishOpts::control(), props)
+                .await?;
+
+            rx.await.map_err(|_| Error::Closed)
+        };
+
+        exchange_within(&self.pending_responses, id, timeout, exchange).await
+    }
+
+    /// Hand a response to the request waiting for it, returning whether there was one
+    pub(crate) async fn resolve_response(&self, event: &PublishEvent) -> bool {
+        if event.topic != self.response_topic() {
+            return false;
+        }
+
+        self.pending_responses.lock().await.resolve(event)
+    }
+}
+
+/// Run a request's `exchange` for at most `timeout`, no longer waiting for its response after
+async fn exchange_within(
+    pending: &Mutex<PendingResponses>,
+    id: u32,
+    timeout: Duration,
+    exchange: impl Future<Output = Result<PublishEvent>>,
+) -> Result<PublishEvent> {
+    let res = tokio::time::timeout(timeout, exchange).await;
+    pending.lock().await.waiting.remove(&id);
+
+    res.map_err(|_| Error::Timeout)?
+}
+
+/// Requests waiting for a response, by correlation ID
+pub(crate) struct PendingResponses {
+    next_id: u32,
+    waiting: BTreeMap<u32, oneshot::Sender<PublishEvent>>,
+}
+
+impl PendingResponses {
+    pub fn new() -> Self {
+        Self { next_id: 1, waiting: BTreeMap::new() }
+    }
+
+    /// Start waiting for a response, returning the correlation ID to send along with the request
+    pub fn register(&mut self) -> (u32, oneshot::Receiver<PublishEvent>) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        // requests that timed out or were cancelled will never be answered
+        self.waiting.retain(|_, tx| !tx.is_closed());
+
+        let (tx, rx) = oneshot::channel();
+        self.waiting.insert(id, tx);
+        (id, rx)
+    }
+
+    /// Hand a response to the request its correlation data refers to, returning whether there
+    /// was one
+    pub fn resolve(&mut self, event: &PublishEvent) -> bool {
+        let Some(id) = event
+            .correlation_data
+            .as_deref()
+            .and_then(|data| data.try_into().ok())
+            .map(u32::from_be_bytes)
+        else {
+            return false;
+        };
+
+        match self.waiting.remove(&id) {
+            Some(tx) => {
+                // the request may have timed out in the meantime
+                let _ = tx.send(event.clone());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The outcome of a command, from the response to it
+pub(crate) fn command_outcome<S: tanuki_common::Property>(
+    response: PublishEvent,
+) -> Result<Option<S>> {
+    match serde_json::from_value(response.payload).map_err(|source| Error::BadPayload {
+        topic: response.topic.to_string(),
+        source,
+    })? {
+        CommandResponse::Success { state } => Ok(state),
+        CommandResponse::Failure { error } => Err(Error::CommandFailed(error)),
+    }
+}
+
+/// Reply to a command, reporting its outcome to the sender
+///
+/// Only senders using `command_and_wait` are listening for a reply; replying to other commands
+/// does nothing.
+pub struct CommandReply<S> {
+    conn: Arc<TanukiConnection>,
+    response_topic: Option<String>,
+    correlation_data: Option<Vec<u8>>,
+    _state: PhantomData<fn(S)>,
+}
+
+impl<S: tanuki_common::Property> CommandReply<S> {
+    pub(crate) fn new(conn: Arc<TanukiConnection>, command: &PublishEvent) -> Self {
+        Self {
+            conn,
+            response_topic: command.response_topic.clone(),
+            correlation_data: command.correlation_data.clone(),
+            _state: PhantomData,
+        }
+    }
+
+    /// Whether the sender is waiting for a reply
+    pub fn is_expected(&self) -> bool {
+        self.response_topic.is_some()
+    }
+
+    /// Report that the command was carried out, with the resulting state if known
+    pub async fn success(self, state: Option<S>) -> Result<()> {
+        self.send(CommandResponse::Success { state }).await
+    }
+
+    /// Report that the command could not be carried out
+    pub async fn failure(self, error: impl Display) -> Result<()> {
+        self.send(CommandResponse::Failure { error: error.to_string() })
+            .await
+    }
+
+    pub async fn send(self, response: CommandResponse<S>) -> Result<()> {
+        let Some(topic) = self.response_topic else {
+            return Ok(());
+        };
+
+        let (payload, props) = encode_reply(&response, self.correlation_data)?;
+
+        tracing::debug!("Replying to command on topic {topic}: {payload}");
+
+        self.conn
+            .publish_with_props(&topic, &payload, PublishOpts::control(), props)
+            .await
+    }
+}
+
+/// Payload and properties of a reply to a command, echoing its correlation data
+fn encode_reply<S: tanuki_common::Property>(
+    response: &CommandResponse<S>,
+    correlation_data: Option<Vec<u8>>,
+) -> Result<(String, Vec<Property>)> {
+    let payload = serde_json::to_string(response)?;
+    let props = match correlation_data {
+        Some(data) => vec![Property::CorrelationData(CorrelationData::new(data)?)],
+        None => Vec::new(),
+    };
+
+    Ok((payload, props))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tanuki_common::capabilities::on_off::On;
+
+    use super::*;
+
+    fn response(correlation_data: Option<Vec<u8>>, payload: serde_json::Value) -> PublishEvent {
+        PublishEvent {
+            sub_id: None,
+            topic: "tanuki/clients/app/responses".parse().unwrap(),
+            payload,
+            retain: false,
+            response_topic: None,
+            correlation_data,
+        }
+    }
+
+    #[test]
+    fn responses_match_by_correlation_data() {
+        let mut pending = PendingResponses::new();
+        let (first, mut first_rx) = pending.register();
+        let (second, mut second_rx) = pending.register();
+        assert_ne!(first, second);
+
+        // unknown, foreign and malformed correlation data is left alone
+        let ok = json!({ "success": { "state": null } });
+        assert!(!pending.resolve(&response(None, ok.clone())));
+        assert!(!pending.resolve(&response(Some(9000u32.to_be_bytes().to_vec()), ok.clone())));
+        assert!(!pending.resolve(&response(Some(b"other".to_vec()), ok.clone())));
+
+        let reply = response(Some(second.to_be_bytes().to_vec()), json!("second"));
+        assert!(pending.resolve(&reply));
+        assert_eq!(second_rx.try_recv().unwrap().payload, json!("second"));
+        assert!(first_rx.try_recv().is_err());
+
+        // only answered once
+        assert!(!pending.resolve(&reply));
+    }
+
+    #[tokio::test]
+    async fn timed_out_requests_are_forgotten() {
+        let pending = Mutex::new(PendingResponses::new());
+        let (id, rx) = pending.lock().await.register();
+
+        let exchange = async { rx.await.map_err(|_| Error::Closed) };
+        let res = exchange_within(&pending, id, Duration::from_millis(10), exchange).await;
+        assert!(matches!(res, Err(Error::Timeout)));
+
+        let late = response(Some(id.to_be_bytes().to_vec()), json!(null));
+        assert!(!pending.lock().await.resolve(&late));
+        assert!(pending.lock().await.waiting.is_empty());
+    }
+
+    #[test]
+    fn command_replies() -> Result<()> {
+        let mut pending = PendingResponses::new();
+        let (id, mut rx) = pending.register();
+        let correlation_data = Some(id.to_be_bytes().to_vec());
+
+        // a reply echoes the correlation data, so it reaches the request it answers
+        let reply = |outcome: CommandResponse<On>| -> Result<PublishEvent> {
+            let (payload, props) = encode_reply(&outcome, correlation_data.clone())?;
+            let [Property::CorrelationData(data)] = props.as_slice() else {
+                panic!("expected correlation data, got {props:?}");
+            };
+
+            Ok(response(Some(data.val().to_vec()), serde_json::from_str(&payload)?))
+        };
+
+        assert!(pending.resolve(&reply(CommandResponse::Success { state: Some(On(true)) })?));
+        assert_eq!(command_outcome::<On>(rx.try_recv().unwrap())?, Some(On(true)));
+
+        let failure = reply(CommandResponse::Failure { error: "unplugged".into() })?;
+        assert!(matches!(
+            command_outcome::<On>(failure),
+            Err(Error::CommandFailed(error)) if error == "unplugged"
+        ));
+
+        let garbage = response(correlation_data.clone(), json!("what"));
+        assert!(matches!(command_outcome::<On>(garbage), Err(Error::BadPayload { .. })));
+
+        Ok(())
+    }
+}
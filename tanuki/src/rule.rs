@@ -0,0 +1,623 @@
+//! Automations as values: a trigger, some conditions and an action
+//!
+//! Instead of matching on every event in an [`EventHandler`](crate::listener::EventHandler),
+//! each automation is a [`Rule`] that says what it reacts to, when it applies and what it does.
+//! Rules are added to a [`Rules`] engine, which can list them and turn them on and off while
+//! running.
+//!
+//! ```no_run
+//! # use tanuki::{TanukiConnection, capabilities::buttons::ButtonName::On};
+//! use tanuki::rule::{button, rule, sensor};
+//! # use tanuki::common::capabilities::buttons::ButtonAction::Pressed;
+//! # async fn example(conn: std::sync::Arc<TanukiConnection>) -> tanuki::Result<()> {
+//! let rules = conn.rules().await?;
+//!
+//! rules.add(
+//!     rule()
+//!         .name("balcony light")
+//!         .when(button("rodret_remote_1", On, Pressed))
+//!         .and_if(sensor("balcony_door", "open").is(false))
+//!         .then(|ctx| async move {
+//!             // turn on the light
+//!             Ok(())
+//!         }),
+//! )?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::{
+    Arc, PoisonError, RwLock,
+    atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use futures::future::BoxFuture;
+use serde::Deserialize as _;
+use tanuki_common::{
+    CapabilityProperty as _, EntityId, Property as _, TanukiString, Topic, TopicFilter,
+    capabilities::{
+        buttons::ButtonAction,
+        on_off::On,
+        sensor::{SensorPayload, SensorValue},
+    },
+};
+use tokio::task::JoinHandle;
+use tracing::Instrument as _;
+
+use crate::{
+    Error, PublishEvent, Result, TanukiConnection,
+    capabilities::{
+        Capability as _,
+        buttons::{ButtonEvent, ButtonName},
+        sensor::Sensor,
+    },
+    mirror::StateMirror,
+};
+
+/// Something that happens which a [`Rule`] reacts to
+pub trait Trigger: Send + Sync {
+    fn matches(&self, event: &PublishEvent) -> bool;
+}
+
+impl<F: Fn(&PublishEvent) -> bool + Send + Sync> Trigger for F {
+    fn matches(&self, event: &PublishEvent) -> bool {
+        self(event)
+    }
+}
+
+/// Something about the current state that must hold for a [`Rule`] to run
+pub trait Condition: Send + Sync {
+    fn check(&self, state: &StateMirror) -> bool;
+
+    /// Topics `check` reads, so a mirror just for this condition can follow only those
+    ///
+    /// Every topic by default.
+    fn topics(&self) -> TopicFilter {
+        TopicFilter::all()
+    }
+}
+
+impl<F: Fn(&StateMirror) -> bool + Send + Sync> Condition for F {
+    fn check(&self, state: &StateMirror) -> bool {
+        self(state)
+    }
+}
+
+/// What a [`Rule`]'s action gets to work with
+pub struct RuleContext {
+    pub conn: Arc<TanukiConnection>,
+    /// The event that triggered the rule
+    pub event: PublishEvent,
+    pub state: Arc<StateMirror>,
+}
+
+type Action = Box<dyn Fn(RuleContext) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Start a new [`Rule`]
+pub fn rule() -> Rule {
+    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+    Rule {
+        name: format!("rule-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+        triggers: Vec::new(),
+        conditions: Vec::new(),
+        action: None,
+        enabled: Arc::new(AtomicBool::new(true)),
+    }
+}
+
+/// An automation: runs its action when any of its triggers fire and all of its conditions hold
+#[must_use]
+pub struct Rule {
+    name: String,
+    triggers: Vec<Box<dyn Trigger>>,
+    conditions: Vec<Box<dyn Condition>>,
+    action: Option<Action>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl Rule {
+    /// Name to list the rule by and to show in logs, unique names are generated otherwise
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Run when `trigger` fires, in addition to any other triggers
+    pub fn when(mut self, trigger: impl Trigger + 'static) -> Self {
+        self.triggers.push(Box::new(trigger));
+        self
+    }
+
+    /// Only run while `condition` holds, on top of any other conditions
+    pub fn and_if(mut self, condition: impl Condition + 'static) -> Self {
+        self.conditions.push(Box::new(condition));
+        self
+    }
+
+    /// What to do when the rule runs, replacing any previous action
+    pub fn then<A, F>(mut self, action: A) -> Self
+    where
+        A: Fn(RuleContext) -> F + Send + Sync + 'static,
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.action = Some(Box::new(move |ctx| Box::pin(action(ctx))));
+        self
+    }
+
+    /// Whether the rule starts out enabled, which it does by default
+    pub fn enabled(self, enabled: bool) -> Self {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        self
+    }
+
+    pub fn handle(&self) -> RuleHandle {
+        RuleHandle {
+            name: self.name.clone(),
+            enabled: self.enabled.clone(),
+        }
+    }
+
+    fn is_triggered_by(&self, event: &PublishEvent) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+            && self.triggers.iter().any(|trigger| trigger.matches(event))
+    }
+
+    fn evaluate(
+        &self,
+        event: &PublishEvent,
+        conn: &Arc<TanukiConnection>,
+        state: &Arc<StateMirror>,
+    ) {
+        if !self.is_triggered_by(event) {
+            return;
+        }
+
+        let span = tracing::info_span!("rule", name = %self.name);
+        let _enter = span.enter();
+
+        if !self
+            .conditions
+            .iter()
+            .all(|condition| condition.check(state))
+        {
+            tracing::debug!(topic = %event.topic, "Triggered, but conditions don't hold");
+            return;
+        }
+
+        tracing::info!(topic = %event.topic, "Triggered");
+
+        let Some(action) = &self.action else {
+            return;
+        };
+
+        let action = action(RuleContext {
+            conn: conn.clone(),
+            event: event.clone(),
+            state: state.clone(),
+        });
+
+        tokio::spawn(
+            async move {
+                if let Err(e) = action.await {
+                    tracing::error!("Rule action failed: {e}");
+                }
+            }
+            .instrument(span.clone()),
+        );
+    }
+}
+
+/// Turns a [`Rule`] on and off, see [`Rules::list`]
+#[derive(Debug, Clone)]
+pub struct RuleHandle {
+    name: String,
+    enabled: Arc<AtomicBool>,
+}
+
+impl RuleHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        tracing::info!(rule = %self.name, enabled, "Toggling rule");
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn enable(&self) {
+        self.set_enabled(true);
+    }
+
+    pub fn disable(&self) {
+        self.set_enabled(false);
+    }
+}
+
+/// Runs [`Rule`]s against the events on a connection
+///
+/// Keeps a [`StateMirror`] for the conditions to read. Retained values the broker replays after
+/// subscribing, such as on startup and after reconnecting, are state that was already there, so
+/// they don't trigger rules. Stops when dropped.
+pub struct Rules {
+    conn: Arc<TanukiConnection>,
+    rules: Arc<RwLock<Vec<Arc<Rule>>>>,
+    task: JoinHandle<()>,
+}
+
+impl Rules {
+    pub async fn new(conn: &Arc<TanukiConnection>) -> Result<Self> {
+        // before the mirror subscribes, so its replay is skipped too
+        let mut events = conn.live_events();
+        let state = Arc::new(StateMirror::new(conn).await?);
+        let rules = Arc::new(RwLock::new(Vec::<Arc<Rule>>::new()));
+
+        let task = tokio::spawn({
+            let conn = conn.clone();
+            let rules = rules.clone();
+
+            async move {
+                while let Ok(event) = events.recv().await {
+                    let rules = rules.read().unwrap_or_else(PoisonError::into_inner).clone();
+
+                    for rule in rules {
+                        rule.evaluate(&event, &conn, &state);
+                    }
+                }
+            }
+        });
+
+        Ok(Self { conn: conn.clone(), rules, task })
+    }
+
+    pub fn conn(&self) -> &Arc<TanukiConnection> {
+        &self.conn
+    }
+
+    /// Start running `rule`
+    ///
+    /// Fails with [`Error::DuplicateRule`] if there already is a rule with the same name.
+    pub fn add(&self, rule: Rule) -> Result<RuleHandle> {
+        let mut rules = self.rules.write().unwrap_or_else(PoisonError::into_inner);
+        if rules.iter().any(|existing| existing.name == rule.name) {
+            return Err(Error::DuplicateRule(rule.name));
+        }
+
+        let handle = rule.handle();
+        rules.push(Arc::new(rule));
+
+        Ok(handle)
+    }
+
+    /// Stop running the rule called `name` and forget it, returns whether it was there
+    pub fn remove(&self, name: &str) -> bool {
+        let mut rules = self.rules.write().unwrap_or_else(PoisonError::into_inner);
+        let before = rules.len();
+        rules.retain(|rule| rule.name != name);
+        rules.len() != before
+    }
+
+    pub fn get(&self, name: &str) -> Option<RuleHandle> {
+        self.list().into_iter().find(|rule| rule.name == name)
+    }
+
+    /// All rules, in the order they were added
+    pub fn list(&self) -> Vec<RuleHandle> {
+        self.rules
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|rule| rule.handle())
+            .collect()
+    }
+}
+
+impl Drop for Rules {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl TanukiConnection {
+    /// Start a rule engine on this connection, see [`Rules`]
+    pub async fn rules(self: &Arc<Self>) -> Result<Rules> {
+        Rules::new(self).await
+    }
+}
+
+/// A button on `entity` being used, see [`ButtonEvent`]
+pub fn button(
+    entity: impl Into<EntityId>,
+    name: ButtonName,
+    action: ButtonAction,
+) -> ButtonTrigger {
+    ButtonTrigger { entity: entity.into(), name, action }
+}
+
+#[derive(Debug, Clone)]
+pub struct ButtonTrigger {
+    entity: EntityId,
+    name: ButtonName,
+    action: ButtonAction,
+}
+
+impl Trigger for ButtonTrigger {
+    fn matches(&self, event: &PublishEvent) -> bool {
+        ButtonEvent::try_from(event).is_ok_and(|event| {
+            event.entity == self.entity && event.name == self.name && event.action == self.action
+        })
+    }
+}
+
+/// Sensor `key` of `entity`
+///
+/// Triggers on every reading, or use [`becomes`](SensorRef::becomes) and the comparisons to get a
+/// trigger or [`Condition`] on its value.
+pub fn sensor(entity: impl Into<EntityId>, key: &str) -> SensorRef {
+    SensorRef {
+        entity: entity.into(),
+        key: key.into(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SensorRef {
+    entity: EntityId,
+    key: TanukiString,
+}
+
+impl SensorRef {
+    /// The reading is exactly `value`
+    pub fn is(self, value: impl Into<SensorValue>) -> SensorCondition {
+        self.test(SensorTest::Is(value.into()))
+    }
+
+    /// The reading is a number greater than `value`
+    pub fn above(self, value: f32) -> SensorCondition {
+        self.test(SensorTest::Above(value))
+    }
+
+    /// The reading is a number less than `value`
+    pub fn below(self, value: f32) -> SensorCondition {
+        self.test(SensorTest::Below(value))
+    }
+
+    /// Trigger on readings of exactly `value`
+    pub fn becomes(self, value: impl Into<SensorValue>) -> SensorCondition {
+        self.is(value)
+    }
+
+    fn test(self, test: SensorTest) -> SensorCondition {
+        SensorCondition { sensor: self, test }
+    }
+
+    fn reading(&self, event: &PublishEvent) -> Option<SensorPayload> {
+        match &event.topic {
+            Topic::CapabilityData { entity, capability, rest }
+                if *entity == self.entity && capability == <Sensor>::ID && *rest == self.key =>
+            {
+                SensorPayload::deserialize(&event.payload).ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn current(&self, state: &StateMirror) -> Option<SensorPayload> {
+        let value = state.get_raw(&Topic::CapabilityData {
+            entity: self.entity.clone(),
+            capability: TanukiString::const_new(<Sensor>::ID),
+            rest: self.key.clone(),
+        })?;
+
+        SensorPayload::deserialize(value).ok()
+    }
+}
+
+impl Trigger for SensorRef {
+    fn matches(&self, event: &PublishEvent) -> bool {
+        self.reading(event).is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SensorTest {
+    Is(SensorValue),
+    Above(f32),
+    Below(f32),
+}
+
+impl SensorTest {
+    fn accepts(self, value: SensorValue) -> bool {
+        match (self, value) {
+            (SensorTest::Is(expected), value) => value == expected,
+            (SensorTest::Above(limit), SensorValue::Number(n)) => n > limit,
+            (SensorTest::Below(limit), SensorValue::Number(n)) => n < limit,
+            (_, SensorValue::Boolean(_)) => false,
+        }
+    }
+}
+
+/// A test on the value of a sensor, see [`sensor`]
+///
+/// As a [`Condition`] it checks the current reading, which fails if there is none. As a
+/// [`Trigger`] it fires on new readings that pass.
+#[derive(Debug, Clone)]
+pub struct SensorCondition {
+    sensor: SensorRef,
+    test: SensorTest,
+}
+
+impl Condition for SensorCondition {
+    fn check(&self, state: &StateMirror) -> bool {
+        self.sensor
+            .current(state)
+            .is_some_and(|reading| self.test.accepts(reading.value))
+    }
+
+    fn topics(&self) -> TopicFilter {
+        Topic::CapabilityData {
+            entity: self.sensor.entity.clone(),
+            capability: TanukiString::const_new(<Sensor>::ID),
+            rest: self.sensor.key.clone(),
+        }
+        .into()
+    }
+}
+
+impl Trigger for SensorCondition {
+    fn matches(&self, event: &PublishEvent) -> bool {
+        self.sensor
+            .reading(event)
+            .is_some_and(|reading| self.test.accepts(reading.value))
+    }
+}
+
+/// The [`On`] state of `entity`
+///
+/// Triggers on every update, or use [`is`](OnOffRef::is) to get a trigger or [`Condition`] on it.
+pub fn on_off(entity: impl Into<EntityId>) -> OnOffRef {
+    OnOffRef { entity: entity.into() }
+}
+
+#[derive(Debug, Clone)]
+pub struct OnOffRef {
+    entity: EntityId,
+}
+
+impl OnOffRef {
+    pub fn is(self, on: bool) -> OnOffCondition {
+        OnOffCondition { entity: self.entity, on }
+    }
+
+    fn update(&self, event: &PublishEvent) -> Option<On> {
+        match &event.topic {
+            Topic::CapabilityData { entity, capability, rest }
+                if *entity == self.entity && capability == On::CAPABILITY && rest == On::KEY =>
+            {
+                On::deserialize(&event.payload).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Trigger for OnOffRef {
+    fn matches(&self, event: &PublishEvent) -> bool {
+        self.update(event).is_some()
+    }
+}
+
+/// The [`On`] state of an entity being on or off, see [`on_off`]
+///
+/// As a [`Condition`] it checks the current state, which fails if there is none. As a
+/// [`Trigger`] it fires on updates to that state.
+#[derive(Debug, Clone)]
+pub struct OnOffCondition {
+    entity: EntityId,
+    on: bool,
+}
+
+impl Condition for OnOffCondition {
+    fn check(&self, state: &StateMirror) -> bool {
+        state.get::<On>(&self.entity) == Some(On(self.on))
+    }
+
+    fn topics(&self) -> TopicFilter {
+        Topic::CapabilityData {
+            entity: self.entity.clone(),
+            capability: TanukiString::const_new(On::CAPABILITY),
+            rest: TanukiString::const_new(On::KEY),
+        }
+        .into()
+    }
+}
+
+impl Trigger for OnOffCondition {
+    fn matches(&self, event: &PublishEvent) -> bool {
+        OnOffRef { entity: self.entity.clone() }.update(event) == Some(On(self.on))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{EventReceiver, dispatch::LiveEvents};
+
+    #[test]
+    fn rule_triggers() {
+        let event = |topic: &str, payload| PublishEvent {
+            sub_id: None,
+            topic: topic.parse().unwrap(),
+            payload,
+            retain: false,
+            response_topic: None,
+            correlation_data: None,
+        };
+        let reading = |value| json!({ "value": value, "unit": "", "timestamp": 1712345678 });
+
+        let pressed = event("tanuki/entities/remote/tanuki.buttons/on", json!("pressed"));
+        let held = event("tanuki/entities/remote/tanuki.buttons/on", json!("long_pressed"));
+        let opened = event("tanuki/entities/door/tanuki.sensor/open", reading(json!(true)));
+        let warm = event("tanuki/entities/door/tanuki.sensor/temperature", reading(json!(25.0)));
+        let lamp_on = event("tanuki/entities/lamp/tanuki.on_off/on", json!(true));
+
+        let rule = rule()
+            .when(button("remote", ButtonName::On, ButtonAction::Pressed))
+            .when(sensor("door", "open").becomes(true))
+            .when(sensor("door", "temperature").above(30.));
+
+        assert!(rule.is_triggered_by(&pressed));
+        assert!(!rule.is_triggered_by(&held));
+        assert!(rule.is_triggered_by(&opened));
+        assert!(!rule.is_triggered_by(&warm));
+        assert!(sensor("door", "temperature").matches(&warm));
+        assert!(on_off("lamp").is(true).matches(&lamp_on));
+        assert!(!on_off("lamp").is(false).matches(&lamp_on));
+        assert!(!on_off("desk").matches(&lamp_on));
+
+        let handle = rule.handle();
+        assert!(handle.name().starts_with("rule-"));
+        handle.disable();
+        assert!(!rule.is_triggered_by(&pressed));
+        handle.enable();
+        assert!(rule.is_triggered_by(&pressed));
+    }
+
+    #[tokio::test]
+    async fn replays_dont_trigger() {
+        let (events, rx) = tokio::sync::broadcast::channel(8);
+        let (subscribed, subscribed_rx) = tokio::sync::broadcast::channel(8);
+        let (_state, state) = tokio::sync::watch::channel(crate::ConnectionState::Connected);
+        let mut live = LiveEvents::new(EventReceiver { rx, state }, subscribed_rx);
+
+        let open = |open: bool, retain| PublishEvent {
+            sub_id: None,
+            topic: "tanuki/entities/door/tanuki.sensor/open".parse().unwrap(),
+            payload: json!({ "value": open, "unit": "", "timestamp": 1712345678 }),
+            retain,
+            response_topic: None,
+            correlation_data: None,
+        };
+
+        let rule = rule().when(sensor("door", "open").becomes(true));
+
+        // the door was already open when the mirror subscribed, then it's closed and opened again
+        subscribed.send(TopicFilter::all()).unwrap();
+        events.send(open(true, true)).unwrap();
+        events.send(open(false, true)).unwrap();
+        events.send(open(true, true)).unwrap();
+
+        let mut triggered = Vec::new();
+        for _ in 0..2 {
+            let event = live.recv().await.unwrap();
+            triggered.push(rule.is_triggered_by(&event));
+        }
+        assert_eq!(triggered, [false, true]);
+    }
+}
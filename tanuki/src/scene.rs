@@ -0,0 +1,238 @@
+//! Capturing, applying and publishing [`Scene`]s
+//!
+//! Published scenes are entities with a [`SceneTrigger`] capability, so buttons and other clients
+//! can activate them like any other device.
+
+use std::sync::Arc;
+
+use futures::future::join_all;
+use tanuki_common::{
+    EntityId,
+    capabilities::{
+        light::{LightCommand, LightState},
+        on_off::{On, OnOffCommand},
+        scene::{Scene, SceneCommand, SceneTarget, TargetCommand},
+    },
+    meta,
+};
+
+use crate::{
+    Authority, Result, Subscription, TanukiConnection, TanukiEntity, User,
+    capabilities::{light::Light, on_off::OnOff, scene::SceneTrigger},
+    mirror::{MirrorState, StateMirror},
+};
+
+impl TanukiConnection {
+    /// Capture the current state of `entities` as a scene
+    ///
+    /// Lights are captured with their brightness and color, other entities that can be turned on
+    /// and off with just that. Entities with neither are left out.
+    pub async fn capture_scene(
+        self: &Arc<Self>,
+        entities: impl IntoIterator<Item = impl Into<EntityId>>,
+    ) -> Result<Scene> {
+        let targets = join_all(
+            entities
+                .into_iter()
+                .map(|entity| self.capture_target(entity.into())),
+        )
+        .await;
+
+        let mut scene = Scene::new();
+        for target in targets {
+            scene.targets.extend(target?);
+        }
+
+        Ok(scene)
+    }
+
+    async fn capture_target(self: &Arc<Self>, entity: EntityId) -> Result<Option<SceneTarget>> {
+        let light = self.entity_cap::<Light<User>>(entity.clone());
+        if let Some(state) = light.try_get().await? {
+            return Ok(scene_target(entity, Some(state), None));
+        }
+
+        let on_off = self.entity_cap::<OnOff<User>>(entity.clone());
+        Ok(scene_target(entity, None, on_off.try_get().await?))
+    }
+
+    /// Send every command of `scene` at once
+    ///
+    /// Every target gets its command even if sending some fails, the first error is returned.
+    pub async fn apply_scene(self: &Arc<Self>, scene: &Scene) -> Result<()> {
+        let results = join_all(scene.targets.iter().map(|target| async move {
+            let res = match &target.command {
+                TargetCommand::Light(cmd) => {
+                    self.entity_cap::<Light<User>>(target.entity.clone())
+                        .command(cmd.clone())
+                        .await
+                }
+                TargetCommand::OnOff(cmd) => {
+                    self.entity_cap::<OnOff<User>>(target.entity.clone())
+                        .command(*cmd)
+                        .await
+                }
+            };
+
+            res.inspect_err(
+                |e| tracing::warn!(entity = %target.entity, "Scene command failed: {e}"),
+            )
+        }))
+        .await;
+
+        results.into_iter().collect()
+    }
+
+    /// Publish `scene` as entity `id`, applying it whenever it's activated
+    ///
+    /// The entity handles commands for as long as the returned [`VirtualScene`] is alive.
+    pub async fn publish_scene(
+        self: &Arc<Self>,
+        id: impl Into<EntityId>,
+        scene: Scene,
+    ) -> Result<VirtualScene> {
+        let init = self.author_entity(id).await?;
+        init.publish_meta(meta::Type("Scene".into())).await?;
+
+        let trigger = init.author_capability::<SceneTrigger<Authority>>().await?;
+        trigger.publish(scene.clone()).await?;
+
+        // the handler is owned by the connection, so don't keep it alive from in there
+        let conn = Arc::downgrade(self);
+        let scene = Arc::new(scene);
+
+        let commands = trigger
+            .handle_commands(move |cmd, reply| match cmd {
+                SceneCommand::Activate => {
+                    let Some(conn) = conn.upgrade() else {
+                        return;
+                    };
+                    let scene = scene.clone();
+
+                    tokio::spawn(async move {
+                        let res = match conn.apply_scene(&scene).await {
+                            Ok(()) => reply.success(Some(Scene::clone(&scene))).await,
+                            Err(e) => reply.failure(e).await,
+                        };
+
+                        if let Err(e) = res {
+                            tracing::warn!("Failed to reply to scene activation: {e}");
+                        }
+                    });
+                }
+            })
+            .await?;
+
+        let entity = init.ready().await?;
+
+        Ok(VirtualScene { entity, _commands: commands })
+    }
+}
+
+impl StateMirror {
+    /// Capture the current state of `entities` as a scene, see
+    /// [`TanukiConnection::capture_scene`]
+    ///
+    /// Reads the mirror instead of asking the broker, so it doesn't wait.
+    pub fn capture_scene(&self, entities: impl IntoIterator<Item = impl Into<EntityId>>) -> Scene {
+        capture(&self.read(), entities)
+    }
+}
+
+fn capture(state: &MirrorState, entities: impl IntoIterator<Item = impl Into<EntityId>>) -> Scene {
+    let mut scene = Scene::new();
+    scene
+        .targets
+        .extend(entities.into_iter().filter_map(|entity| {
+            let entity = entity.into();
+            let light = state.get_property::<LightState>(&entity);
+            let on_off = state.get_property::<On>(&entity);
+            scene_target(entity, light, on_off)
+        }));
+
+    scene
+}
+
+/// Target restoring the light state of `entity`, or else its on/off state
+fn scene_target(
+    entity: EntityId,
+    light: Option<LightState>,
+    on_off: Option<On>,
+) -> Option<SceneTarget> {
+    let command = match (light, on_off) {
+        (Some(LightState { on, brightness, color }), _) => {
+            TargetCommand::Light(LightCommand { on, brightness, color })
+        }
+        (None, Some(On(on))) => TargetCommand::OnOff(match on {
+            true => OnOffCommand::On,
+            false => OnOffCommand::Off,
+        }),
+        (None, None) => {
+            tracing::warn!(%entity, "Entity has no state to capture, leaving it out of the scene");
+            return None;
+        }
+    };
+
+    Some(SceneTarget { entity, command })
+}
+
+/// A [`Scene`] published as an entity, see [`TanukiConnection::publish_scene`]
+///
+/// Stops handling activations when dropped, and marks the entity
+/// [`Disconnected`](meta::EntityStatus::Disconnected).
+pub struct VirtualScene {
+    entity: Arc<TanukiEntity<Authority>>,
+    _commands: Subscription,
+}
+
+impl VirtualScene {
+    pub fn entity(&self) -> &Arc<TanukiEntity<Authority>> {
+        &self.entity
+    }
+}
+
+impl Drop for VirtualScene {
+    fn drop(&mut self) {
+        self.entity.disconnect_in_background();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tanuki_common::Topic;
+
+    use super::*;
+
+    #[test]
+    fn capture_from_mirror() {
+        let mut state = MirrorState::default();
+        for (topic, value) in [
+            (
+                "tanuki/entities/desk.lamp/tanuki.light/state",
+                json!({ "on": true, "brightness": 0.5 }),
+            ),
+            ("tanuki/entities/desk.lamp/tanuki.on_off/on", json!(true)),
+            ("tanuki/entities/fan/tanuki.on_off/on", json!(false)),
+            ("tanuki/entities/thermometer/tanuki.sensor/temperature", json!(21.5)),
+        ] {
+            state.insert(&topic.parse::<Topic>().unwrap(), value);
+        }
+
+        let scene = capture(&state, ["desk.lamp", "fan", "thermometer", "unknown"]);
+        assert_eq!(scene.targets, [
+            SceneTarget {
+                entity: "desk.lamp".into(),
+                command: TargetCommand::Light(LightCommand {
+                    on: true,
+                    brightness: Some(0.5),
+                    color: None,
+                }),
+            },
+            SceneTarget {
+                entity: "fan".into(),
+                command: TargetCommand::OnOff(OnOffCommand::Off),
+            },
+        ]);
+    }
+}
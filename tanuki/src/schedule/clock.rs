@@ -0,0 +1,70 @@
+use core::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio::sync::watch;
+
+/// Source of the current time for a [`Scheduler`](super::Scheduler)
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Wait until the clock reads `deadline` or later
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> impl Future<Output = ()> + Send;
+}
+
+/// The system's wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+/// Longest single sleep, so jumps in the wall clock are noticed within this time
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    async fn sleep_until(&self, deadline: DateTime<Utc>) {
+        loop {
+            let Ok(left) = (deadline - Utc::now()).to_std() else {
+                return;
+            };
+
+            if left.is_zero() {
+                return;
+            }
+
+            tokio::time::sleep(left.min(MAX_SLEEP)).await;
+        }
+    }
+}
+
+/// A clock that only moves when told to, for testing schedules
+#[derive(Debug)]
+pub struct ManualClock {
+    now: watch::Sender<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self { now: watch::Sender::new(now) }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        self.now.send_replace(now);
+    }
+
+    pub fn advance(&self, by: TimeDelta) {
+        self.now.send_modify(|now| *now += by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.borrow()
+    }
+
+    async fn sleep_until(&self, deadline: DateTime<Utc>) {
+        // can't fail, the sender is right here
+        let _ = self.now.subscribe().wait_for(|now| *now >= deadline).await;
+    }
+}
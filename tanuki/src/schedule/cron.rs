@@ -0,0 +1,193 @@
+//! Cron expressions, as in crontab(5)
+
+use core::{fmt, str::FromStr};
+
+use chrono::{Datelike as _, NaiveDate, NaiveDateTime, TimeDelta, Timelike as _};
+
+use crate::Error;
+
+/// A cron expression: `minute hour day-of-month month day-of-week`
+///
+/// Fields take `*`, numbers, ranges (`1-5`), steps (`*/15`, `0-30/10`) and comma separated lists
+/// of those. Days of the week count from Sunday as 0, and 7 is Sunday too. Like in crontab, when
+/// both day fields are restricted a day matching either is enough.
+///
+/// `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` are accepted as shorthands.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// How far ahead to look for a matching day, covers leap days on a given weekday
+const SEARCH_DAYS: i64 = 366 * 28;
+
+impl Cron {
+    /// First time strictly after `after` that matches the expression
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + TimeDelta::minutes(1);
+
+        (0..SEARCH_DAYS)
+            .map_while(|offset| start.date().checked_add_signed(TimeDelta::days(offset)))
+            .filter(|date| self.matches_day(*date))
+            .flat_map(|date| {
+                (0..24)
+                    .filter(|hour| bit(self.hours, *hour))
+                    .flat_map(move |hour| {
+                        (0..60)
+                            .filter(|minute| bit(self.minutes, *minute))
+                            .filter_map(move |minute| date.and_hms_opt(hour, minute, 0))
+                    })
+            })
+            .find(|time| *time >= start)
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = bit(self.days, date.day());
+        let weekday = bit(self.weekdays, date.weekday().num_days_from_sunday());
+
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        day_matches && bit(self.months, date.month())
+    }
+}
+
+fn bit(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+/// Parse one field into a bitset of the values it matches
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut set = 0;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+
+        let number = |s: &str| {
+            s.parse::<u32>()
+                .ok()
+                .filter(|n| (min..=max).contains(n))
+                .ok_or_else(|| format!("{s:?} is not a number from {min} to {max}"))
+        };
+
+        let (from, to) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((from, to)) => (number(from)?, number(to)?),
+                // `5/10` means from 5 onwards
+                None if step.is_some() => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+
+        let step = match step {
+            Some(step) => step
+                .parse::<usize>()
+                .ok()
+                .filter(|step| *step > 0)
+                .ok_or_else(|| format!("bad step {step:?}"))?,
+            None => 1,
+        };
+
+        if from > to {
+            return Err(format!("range {range:?} is backwards"));
+        }
+
+        for n in (from..=to).step_by(step) {
+            set |= 1 << n;
+        }
+    }
+
+    Ok(set)
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+
+        let invalid = |reason: String| Error::InvalidSchedule(format!("{s:?}: {reason}"));
+
+        let [minutes, hours, days, months, weekdays] = expr
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| invalid("expected 5 fields".into()))?;
+
+        let mut weekday_set = parse_field(weekdays, 0, 7).map_err(invalid)?;
+        // both 0 and 7 are sunday
+        if bit(weekday_set, 7) {
+            weekday_set |= 1;
+        }
+
+        Ok(Self {
+            source: s.into(),
+            minutes: parse_field(minutes, 0, 59).map_err(invalid)?,
+            hours: parse_field(hours, 0, 23).map_err(invalid)?,
+            days: parse_field(days, 1, 31).map_err(invalid)?,
+            months: parse_field(months, 1, 12).map_err(invalid)?,
+            weekdays: weekday_set,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Debug for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Cron").field(&self.source).finish()
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cron_next() {
+        let at = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        let next =
+            |expr: &str, after: &str| expr.parse::<Cron>().unwrap().next_after(at(after)).unwrap();
+
+        assert_eq!(next("* * * * *", "2024-03-10 12:00:30"), at("2024-03-10 12:01:00"));
+        assert_eq!(next("*/15 * * * *", "2024-03-10 12:00:00"), at("2024-03-10 12:15:00"));
+        assert_eq!(next("30 7 * * 1-5", "2024-03-08 08:00:00"), at("2024-03-11 07:30:00"));
+        assert_eq!(next("0 22 * * 7", "2024-03-10 21:59:00"), at("2024-03-10 22:00:00"));
+        assert_eq!(next("0 0 29 2 *", "2024-03-01 00:00:00"), at("2028-02-29 00:00:00"));
+        assert_eq!(next("@monthly", "2024-12-15 10:00:00"), at("2025-01-01 00:00:00"));
+        assert_eq!(next("5/20 9,18 * * *", "2024-03-10 09:45:00"), at("2024-03-10 18:05:00"));
+        // the 13th, or any friday
+        assert_eq!(next("0 12 13 * 5", "2024-03-09 00:00:00"), at("2024-03-13 12:00:00"));
+
+        for bad in ["* * * *", "60 * * * *", "* * 0 * *", "5-1 * * * *", "*/0 * * * *", "x * * * *"]
+        {
+            assert!(bad.parse::<Cron>().is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! Running jobs at set times
+//!
+//! A [`Scheduler`] runs jobs on a [`Trigger`]: a cron expression, a delay, or the sun rising or
+//! setting at a configured [`Location`]. Every run is started as an
+//! [intent](TanukiConnection::intent), so jobs can send commands like any other automation.
+//!
+//! ```no_run
+//! # use tanuki::{TanukiConnection, schedule::{Location, SunEvent, Trigger}};
+//! # use chrono::TimeDelta;
+//! # async fn example(conn: std::sync::Arc<TanukiConnection>) -> tanuki::Result<()> {
+//! let scheduler = conn.scheduler().location(Location::new(52.37, 4.90));
+//!
+//! scheduler
+//!     .schedule(Trigger::sun(SunEvent::Sunset, TimeDelta::minutes(-15)), |conn| async move {
+//!         // turn on the lights
+//!         Ok(())
+//!     })?
+//!     .detach();
+//! # Ok(())
+//! # }
+//! ```
+
+mod clock;
+mod cron;
+mod sun;
+
+use core::time::Duration;
+use std::sync::Arc;
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use tokio::task::JoinHandle;
+
+pub use self::{
+    clock::{Clock, ManualClock, SystemClock},
+    cron::Cron,
+    sun::{Location, SunEvent, sun_time},
+};
+use crate::{Error, Result, TanukiConnection};
+
+/// Runs that are late by more than this, eg. because the machine was asleep, are skipped
+const MISSED_GRACE: TimeDelta = TimeDelta::minutes(1);
+
+/// Shortest delay of [`Trigger::After`] and [`Trigger::Every`]
+const MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// When a job should run
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    /// Whenever the expression matches, in the scheduler's time zone
+    Cron(Cron),
+    /// Once, this long after being scheduled, at least a second
+    After(Duration),
+    /// Repeatedly, this long apart, at least a second
+    Every(Duration),
+    /// Every day at a [`SunEvent`], shifted by `offset`
+    Sun { event: SunEvent, offset: TimeDelta },
+}
+
+impl Trigger {
+    /// Parse a cron expression, see [`Cron`]
+    pub fn cron(expr: &str) -> Result<Self> {
+        Ok(Self::Cron(expr.parse()?))
+    }
+
+    pub fn sun(event: SunEvent, offset: TimeDelta) -> Self {
+        Self::Sun { event, offset }
+    }
+
+    /// Next time to run strictly after `after`
+    fn next_after(&self, after: DateTime<Utc>, calendar: &Calendar) -> Option<DateTime<Utc>> {
+        match self {
+            Trigger::Cron(cron) => {
+                let mut local = calendar.local(after);
+
+                // skip over times that don't exist in the time zone, when clocks move forward
+                loop {
+                    local = cron.next_after(local)?;
+
+                    if let Some(time) = calendar.utc(local)
+                        && time > after
+                    {
+                        return Some(time);
+                    }
+                }
+            }
+            Trigger::After(delay) | Trigger::Every(delay) => {
+                Some(after + TimeDelta::from_std(*delay).ok()?)
+            }
+            Trigger::Sun { event, offset } => {
+                let location = calendar.location?;
+                let today = calendar.local(after).date();
+
+                // from yesterday, in case the offset pushes it past midnight
+                today
+                    .pred_opt()?
+                    .iter_days()
+                    .take(366)
+                    .filter_map(|date| sun_time(*event, date, location))
+                    .map(|time| time + *offset)
+                    .find(|time| *time > after)
+            }
+        }
+    }
+
+    fn is_once(&self) -> bool {
+        matches!(self, Trigger::After(_))
+    }
+
+    /// Check that the trigger can fire as intended in `calendar`
+    fn validate(&self, calendar: &Calendar) -> Result<()> {
+        match self {
+            Trigger::After(delay) | Trigger::Every(delay) if *delay < MIN_DELAY => {
+                Err(Error::InvalidSchedule(format!("delay of {delay:?} is shorter than a second")))
+            }
+            Trigger::Sun { .. } if calendar.location.is_none() => Err(Error::InvalidSchedule(
+                "sun triggers need a location, see Scheduler::location".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Time zone and place to interpret triggers in
+#[derive(Debug, Clone, Copy)]
+struct Calendar {
+    /// `None` for the system's local time zone
+    offset: Option<FixedOffset>,
+    location: Option<Location>,
+}
+
+impl Calendar {
+    fn local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        match self.offset {
+            Some(offset) => time.with_timezone(&offset).naive_local(),
+            None => time.with_timezone(&Local).naive_local(),
+        }
+    }
+
+    /// `None` if the local time doesn't exist, the earlier one if it happens twice
+    fn utc(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self.offset {
+            Some(offset) => offset
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|t| t.to_utc()),
+            None => Local
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|t| t.to_utc()),
+        }
+    }
+}
+
+/// Schedules jobs for a connection, see the [module docs](self)
+#[must_use]
+pub struct Scheduler<C: Clock = SystemClock> {
+    conn: Arc<TanukiConnection>,
+    clock: Arc<C>,
+    calendar: Calendar,
+}
+
+impl<C: Clock> Clone for Scheduler<C> {
+    fn clone(&self) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            clock: self.clock.clone(),
+            calendar: self.calendar,
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(conn: &Arc<TanukiConnection>) -> Self {
+        Self::with_clock(conn, SystemClock)
+    }
+}
+
+impl<C: Clock> Scheduler<C> {
+    /// Schedule by another clock than the system's, such as a [`ManualClock`]
+    pub fn with_clock(conn: &Arc<TanukiConnection>, clock: C) -> Self {
+        Self {
+            conn: conn.clone(),
+            clock: Arc::new(clock),
+            calendar: Calendar { offset: None, location: None },
+        }
+    }
+
+    /// Where to work out sunrise and sunset for, required for [`Trigger::Sun`]
+    pub fn location(mut self, location: Location) -> Self {
+        self.calendar.location = Some(location);
+        self
+    }
+
+    /// Interpret cron expressions at a fixed offset from UTC, instead of the local time zone
+    pub fn utc_offset(mut self, offset: FixedOffset) -> Self {
+        self.calendar.offset = Some(offset);
+        self
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// When `trigger` would next fire, `None` if never
+    pub fn next_run(&self, trigger: &Trigger) -> Option<DateTime<Utc>> {
+        trigger.next_after(self.clock.now(), &self.calendar)
+    }
+
+    /// Run `job` every time `trigger` fires, until the returned handle is dropped
+    ///
+    /// Each run is started as an [intent](TanukiConnection::intent), so errors are logged and
+    /// runs that take long don't hold up the next one.
+    ///
+    /// Fails with [`Error::InvalidSchedule`] for delays shorter than a second, or sun triggers
+    /// without a [location](Self::location).
+    pub fn schedule<J, F>(&self, trigger: Trigger, job: J) -> Result<ScheduledJob>
+    where
+        J: Fn(Arc<TanukiConnection>) -> F + Send + Sync + 'static,
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        trigger.validate(&self.calendar)?;
+
+        let conn = self.conn.clone();
+        let job = Arc::new(job);
+        let task = tokio::spawn(run(trigger, self.calendar, self.clock.clone(), move |_| {
+            let job = job.clone();
+            conn.intent(move |conn| job(conn));
+        }));
+
+        Ok(ScheduledJob { task, active: true })
+    }
+
+    /// Run `job` once after `delay`, which must be at least a second
+    pub fn after<J, F>(&self, delay: Duration, job: J) -> Result<ScheduledJob>
+    where
+        J: Fn(Arc<TanukiConnection>) -> F + Send + Sync + 'static,
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.schedule(Trigger::After(delay), job)
+    }
+}
+
+/// Fire `trigger` until it runs out, calling `fire` with the time it was due
+async fn run<C: Clock>(
+    trigger: Trigger,
+    calendar: Calendar,
+    clock: Arc<C>,
+    mut fire: impl FnMut(DateTime<Utc>) + Send,
+) {
+    let mut after = clock.now();
+
+    while let Some(next) = trigger.next_after(after, &calendar) {
+        clock.sleep_until(next).await;
+
+        let now = clock.now();
+        if now - next > MISSED_GRACE {
+            tracing::debug!(?trigger, %next, "Skipping missed scheduled run");
+
+            // a one-off that was missed is gone, rather than rescheduled from now
+            if trigger.is_once() {
+                break;
+            }
+
+            after = now;
+            continue;
+        }
+
+        fire(next);
+
+        if trigger.is_once() {
+            break;
+        }
+
+        // a late run pushes back the ones after it, rather than catching up in a burst
+        after = match trigger {
+            Trigger::Every(_) => next.max(now),
+            _ => next,
+        };
+    }
+}
+
+/// Handle to a job started by a [`Scheduler`]
+///
+/// The job stops when this handle is dropped or [cancelled](Self::cancel), runs already started
+/// finish on their own. Use [`detach`](Self::detach) to keep it running for the lifetime of the
+/// program instead.
+#[must_use = "the job is cancelled when dropped"]
+pub struct ScheduledJob {
+    task: JoinHandle<()>,
+    active: bool,
+}
+
+impl ScheduledJob {
+    /// Stop the job, same as dropping the handle
+    pub fn cancel(self) {}
+
+    /// Keep the job running until the program exits
+    pub fn detach(mut self) {
+        self.active = false;
+    }
+
+    /// The trigger has no more times to fire, such as a delay that already ran
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Drop for ScheduledJob {
+    fn drop(&mut self) {
+        if self.active {
+            self.task.abort();
+        }
+    }
+}
+
+impl TanukiConnection {
+    /// Start scheduling jobs by the system clock in the local time zone, see [`Scheduler`]
+    pub fn scheduler(self: &Arc<Self>) -> Scheduler {
+        Scheduler::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DurationRound as _;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn scheduled_runs() {
+        let start = "2024-03-10T11:59:30Z".parse::<DateTime<Utc>>().unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let location = Location::new(52.37, 4.90);
+        let calendar = Calendar {
+            offset: Some(FixedOffset::east_opt(3600).unwrap()),
+            location: Some(location),
+        };
+
+        let spawn = |trigger| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let task = tokio::spawn(run(trigger, calendar, clock.clone(), move |time| {
+                tx.send(time).unwrap();
+            }));
+            (rx, task)
+        };
+
+        let (mut hourly, _) = spawn(Trigger::cron("0 * * * *").unwrap());
+        let (mut once, once_task) = spawn(Trigger::After(Duration::from_secs(60)));
+        let (mut sunset, _) = spawn(Trigger::sun(SunEvent::Sunset, TimeDelta::minutes(-30)));
+        let (mut missed, missed_task) = spawn(Trigger::After(Duration::from_secs(120)));
+        tokio::task::yield_now().await;
+
+        // 13:00 in UTC+1
+        clock.advance(TimeDelta::seconds(30));
+        assert_eq!(hourly.recv().await.unwrap(), start + TimeDelta::seconds(30));
+        assert!(once.try_recv().is_err());
+
+        clock.advance(TimeDelta::seconds(30));
+        assert_eq!(once.recv().await.unwrap(), start + TimeDelta::seconds(60));
+        once_task.await.unwrap();
+
+        let sunset_at = sun_time(SunEvent::Sunset, start.date_naive(), location).unwrap()
+            - TimeDelta::minutes(30);
+        clock.set(sunset_at);
+        assert_eq!(sunset.recv().await.unwrap(), sunset_at);
+
+        // a one-off that was missed by more than the grace period is dropped, not re-armed
+        tokio::time::timeout(Duration::from_secs(1), missed_task)
+            .await
+            .expect("missed one-off stops")
+            .unwrap();
+        assert!(missed.try_recv().is_err());
+
+        // the hourly runs missed while the clock jumped ahead are skipped
+        let next_hour =
+            sunset_at.duration_trunc(TimeDelta::hours(1)).unwrap() + TimeDelta::hours(1);
+        clock.set(next_hour);
+        assert_eq!(hourly.recv().await.unwrap(), next_hour);
+        assert!(hourly.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn late_runs_dont_catch_up() {
+        let start = "2024-03-10T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let calendar = Calendar { offset: None, location: None };
+
+        let (tx, mut runs) = mpsc::unbounded_channel();
+        let _task = tokio::spawn(run(
+            Trigger::Every(Duration::from_secs(10)),
+            calendar,
+            clock.clone(),
+            move |time| tx.send(time).unwrap(),
+        ));
+        tokio::task::yield_now().await;
+
+        // late by 25s, within the grace period
+        clock.advance(TimeDelta::seconds(35));
+        assert_eq!(runs.recv().await.unwrap(), start + TimeDelta::seconds(10));
+        tokio::task::yield_now().await;
+        assert!(runs.try_recv().is_err());
+
+        clock.advance(TimeDelta::seconds(10));
+        assert_eq!(runs.recv().await.unwrap(), start + TimeDelta::seconds(45));
+    }
+
+    #[test]
+    fn invalid_triggers() {
+        let calendar = Calendar { offset: None, location: None };
+
+        for trigger in [
+            Trigger::After(Duration::ZERO),
+            Trigger::Every(Duration::ZERO),
+            Trigger::Every(Duration::from_millis(999)),
+            Trigger::sun(SunEvent::Sunrise, TimeDelta::zero()),
+        ] {
+            assert!(
+                matches!(trigger.validate(&calendar), Err(Error::InvalidSchedule(_))),
+                "{trigger:?} should be rejected"
+            );
+        }
+
+        assert!(
+            Trigger::Every(Duration::from_secs(1))
+                .validate(&calendar)
+                .is_ok()
+        );
+        assert!(
+            Trigger::cron("* * * * *")
+                .unwrap()
+                .validate(&calendar)
+                .is_ok()
+        );
+
+        let calendar = Calendar {
+            location: Some(Location::new(52.37, 4.90)),
+            ..calendar
+        };
+        assert!(
+            Trigger::sun(SunEvent::Sunrise, TimeDelta::zero())
+                .validate(&calendar)
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Times of sunrise, sunset and twilight, using the sunrise equation
+//!
+//! Accurate to within a minute or two away from the poles, which is plenty for turning on lights.
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// Where on earth, for working out when the sun rises and sets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    /// Degrees north of the equator
+    pub latitude: f64,
+    /// Degrees east of Greenwich
+    pub longitude: f64,
+}
+
+impl Location {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Self { latitude, longitude }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SunEvent {
+    /// Start of civil twilight, when the sun is 6° below the horizon
+    Dawn,
+    Sunrise,
+    Sunset,
+    /// End of civil twilight, when the sun is 6° below the horizon
+    Dusk,
+}
+
+impl SunEvent {
+    /// Altitude of the sun's center at the event, in degrees
+    fn altitude(self) -> f64 {
+        match self {
+            // refraction and the radius of the sun's disc
+            SunEvent::Sunrise | SunEvent::Sunset => -0.833,
+            SunEvent::Dawn | SunEvent::Dusk => -6.0,
+        }
+    }
+
+    fn is_morning(self) -> bool {
+        matches!(self, SunEvent::Dawn | SunEvent::Sunrise)
+    }
+}
+
+/// Julian date of 2000-01-01 12:00 UTC
+const J2000: f64 = 2_451_545.0;
+/// Julian date of the unix epoch
+const UNIX_EPOCH: f64 = 2_440_587.5;
+
+/// When `event` happens on `date` at `location`
+///
+/// `None` if it doesn't happen that day, during polar day or night.
+pub fn sun_time(event: SunEvent, date: NaiveDate, location: Location) -> Option<DateTime<Utc>> {
+    let days_since_epoch = (date - DateTime::UNIX_EPOCH.date_naive()).num_days() as f64;
+    let day = (UNIX_EPOCH + 0.5 + days_since_epoch - J2000).round();
+
+    let mean_solar_time = day - location.longitude / 360.;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_time)
+        .rem_euclid(360.)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.0200 * (2. * anomaly).sin() + 0.0003 * (3. * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180. + 102.9372)
+        .rem_euclid(360.)
+        .to_radians();
+    let transit =
+        J2000 + mean_solar_time + 0.0053 * anomaly.sin() - 0.0069 * (2. * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = (event.altitude().to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+
+    if !(-1. ..=1.).contains(&cos_hour_angle) {
+        return None;
+    }
+
+    let hour_angle = cos_hour_angle.acos().to_degrees() / 360.;
+    let julian = if event.is_morning() {
+        transit - hour_angle
+    } else {
+        transit + hour_angle
+    };
+
+    DateTime::from_timestamp_millis(((julian - UNIX_EPOCH) * 86_400_000.).round() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    #[test]
+    fn sun_times() {
+        let amsterdam = Location::new(52.37, 4.90);
+        let midsummer = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let at = |h, m| midsummer.and_hms_opt(h, m, 0).unwrap().and_utc();
+
+        let close = |event, expected: DateTime<Utc>| {
+            let time = sun_time(event, midsummer, amsterdam).unwrap();
+            assert!((time - expected).abs() < TimeDelta::minutes(3), "{event:?} at {time}");
+        };
+
+        close(SunEvent::Sunrise, at(3, 18));
+        close(SunEvent::Sunset, at(20, 6));
+        close(SunEvent::Dusk, at(20, 56));
+
+        let tromso = Location::new(69.65, 18.96);
+        assert_eq!(sun_time(SunEvent::Sunrise, midsummer, tromso), None);
+    }
+}
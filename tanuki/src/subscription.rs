@@ -0,0 +1,275 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use mqtt_protocol_core::mqtt::packet::SubscriptionIdentifier;
+use tanuki_common::TopicFilter;
+
+use crate::{PublishEvent, Result, SubscriptionHandler, TanukiConnection};
+
+/// Handle to an active subscription
+///
+/// The subscription is removed when this handle is dropped or [cancelled](Self::cancel), and the
+/// topic unsubscribed from the broker once no other subscription uses the same filter. Use
+/// [`detach`](Self::detach) to keep it for the lifetime of the connection instead.
+#[must_use = "the subscription is cancelled when dropped"]
+pub struct Subscription {
+    conn: Arc<TanukiConnection>,
+    sub_id: u32,
+    handler_id: u64,
+    active: bool,
+}
+
+impl Subscription {
+    pub(crate) fn new(conn: Arc<TanukiConnection>, sub_id: u32, handler_id: u64) -> Self {
+        Self {
+            conn,
+            sub_id,
+            handler_id,
+            active: true,
+        }
+    }
+
+    /// Subscription identifier on the broker, shared with other subscriptions on the same filter
+    pub fn id(&self) -> u32 {
+        self.sub_id
+    }
+
+    /// Unsubscribe, waiting for the broker to acknowledge
+    pub async fn cancel(mut self) -> Result<()> {
+        self.active = false;
+        self.conn.unsubscribe(self.handler_id).await
+    }
+
+    /// Keep the subscription active until the connection is shut down
+    pub fn detach(mut self) {
+        self.active = false;
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!(sub_id = self.sub_id, "Subscription dropped outside of a runtime");
+            return;
+        };
+
+        let conn = self.conn.clone();
+        let sub_id = self.sub_id;
+        let handler_id = self.handler_id;
+        runtime.spawn(async move {
+            if let Err(e) = conn.unsubscribe(handler_id).await {
+                tracing::warn!(sub_id, "Failed to unsubscribe: {e}");
+            }
+        });
+    }
+}
+
+/// Subscriptions on the broker, and the handlers sharing them
+///
+/// Subscribing to the same filter again replaces the broker's subscription along with its
+/// identifier, so every filter gets a single identifier shared by all its users, and is only
+/// unsubscribed once the last of them is gone.
+pub(crate) struct Subscriptions {
+    /// Topic filter and number of users by subscription ID, replayed after reconnecting
+    filters: BTreeMap<u32, (String, usize)>,
+    /// Handlers by handler ID, along with the subscription they share
+    handlers: BTreeMap<u64, (u32, TopicFilter, SubscriptionHandler)>,
+    next_id: u32,
+    next_handler_id: u64,
+}
+
+impl Subscriptions {
+    /// Largest subscription identifier, which is a variable byte integer of up to 4 bytes
+    const MAX_ID: u32 = (1 << 28) - 1;
+
+    pub fn new() -> Self {
+        Self {
+            filters: BTreeMap::new(),
+            handlers: BTreeMap::new(),
+            next_id: 1,
+            next_handler_id: 1,
+        }
+    }
+
+    /// Start using the subscription on `topic`, picking an unused identifier if it's new
+    pub fn acquire(&mut self, topic: &str) -> SubscriptionIdentifier {
+        let existing = self.filters.iter_mut().find(|(_, (t, _))| t == topic);
+        let id = match existing {
+            Some((&id, (_, users))) => {
+                *users += 1;
+                id
+            }
+            None => {
+                let id = self.next_unused_id();
+                self.filters.insert(id, (topic.to_string(), 1));
+                id
+            }
+        };
+
+        SubscriptionIdentifier::new(id).expect("id is in range")
+    }
+
+    fn next_unused_id(&mut self) -> u32 {
+        loop {
+            let id = self.next_id;
+            self.next_id = if id >= Self::MAX_ID { 1 } else { id + 1 };
+
+            if !self.filters.contains_key(&id) {
+                break id;
+            }
+        }
+    }
+
+    /// Stop using subscription `sub_id`, returning its topic filter if nobody uses it anymore
+    pub fn release(&mut self, sub_id: u32) -> Option<String> {
+        let (_, users) = self.filters.get_mut(&sub_id)?;
+        *users -= 1;
+
+        if *users > 0 {
+            return None;
+        }
+
+        self.filters.remove(&sub_id).map(|(topic, _)| topic)
+    }
+
+    pub fn add_handler(
+        &mut self,
+        sub_id: u32,
+        filter: TopicFilter,
+        handler: SubscriptionHandler,
+    ) -> u64 {
+        let handler_id = self.next_handler_id;
+        self.next_handler_id += 1;
+
+        self.handlers.insert(handler_id, (sub_id, filter, handler));
+        handler_id
+    }
+
+    /// Remove a handler, returning the subscription it used
+    pub fn remove_handler(&mut self, handler_id: u64) -> Option<u32> {
+        self.handlers
+            .remove(&handler_id)
+            .map(|(sub_id, _, _)| sub_id)
+    }
+
+    /// Call every handler on one of the subscriptions `sub_ids` that matches the event
+    ///
+    /// Handlers that are done are removed, returning the subscriptions they used.
+    pub fn dispatch(&mut self, sub_ids: &[u32], event: &PublishEvent) -> Vec<u32> {
+        let mut done = Vec::new();
+
+        self.handlers
+            .retain(|handler_id, (sub_id, filter, handler)| {
+                // the broker can't tell `$meta` apart from other levels, so check the whole topic
+                if !sub_ids.contains(sub_id) || !filter.matches(&event.topic) {
+                    return true;
+                }
+
+                if handler(event.clone()) {
+                    return true;
+                }
+
+                tracing::debug!(sub_id, "Removing subscription handler {handler_id}");
+                done.push(*sub_id);
+                false
+            });
+
+        done
+    }
+
+    /// Topic filters by subscription ID
+    pub fn topics(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.filters
+            .iter()
+            .map(|(&id, (topic, _))| (id, topic.as_str()))
+    }
+
+    /// Forget every subscription and handler, returning the topic filters that were in use
+    pub fn clear(&mut self) -> BTreeSet<String> {
+        self.handlers.clear();
+        core::mem::take(&mut self.filters)
+            .into_values()
+            .map(|(topic, _)| topic)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn shared_filters() {
+        let event = |topic: &str| PublishEvent {
+            sub_id: None,
+            topic: topic.parse().unwrap(),
+            payload: json!(true),
+            retain: false,
+            response_topic: None,
+            correlation_data: None,
+        };
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handler = |name: &'static str, keep: bool| -> SubscriptionHandler {
+            let seen = seen.clone();
+            Box::new(move |event: PublishEvent| {
+                seen.lock().unwrap().push((name, event.topic.to_string()));
+                keep
+            })
+        };
+
+        let mut subs = Subscriptions::new();
+        let all = TopicFilter::all();
+        let topic = all.to_string();
+
+        // both subscriptions on the same filter share one identifier on the broker
+        let first = subs.acquire(&topic).val();
+        let first_handler = subs.add_handler(first, all.clone(), handler("first", true));
+        let second = subs.acquire(&topic).val();
+        subs.add_handler(second, all.clone(), handler("second", false));
+        assert_eq!(first, second);
+        assert_eq!(subs.topics().collect::<Vec<_>>(), [(first, topic.as_str())]);
+
+        let lamp = "tanuki/entities/lamp/tanuki.on_off/on";
+        assert_eq!(subs.dispatch(&[first], &event(lamp)), [second]);
+        assert!(subs.dispatch(&[first], &event(lamp)).is_empty());
+        assert!(subs.dispatch(&[first + 1], &event(lamp)).is_empty());
+        assert_eq!(*seen.lock().unwrap(), [
+            ("first", lamp.to_string()),
+            ("second", lamp.to_string()),
+            ("first", lamp.to_string())
+        ]);
+
+        // only unsubscribed once the last user is gone
+        assert_eq!(subs.release(second), None);
+        assert_eq!(subs.remove_handler(first_handler), Some(first));
+        assert_eq!(subs.release(first), Some(topic.clone()));
+        assert_eq!(subs.topics().count(), 0);
+    }
+
+    #[test]
+    fn identifiers_skip_those_in_use() {
+        let mut subs = Subscriptions::new();
+        subs.next_id = Subscriptions::MAX_ID - 1;
+
+        let a = subs.acquire("a").val();
+        let b = subs.acquire("b").val();
+        assert_eq!((a, b), (Subscriptions::MAX_ID - 1, Subscriptions::MAX_ID));
+
+        // wraps around to 1, skipping identifiers that are still in use
+        subs.next_id = Subscriptions::MAX_ID - 1;
+        assert_eq!(subs.acquire("c").val(), 1);
+        subs.next_id = 1;
+        assert_eq!(subs.acquire("d").val(), 2);
+    }
+}
@@ -0,0 +1,291 @@
+use core::time::Duration;
+use std::sync::{Arc, Weak};
+
+use mqtt_endpoint_tokio::mqtt_ep::{
+    endpoint::Endpoint,
+    packet::{Packet, SubscriptionIdentifier, v5_0},
+    result_code::DisconnectReasonCode,
+    role,
+};
+use tanuki_common::meta;
+
+use crate::{Error, PublishOpts, Result, TanukiConnection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The initial connection is being established
+    Connecting,
+    /// Connected to the broker, subscriptions and metadata are in place
+    Connected,
+    /// The connection was lost and is being re-established
+    Reconnecting { attempt: u32 },
+    /// The connection was shut down and will not reconnect
+    Closed,
+}
+
+impl TanukiConnection {
+    /// Reads every packet from the endpoint, reconnecting whenever the broker goes away
+    ///
+    /// Only holds on to the connection while handling a packet or attempting to reconnect, and
+    /// stops once it's dropped.
+    pub(crate) async fn supervise(weak: Weak<Self>, endpoint: Arc<Endpoint<role::Client>>) {
+        loop {
+            let packet = endpoint.recv().await;
+
+            let Some(conn) = weak.upgrade() else {
+                tracing::debug!("Connection dropped, stopping supervisor");
+                break;
+            };
+
+            match packet {
+                Ok(Packet::V5_0Publish(publish)) => conn.dispatch(publish).await,
+                Ok(packet) => match ack_packet_id(&packet) {
+                    Some(packet_id) => conn.resolve_ack(packet_id, packet).await,
+                    None => tracing::trace!("Received {packet:?}"),
+                },
+                Err(_) if conn.connection_state() == ConnectionState::Closed => {
+                    tracing::debug!("Connection closed, stopping supervisor");
+                    conn.drop_pending_acks().await;
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Lost connection to MQTT broker: {e}");
+                    conn.drop_pending_acks().await;
+                    drop(conn);
+
+                    if !Self::reconnect(&weak).await {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Re-establishes the session, backing off between attempts
+    ///
+    /// Doesn't hold on to the connection while sleeping. Returns false if it was dropped or closed
+    /// in the meantime.
+    async fn reconnect(weak: &Weak<Self>) -> bool {
+        let mut backoff = Backoff::default();
+
+        for attempt in 1.. {
+            let Some(conn) = weak.upgrade() else {
+                tracing::debug!("Connection dropped, giving up on reconnecting");
+                return false;
+            };
+
+            let closed = !conn.state.send_if_modified(|state| {
+                if *state == ConnectionState::Closed {
+                    return false;
+                }
+
+                *state = ConnectionState::Reconnecting { attempt };
+                true
+            });
+
+            if closed {
+                return false;
+            }
+
+            drop(conn);
+
+            let delay = backoff.next_delay();
+            tracing::info!(attempt, "Reconnecting to MQTT broker in {delay:?}");
+            tokio::time::sleep(delay).await;
+
+            let Some(conn) = weak.upgrade() else {
+                tracing::debug!("Connection dropped, giving up on reconnecting");
+                return false;
+            };
+
+            // make sure the endpoint is detached, it may have half-connected on a previous attempt
+            let _ = conn.endpoint.close().await;
+
+            match conn.establish().await {
+                Ok(()) => match conn.restore_session().await {
+                    Ok(()) => {
+                        tracing::info!("Reconnected to MQTT broker");
+                        return conn.state.send_if_modified(|state| {
+                            if *state == ConnectionState::Closed {
+                                return false;
+                            }
+
+                            *state = ConnectionState::Connected;
+                            true
+                        });
+                    }
+                    Err(e) => tracing::warn!(attempt, "Failed to restore session: {e}"),
+                },
+                Err(e) => tracing::warn!(attempt, "Failed to reconnect: {e}"),
+            }
+        }
+
+        unreachable!("attempts are unbounded")
+    }
+
+    /// Replays subscriptions and retained metadata after a new session was established
+    async fn restore_session(&self) -> Result<()> {
+        let subscriptions = self
+            .subscriptions
+            .lock()
+            .await
+            .topics()
+            .map(|(sub_id, topic)| (sub_id, topic.to_string()))
+            .collect::<Vec<_>>();
+        for (sub_id, topic) in subscriptions {
+            tracing::debug!("Resubscribing to topic '{topic}'");
+
+            let sub_id = SubscriptionIdentifier::new(sub_id).expect("stored id is valid");
+            let ack = self.send_subscribe(sub_id, &topic).await?;
+
+            // we're the ones reading acknowledgements, so don't wait for it here
+            tokio::spawn(async move {
+                if let Err(e) = ack.subscribe().await {
+                    tracing::warn!("Failed to resubscribe to topic '{topic}': {e}");
+                }
+            });
+        }
+
+        let retained_meta = self.retained_meta.lock().await.clone();
+        for (topic, payload) in retained_meta {
+            self.send_publish(&topic, &payload, PublishOpts::metadata(), Vec::new())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Cleanly disconnect from the broker
+    ///
+    /// Marks every entity authored through this connection as
+    /// [`Disconnected`](meta::EntityStatus::Disconnected), unsubscribes from all topics, waits for
+    /// in-flight QoS 1/2 publishes to be acknowledged and sends DISCONNECT, so the broker does not
+    /// publish our will. The connection cannot be used afterwards.
+    pub async fn shutdown(&self) -> Result<()> {
+        if self.connection_state() == ConnectionState::Connected {
+            let authored = core::mem::take(&mut *self.authored.lock().await);
+            for entity in authored {
+                self.publish_entity_meta(entity, meta::EntityStatus::Disconnected)
+                    .await?;
+            }
+
+            self.publish(
+                self.client_status_topic(),
+                meta::EntityStatus::Disconnected,
+                PublishOpts::metadata(),
+            )
+            .await?;
+
+            let topics = self.subscriptions.lock().await.clear();
+            if !topics.is_empty() {
+                self.send_unsubscribe(topics).await?.unsubscribe().await?;
+            }
+
+            self.flush().await?;
+        }
+
+        self.state.send_replace(ConnectionState::Closed);
+
+        if let Err(e) = self
+            .endpoint
+            .send(
+                v5_0::Disconnect::builder()
+                    .reason_code(DisconnectReasonCode::NormalDisconnection)
+                    .build()?,
+            )
+            .await
+        {
+            tracing::warn!("Failed to send DISCONNECT: {e}");
+        }
+
+        self.endpoint.close().await?;
+
+        tracing::info!("Disconnected from MQTT broker");
+
+        Ok(())
+    }
+
+    /// Wait until all QoS 1/2 publishes have been acknowledged by the broker
+    async fn flush(&self) -> Result<()> {
+        const TIMEOUT: Duration = Duration::from_secs(5);
+
+        let flushed = tokio::time::timeout(TIMEOUT, async {
+            while !self.endpoint.get_stored_packets().await?.is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+
+            Ok::<_, Error>(())
+        })
+        .await;
+
+        match flushed {
+            Ok(res) => res,
+            Err(_) => {
+                tracing::warn!("Timed out waiting for in-flight publishes to be acknowledged");
+                Ok(())
+            }
+        }
+    }
+
+    /// Wait for ctrl-c, then [shut down](Self::shutdown) the connection
+    pub async fn shutdown_on_ctrl_c(&self) -> Result<()> {
+        tokio::signal::ctrl_c().await?;
+
+        tracing::info!("Received ctrl-c, shutting down");
+
+        self.shutdown().await
+    }
+}
+
+fn ack_packet_id(packet: &Packet) -> Option<u16> {
+    match packet {
+        Packet::V5_0Puback(p) => Some(p.packet_id()),
+        Packet::V5_0Pubrec(p) => Some(p.packet_id()),
+        Packet::V5_0Pubcomp(p) => Some(p.packet_id()),
+        Packet::V5_0Suback(p) => Some(p.packet_id()),
+        Packet::V5_0Unsuback(p) => Some(p.packet_id()),
+        _ => None,
+    }
+}
+
+/// Exponential backoff between reconnection attempts
+pub(crate) struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_millis(500);
+    const MAX: Duration = Duration::from_secs(30);
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            current: Self::INITIAL,
+            max: Self::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::default();
+
+        let delays = (0..9)
+            .map(|_| backoff.next_delay().as_millis())
+            .collect::<Vec<_>>();
+
+        assert_eq!(delays, [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000]);
+    }
+}
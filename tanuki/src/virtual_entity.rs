@@ -0,0 +1,432 @@
+//! Entities computed from the sensors of other entities
+//!
+//! A [`VirtualEntity`] follows a few input sensors, and publishes sensor readings computed from
+//! them as an entity of its own, such as a dew point from a temperature and humidity, or whether
+//! any of several windows is open.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use tanuki::{TanukiConnection, common::capabilities::sensor::SensorValue};
+//! # use tanuki::virtual_entity::dew_point;
+//! # async fn example(conn: std::sync::Arc<TanukiConnection>) -> tanuki::Result<()> {
+//! let balcony = conn
+//!     .virtual_entity("balcony.dew_point")
+//!     .name("Balcony dew point")
+//!     .input("balcony_door.temperature", "temperature")
+//!     .input("balcony_door.temperature", "humidity")
+//!     .max_age(Duration::from_secs(15 * 60))
+//!     .output("dew_point", "°C", |inputs| match inputs {
+//!         [SensorValue::Number(t), SensorValue::Number(rh)] => {
+//!             Some(SensorValue::Number(dew_point(*t, *rh)))
+//!         }
+//!         _ => None,
+//!     })
+//!     .publish()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use core::time::Duration;
+use std::{collections::BTreeSet, sync::Arc};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{StreamExt as _, channel::mpsc};
+use tanuki_common::{
+    EntityId, TanukiString, ToTanukiString as _, TopicFilter,
+    capabilities::sensor::{SensorPayload, SensorValue},
+    meta::{self, EntityStatus},
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    Authority, EntityInit, Error, Result, Subscription, TanukiConnection, TanukiEntity,
+    capabilities::{
+        Capability as _,
+        sensor::{Sensor, SensorEvent},
+    },
+};
+
+type Compute = Box<dyn Fn(&[SensorValue]) -> Option<SensorValue> + Send + Sync>;
+
+struct Output {
+    key: TanukiString,
+    unit: String,
+    compute: Compute,
+}
+
+/// Describes a [`VirtualEntity`] before it's published, see [`TanukiConnection::virtual_entity`]
+#[must_use]
+pub struct VirtualEntityBuilder {
+    conn: Arc<TanukiConnection>,
+    id: EntityId,
+    name: Option<String>,
+    inputs: Vec<(EntityId, TanukiString)>,
+    outputs: Vec<Output>,
+    max_age: Option<Duration>,
+}
+
+impl VirtualEntityBuilder {
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Follow sensor `key` of `entity`, which is passed to the outputs after the inputs added
+    /// before it
+    pub fn input(mut self, entity: impl Into<EntityId>, key: &str) -> Self {
+        self.inputs.push((entity.into(), key.to_tanuki_string()));
+        self
+    }
+
+    /// Consider inputs stale when their latest reading is older than `max_age`
+    ///
+    /// Without one, inputs never go stale.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Publish sensor `key`, computed from the values of all inputs in the order they were added
+    ///
+    /// Only called once every input has a fresh reading. Nothing is published when `compute`
+    /// returns `None`.
+    pub fn output(
+        mut self,
+        key: &str,
+        unit: &str,
+        compute: impl Fn(&[SensorValue]) -> Option<SensorValue> + Send + Sync + 'static,
+    ) -> Self {
+        self.outputs.push(Output {
+            key: key.to_tanuki_string(),
+            unit: unit.into(),
+            compute: Box::new(compute),
+        });
+        self
+    }
+
+    /// Author the entity and start computing its outputs
+    ///
+    /// The entity stays [`Init`](EntityStatus::Init) until every input has a fresh reading, then
+    /// goes [`Online`](EntityStatus::Online). It's marked [`Lost`](EntityStatus::Lost) while any
+    /// input is stale, and back online once they're all fresh again.
+    ///
+    /// Fails with [`Error::InvalidId`] if an input isn't a valid entity ID, before publishing
+    /// anything.
+    pub async fn publish(self) -> Result<VirtualEntity> {
+        validate_inputs(&self.inputs)?;
+
+        let init = self.conn.author_entity(self.id).await?;
+        init.publish_meta(meta::Type("Virtual".into())).await?;
+        if let Some(name) = self.name {
+            init.publish_meta(meta::Name(name.into())).await?;
+        }
+
+        let sensor = init.author_capability::<Sensor<Authority>>().await?;
+        let entity = (*init).clone();
+
+        let (tx, mut rx) = mpsc::unbounded();
+
+        let mut subscriptions = Vec::new();
+        let entities = self.inputs.iter().map(|(entity, _)| entity);
+        for entity in entities.collect::<BTreeSet<_>>() {
+            let inputs = self.inputs.clone();
+            let tx = tx.clone();
+
+            let subscription = self
+                .conn
+                .subscribe_with_handler(
+                    TopicFilter::capability_data(<Sensor>::ID)
+                        .and_then(|filter| filter.entity(entity))
+                        .map_err(Error::BadTopic)?,
+                    Box::new(move |event| {
+                        let Ok(event) = SensorEvent::try_from(&event) else {
+                            return true;
+                        };
+
+                        for (i, (entity, key)) in inputs.iter().enumerate() {
+                            if event.entity == *entity && event.key == *key {
+                                let _ = tx.unbounded_send((i, event.payload.clone()));
+                            }
+                        }
+
+                        true
+                    }),
+                )
+                .await?;
+
+            subscriptions.push(subscription);
+        }
+
+        let mut inputs = Inputs::new(
+            self.inputs.len(),
+            self.max_age.and_then(|age| TimeDelta::from_std(age).ok()),
+        );
+        let outputs = self.outputs;
+
+        let task = tokio::spawn(async move {
+            let mut computed = Computed {
+                sensor,
+                init: Some(init),
+                status: EntityStatus::Init,
+                published: outputs.iter().map(|_| None).collect(),
+                outputs,
+            };
+
+            loop {
+                let expiry = inputs.next_expiry(Utc::now());
+                let sleep = expiry
+                    .and_then(|expiry| (expiry - Utc::now()).to_std().ok())
+                    .unwrap_or_default();
+
+                tokio::select! {
+                    input = rx.next() => {
+                        let Some((i, payload)) = input else { break };
+                        inputs.set(i, payload);
+                    }
+                    _ = tokio::time::sleep(sleep), if expiry.is_some() => {}
+                }
+
+                if let Err(e) = computed.update(&inputs, Utc::now()).await {
+                    tracing::warn!("Failed to publish virtual entity: {e}");
+                }
+            }
+        });
+
+        Ok(VirtualEntity {
+            entity,
+            task,
+            _subscriptions: subscriptions,
+        })
+    }
+}
+
+fn validate_inputs(inputs: &[(EntityId, TanukiString)]) -> Result<()> {
+    for (entity, _) in inputs {
+        if let Err(reason) = tanuki_common::validate_id(entity) {
+            return Err(Error::InvalidId { id: entity.to_string(), reason });
+        }
+    }
+
+    Ok(())
+}
+
+/// Latest reading of every input
+#[derive(Debug, Clone)]
+struct Inputs {
+    readings: Vec<Option<SensorPayload>>,
+    max_age: Option<TimeDelta>,
+}
+
+impl Inputs {
+    fn new(count: usize, max_age: Option<TimeDelta>) -> Self {
+        Self { readings: vec![None; count], max_age }
+    }
+
+    fn set(&mut self, i: usize, payload: SensorPayload) {
+        self.readings[i] = Some(payload);
+    }
+
+    fn is_fresh(&self, payload: &SensorPayload, now: DateTime<Utc>) -> bool {
+        self.max_age
+            .is_none_or(|max_age| now - payload.timestamp < max_age)
+    }
+
+    /// Values of all inputs, `None` unless every one has a fresh reading
+    fn values(&self, now: DateTime<Utc>) -> Option<Vec<SensorValue>> {
+        self.readings
+            .iter()
+            .map(|reading| {
+                reading
+                    .as_ref()
+                    .filter(|reading| self.is_fresh(reading, now))
+                    .map(|reading| reading.value)
+            })
+            .collect()
+    }
+
+    /// When the next reading that's fresh at `now` goes stale
+    fn next_expiry(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let max_age = self.max_age?;
+
+        self.readings
+            .iter()
+            .flatten()
+            .map(|reading| reading.timestamp + max_age)
+            .filter(|expiry| *expiry > now)
+            .min()
+    }
+
+    /// Timestamp of the newest reading
+    fn latest(&self) -> Option<DateTime<Utc>> {
+        self.readings
+            .iter()
+            .flatten()
+            .map(|reading| reading.timestamp)
+            .max()
+    }
+}
+
+/// Status the entity should have, given the one it has now and whether its inputs are all fresh
+fn next_status(current: EntityStatus, fresh: bool) -> EntityStatus {
+    match (current, fresh) {
+        (_, true) => EntityStatus::Online,
+        (EntityStatus::Init, false) => EntityStatus::Init,
+        (_, false) => EntityStatus::Lost,
+    }
+}
+
+/// Publishing side of a running [`VirtualEntity`]
+struct Computed {
+    sensor: Sensor<Authority>,
+    /// Until the entity is first marked online
+    init: Option<EntityInit>,
+    status: EntityStatus,
+    outputs: Vec<Output>,
+    published: Vec<Option<SensorValue>>,
+}
+
+impl Computed {
+    async fn update(&mut self, inputs: &Inputs, now: DateTime<Utc>) -> Result<()> {
+        let values = inputs.values(now);
+        let status = next_status(self.status, values.is_some());
+
+        // the outputs went stale along with the inputs, so refresh them even if they didn't change
+        let resumed = self.status == EntityStatus::Lost && status == EntityStatus::Online;
+
+        if let Some(values) = &values {
+            let timestamp = inputs.latest().unwrap_or(now);
+
+            for (output, published) in self.outputs.iter().zip(&mut self.published) {
+                let Some(value) = (output.compute)(values) else {
+                    continue;
+                };
+
+                if !resumed && *published == Some(value) {
+                    continue;
+                }
+
+                self.sensor
+                    .publish(output.key.clone(), SensorPayload {
+                        value,
+                        unit: output.unit.as_str().into(),
+                        timestamp,
+                    })
+                    .await?;
+                *published = Some(value);
+            }
+        }
+
+        if status == self.status {
+            return Ok(());
+        }
+
+        match self.init.take() {
+            Some(init) if status == EntityStatus::Online => {
+                init.ready().await?;
+            }
+            init => {
+                self.init = init;
+                self.sensor.entity.publish_meta(status).await?;
+            }
+        }
+
+        tracing::info!(entity = %self.sensor.entity_id(), ?status, "Virtual entity status changed");
+        self.status = status;
+        Ok(())
+    }
+}
+
+/// An entity computed from other entities, see the [module docs](self)
+///
+/// Stops updating when dropped, and marks the entity
+/// [`Disconnected`](EntityStatus::Disconnected).
+pub struct VirtualEntity {
+    entity: Arc<TanukiEntity<Authority>>,
+    task: JoinHandle<()>,
+    _subscriptions: Vec<Subscription>,
+}
+
+impl VirtualEntity {
+    pub fn entity(&self) -> &Arc<TanukiEntity<Authority>> {
+        &self.entity
+    }
+}
+
+impl Drop for VirtualEntity {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.entity.disconnect_in_background();
+    }
+}
+
+impl TanukiConnection {
+    /// Start describing an entity computed from others, see [`VirtualEntity`]
+    pub fn virtual_entity(self: &Arc<Self>, id: impl Into<EntityId>) -> VirtualEntityBuilder {
+        VirtualEntityBuilder {
+            conn: self.clone(),
+            id: id.into(),
+            name: None,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            max_age: None,
+        }
+    }
+}
+
+/// Dew point in °C, from a temperature in °C and relative humidity in %
+///
+/// Uses the Magnus formula, accurate to a few tenths of a degree in normal conditions.
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    const A: f32 = 17.62;
+    const B: f32 = 243.12;
+
+    let gamma = (humidity / 100.).ln() + A * temperature / (B + temperature);
+    B * gamma / (A - gamma)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_entity_inputs() {
+        let now = Utc::now();
+        let reading = |value: f32, age| SensorPayload {
+            value: value.into(),
+            unit: "".into(),
+            timestamp: now - TimeDelta::minutes(age),
+        };
+
+        let mut inputs = Inputs::new(2, Some(TimeDelta::minutes(10)));
+        assert_eq!(inputs.values(now), None);
+        assert_eq!(next_status(EntityStatus::Init, false), EntityStatus::Init);
+
+        inputs.set(0, reading(20., 1));
+        assert_eq!(inputs.values(now), None);
+
+        inputs.set(1, reading(50., 5));
+        assert_eq!(inputs.values(now), Some(vec![20_f32.into(), 50_f32.into()]));
+        assert_eq!(inputs.next_expiry(now), Some(now + TimeDelta::minutes(5)));
+        assert_eq!(next_status(EntityStatus::Init, true), EntityStatus::Online);
+
+        // stale right when it expires
+        let expiry = now + TimeDelta::minutes(5);
+        assert!(inputs.values(expiry - TimeDelta::seconds(1)).is_some());
+        assert_eq!(inputs.values(expiry), None);
+        assert_eq!(inputs.next_expiry(expiry), Some(now + TimeDelta::minutes(9)));
+
+        assert_eq!(next_status(EntityStatus::Online, false), EntityStatus::Lost);
+        assert_eq!(next_status(EntityStatus::Lost, true), EntityStatus::Online);
+
+        let input = |entity: &str| (EntityId::from(entity), "temperature".to_tanuki_string());
+        assert!(validate_inputs(&[input("balcony"), input("front_door.temperature")]).is_ok());
+        assert!(matches!(
+            validate_inputs(&[input("balcony"), input("Front Door")]),
+            Err(Error::InvalidId { id, .. }) if id == "Front Door"
+        ));
+
+        assert!((dew_point(20., 50.) - 9.3).abs() < 0.1);
+        assert!((dew_point(30., 100.) - 30.).abs() < 0.01);
+    }
+}
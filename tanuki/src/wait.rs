@@ -0,0 +1,186 @@
+//! Waiting for something to happen, for automations that run in steps
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use tanuki::{TanukiConnection, capabilities::buttons::{ButtonEvent, ButtonName}};
+//! # use tanuki::{common::{TopicFilter, capabilities::ids}, rule::sensor, wait::WaitOutcome};
+//! # async fn example(conn: std::sync::Arc<TanukiConnection>) -> tanuki::Result<()> {
+//! // turn on the fan
+//!
+//! let dry = sensor("bathroom", "humidity").below(60.);
+//! let buttons = TopicFilter::capability_data(ids::BUTTONS).map_err(tanuki::Error::BadTopic)?;
+//! tokio::select! {
+//!     outcome = conn.wait_for(dry, Duration::from_secs(30 * 60)) => match outcome? {
+//!         WaitOutcome::Met => tracing::info!("Dry again"),
+//!         WaitOutcome::TimedOut => tracing::info!("Gave up"),
+//!     },
+//!     _ = conn.wait_for_event(buttons, |ev: &ButtonEvent| ev.name == ButtonName::Off) => {
+//!         tracing::info!("Turned off by hand");
+//!     }
+//! }
+//!
+//! // turn off the fan
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Everything here is cancel safe: dropping a future, such as the branches of a `select!` that
+//! didn't finish, just stops waiting.
+
+use core::time::Duration;
+use std::sync::Arc;
+
+use tanuki_common::TopicFilter;
+use tokio::{sync::broadcast, time::Instant};
+
+use crate::{
+    Error, PublishEvent, Result, TanukiConnection, dispatch::RETAINED_GRACE_PERIOD,
+    mirror::StateMirror, rule::Condition,
+};
+
+/// Which way a wait ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// The condition holds
+    Met,
+    TimedOut,
+}
+
+impl WaitOutcome {
+    pub fn is_met(self) -> bool {
+        self == WaitOutcome::Met
+    }
+}
+
+impl StateMirror {
+    /// Wait until `condition` holds, checking it now and after every change to the mirror
+    pub async fn wait_for(&self, condition: impl Condition, timeout: Duration) -> WaitOutcome {
+        self.wait_until(&condition, Instant::now() + timeout).await
+    }
+
+    async fn wait_until(&self, condition: &impl Condition, deadline: Instant) -> WaitOutcome {
+        wait_until(|| condition.check(self), self.changes(), deadline).await
+    }
+}
+
+/// Call `check` now and whenever `changes` has something, until it returns `true` or `deadline`
+/// passes
+async fn wait_until<T: Clone>(
+    mut check: impl FnMut() -> bool,
+    mut changes: broadcast::Receiver<T>,
+    deadline: Instant,
+) -> WaitOutcome {
+    let timeout = tokio::time::sleep_until(deadline);
+    tokio::pin!(timeout);
+
+    loop {
+        if check() {
+            return WaitOutcome::Met;
+        }
+
+        tokio::select! {
+            _ = &mut timeout => return WaitOutcome::TimedOut,
+            res = changes.recv() => {
+                if let Err(broadcast::error::RecvError::Closed) = res {
+                    // nothing can change anymore
+                    timeout.await;
+                    return WaitOutcome::TimedOut;
+                }
+            }
+        }
+    }
+}
+
+impl TanukiConnection {
+    /// Wait until `condition` holds, or `timeout` passes
+    ///
+    /// The condition is checked against the current retained state, and again after every
+    /// update. Follows the [topics](Condition::topics) of the condition through a temporary
+    /// [`StateMirror`], use [`StateMirror::wait_for`] with a long-lived one to wait repeatedly.
+    pub async fn wait_for(
+        self: &Arc<Self>,
+        condition: impl Condition,
+        timeout: Duration,
+    ) -> Result<WaitOutcome> {
+        let deadline = Instant::now() + timeout;
+
+        let mirror = tokio::time::timeout_at(deadline, async {
+            // otherwise the subscription is only queued, and we'd wait for nothing
+            self.wait_connected().await?;
+
+            let mirror = StateMirror::with_filter(self, condition.topics()).await?;
+            tokio::time::sleep(RETAINED_GRACE_PERIOD).await;
+            Ok::<_, Error>(mirror)
+        })
+        .await;
+
+        match mirror {
+            Ok(mirror) => Ok(mirror?.wait_until(&condition, deadline).await),
+            Err(_) => Ok(WaitOutcome::TimedOut),
+        }
+    }
+
+    /// Wait for the next event of type `E` on `topics` that passes `filter`, such as a
+    /// [`ButtonEvent`](crate::capabilities::buttons::ButtonEvent)
+    ///
+    /// Only events from now on count, the retained values the broker sends right after
+    /// subscribing are skipped. Combine with [`tokio::time::timeout`] or `select!` to give up at
+    /// some point.
+    pub async fn wait_for_event<E>(
+        self: &Arc<Self>,
+        topics: impl Into<TopicFilter>,
+        mut filter: impl FnMut(&E) -> bool,
+    ) -> Result<E>
+    where
+        E: for<'event> TryFrom<&'event PublishEvent, Error = ()>,
+    {
+        let topics = topics.into();
+        let mut events = self.live_events();
+        let _subscription = self
+            .subscribe_with_handler(topics.clone(), Box::new(|_| true))
+            .await?;
+
+        loop {
+            let event = events.recv().await?;
+
+            if !topics.matches(&event.topic) {
+                continue;
+            }
+
+            if let Ok(event) = E::try_from(&event)
+                && filter(&event)
+            {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn wait_outcomes() {
+        let in_a_bit = || Instant::now() + Duration::from_millis(50);
+        let (changes, _) = broadcast::channel::<()>(4);
+        let flag = AtomicBool::new(false);
+        let check = || flag.load(Ordering::Relaxed);
+
+        assert_eq!(wait_until(check, changes.subscribe(), in_a_bit()).await, WaitOutcome::TimedOut);
+
+        // only checked again after a change
+        let wait = wait_until(check, changes.subscribe(), in_a_bit());
+        let change = async {
+            tokio::task::yield_now().await;
+            flag.store(true, Ordering::Relaxed);
+            changes.send(()).unwrap();
+        };
+        let (outcome, ()) = tokio::join!(wait, change);
+        assert_eq!(outcome, WaitOutcome::Met);
+
+        assert_eq!(wait_until(check, changes.subscribe(), in_a_bit()).await, WaitOutcome::Met);
+    }
+}